@@ -0,0 +1,518 @@
+//! 8080 assembler, driven by the opcode table in `opcodes` like the disassembler.
+//!
+//! It accepts the Intel syntax of `cpudiag.asm`: one statement per line, labels in the
+//! first column or followed by a colon, comments after `;`, and the directives `ORG`,
+//! `EQU`, `DB`, `DW`, `DS` and `END`. Operands are expressions made of numbers (`10`,
+//! `0FFH`, `1010B`, `17O` or `17Q`), characters (`'A'`), symbols, `$` (the address of the
+//! current statement), the operators `+ - * / MOD AND OR XOR SHL SHR NOT`, `HIGH` and
+//! `LOW`, and parentheses.
+//!
+//! ```
+//! let program = emulator::assembler::assemble("
+//!         ORG     100H
+//! START:  MVI     A,'A'
+//!         JMP     START
+//! ").unwrap();
+//! assert_eq!(program.origin, 0x100);
+//! assert_eq!(program.bytes, vec![0x3e, 0x41, 0xc3, 0x00, 0x01]);
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use lazy_static::lazy_static;
+use crate::error::EmulatorError;
+use crate::opcodes::OPCODES;
+
+/// The result of `assemble()`.
+pub struct Program {
+    /// The address of `bytes[0]`
+    pub origin: u16,
+    /// Everything between the lowest and the highest address assembled. The gaps left by
+    /// `ORG` and `DS` are zeros.
+    pub bytes: Vec<u8>,
+    /// The symbols, with the case they were defined with
+    pub symbols: BTreeMap<String, u16>,
+    /// The source with the address and bytes of each line, in the format of `cpudiag.lst`
+    pub listing: String,
+}
+
+lazy_static! {
+    /// The opcodes of each mnemonic with their operands, e.g. "MVI" -> [(0x06, ["B", "d8"]), ...].
+    /// The undocumented duplicates come after the documented opcodes, so they're never picked.
+    static ref MNEMONICS: HashMap<&'static str, Vec<(u8, Vec<&'static str>)>> = {
+        let mut result: HashMap<&'static str, Vec<(u8, Vec<&'static str>)>> = HashMap::new();
+        for opcode in 0..=255u8 {
+            let name = OPCODES[&opcode].name;
+            let (mnemonic, operands) = match name.split_once(' ') {
+                Some((mnemonic, operands)) => (mnemonic, operands.split(',').collect()),
+                None => (name, Vec::new()),
+            };
+            result.entry(mnemonic).or_default().push((opcode, operands));
+        }
+        result
+    };
+}
+
+const REGISTERS: [&str; 10] = ["A", "B", "C", "D", "E", "H", "L", "M", "SP", "PSW"];
+
+/// A source line, split into its fields.
+struct Statement<'a> {
+    label: Option<&'a str>,
+    mnemonic: Option<String>,
+    operands: Vec<&'a str>,
+}
+
+/// Where `assemble()` is at
+struct Assembler<'a> {
+    symbols: HashMap<String, (String, i64)>,
+    /// The EQUs that can't be computed yet: their line, name, expression and address,
+    /// which is the value of `$` in the expression
+    pending: Vec<(usize, &'a str, &'a str, i64)>,
+    address: i64,
+    line: usize,
+}
+
+fn error(line: usize, message: String) -> EmulatorError {
+    EmulatorError::Assembly { line, message }
+}
+
+/// Split `s` at the commas that aren't in a string.
+fn split_operands(s: &str) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' => quoted = ! quoted,
+            ',' if ! quoted => {
+                result.push(s[start..i].trim());
+                start = i + 1;
+            },
+            _ => {},
+        }
+    }
+    if ! s[start..].trim().is_empty() || ! result.is_empty() {
+        result.push(s[start..].trim());
+    }
+    result
+}
+
+/// The line without its comment.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\'' => quoted = ! quoted,
+            ';' if ! quoted => return &line[..i],
+            _ => {},
+        }
+    }
+    line
+}
+
+fn parse_statement(line: &str) -> Statement<'_> {
+    let code = strip_comment(line).trim_end();
+    let mut rest = code.trim_start();
+    let mut label = None;
+    let first_len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let first = &rest[..first_len];
+    let second = rest[first_len..].split_whitespace().next().unwrap_or("");
+    if let Some(name) = first.strip_suffix(':') {
+        label = Some(name);
+        rest = rest[first_len..].trim_start();
+    } else if ! first.is_empty() && (! code.starts_with(char::is_whitespace) || second.eq_ignore_ascii_case("EQU")) {
+        label = Some(first);
+        rest = rest[first_len..].trim_start();
+    }
+    let mnemonic_len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let mnemonic = Some(rest[..mnemonic_len].to_ascii_uppercase()).filter(|m| ! m.is_empty());
+    Statement { label, mnemonic, operands: split_operands(rest[mnemonic_len..].trim()) }
+}
+
+/// The bytes of a `DB` operand: a string, or an expression.
+fn is_string(operand: &str) -> bool {
+    operand.len() >= 2 && operand.starts_with('\'') && operand.ends_with('\'')
+}
+
+fn string_bytes(operand: &str) -> Vec<u8> {
+    operand[1..operand.len() - 1].replace("''", "'").bytes().collect()
+}
+
+impl<'a> Assembler<'a> {
+    fn define(&mut self, name: &str, value: i64) -> Result<(), EmulatorError> {
+        let key = name.to_ascii_uppercase();
+        if REGISTERS.contains(&key.as_str()) || ! name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '?' || c == '@') {
+            return Err(error(self.line, format!("Invalid symbol name: {}", name)));
+        }
+        if self.symbols.insert(key, (name.to_string(), value)).is_some() {
+            return Err(error(self.line, format!("Symbol defined twice: {}", name)));
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<i64, EmulatorError> {
+        let tokens = tokenize(expression).map_err(|m| error(self.line, m))?;
+        let mut parser = Parser { tokens, position: 0, assembler: self };
+        let result = parser.expression(0).map_err(|m| error(self.line, m))?;
+        if parser.position < parser.tokens.len() {
+            return Err(error(self.line, format!("Invalid expression: {}", expression)));
+        }
+        Ok(result)
+    }
+
+    /// The value of an 8 bit operand, which can be negative.
+    fn byte(&self, expression: &str) -> Result<u8, EmulatorError> {
+        let value = self.evaluate(expression)?;
+        if ! (-256..=255).contains(&value) {
+            return Err(error(self.line, format!("Value out of range: {} = {}", expression, value)));
+        }
+        Ok(value as u8)
+    }
+
+    fn word(&self, expression: &str) -> Result<u16, EmulatorError> {
+        let value = self.evaluate(expression)?;
+        if ! (-65536..=65535).contains(&value) {
+            return Err(error(self.line, format!("Value out of range: {} = {}", expression, value)));
+        }
+        Ok(value as u16)
+    }
+
+    /// The opcode and operand templates of an instruction.
+    fn find_opcode(&self, mnemonic: &str, operands: &[&str]) -> Result<(u8, Vec<&'static str>), EmulatorError> {
+        let candidates = MNEMONICS.get(mnemonic)
+            .ok_or_else(|| error(self.line, format!("Unknown instruction: {}", mnemonic)))?;
+        for (opcode, templates) in candidates {
+            if templates.len() != operands.len() {
+                continue;
+            }
+            let matches = templates.iter().zip(operands).all(|(template, operand)| {
+                let operand = operand.to_ascii_uppercase();
+                match *template {
+                    "d8" | "d16" | "a16" | "p8" => ! REGISTERS.contains(&operand.as_str()),
+                    // RST: the vector is an expression
+                    n if n.starts_with(|c: char| c.is_ascii_digit()) =>
+                        self.evaluate(&operand).ok() == n.parse().ok(),
+                    register => operand == register,
+                }
+            });
+            if matches {
+                return Ok((*opcode, templates.clone()));
+            }
+        }
+        Err(error(self.line, format!("Invalid operands for {}: {}", mnemonic, operands.join(","))))
+    }
+
+    /// The size of a statement, for the first pass.
+    fn size(&self, mnemonic: &str, operands: &[&str]) -> Result<i64, EmulatorError> {
+        Ok(match mnemonic {
+            "DB" => operands.iter().map(|o| if is_string(o) { string_bytes(o).len() } else { 1 }).sum::<usize>() as i64,
+            "DW" => 2 * operands.len() as i64,
+            "DS" => match self.evaluate(self.single(mnemonic, operands)?)? {
+                n if n < 0 => return Err(error(self.line, format!("Negative size: {}", n))),
+                n => n,
+            },
+            // The RST vector can be a forward reference, only its size matters here
+            "RST" => 1,
+            _ => OPCODES[&self.find_opcode(mnemonic, operands)?.0].size as i64,
+        })
+    }
+
+    fn single<'b>(&self, mnemonic: &str, operands: &[&'b str]) -> Result<&'b str, EmulatorError> {
+        match operands {
+            [operand] => Ok(operand),
+            _ => Err(error(self.line, format!("{} takes one operand", mnemonic))),
+        }
+    }
+
+    /// The bytes of a statement, for the second pass.
+    fn bytes(&self, mnemonic: &str, operands: &[&str]) -> Result<Vec<u8>, EmulatorError> {
+        let mut result = Vec::new();
+        match mnemonic {
+            "DB" => for operand in operands {
+                if is_string(operand) {
+                    result.extend(string_bytes(operand));
+                } else {
+                    result.push(self.byte(operand)?);
+                }
+            },
+            "DW" => for operand in operands {
+                result.extend(self.word(operand)?.to_le_bytes());
+            },
+            "DS" => result.resize(self.evaluate(operands[0])? as usize, 0),
+            _ => {
+                let (opcode, templates) = self.find_opcode(mnemonic, operands)?;
+                result.push(opcode);
+                for (template, operand) in templates.iter().zip(operands) {
+                    match *template {
+                        "d8" | "p8" => result.push(self.byte(operand)?),
+                        "d16" | "a16" => result.extend(self.word(operand)?.to_le_bytes()),
+                        _ => {},
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Compute the EQUs that depend on later symbols, as long as some can be.
+    fn resolve_pending(&mut self) -> Result<(), EmulatorError> {
+        let end = self.address;
+        while ! self.pending.is_empty() {
+            let before = self.pending.len();
+            for (line, name, expression, address) in std::mem::take(&mut self.pending) {
+                self.line = line;
+                self.address = address;
+                match self.evaluate(expression) {
+                    Ok(value) => self.define(name, value)?,
+                    Err(_) => self.pending.push((line, name, expression, address)),
+                }
+            }
+            if self.pending.len() == before {
+                let (line, _, expression, address) = self.pending[0];
+                self.line = line;
+                self.address = address;
+                return self.evaluate(expression).map(|_| ());
+            }
+        }
+        self.address = end;
+        Ok(())
+    }
+}
+
+/// Assemble `source`. The errors are `EmulatorError::Assembly`, with the line number.
+pub fn assemble(source: &str) -> Result<Program, EmulatorError> {
+    let lines: Vec<&str> = source.lines().collect();
+    let mut assembler = Assembler { symbols: HashMap::new(), pending: Vec::new(), address: 0, line: 0 };
+
+    // First pass: the address of each line and the symbols
+    let mut addresses = Vec::new();
+    for (i, line) in lines.iter().copied().enumerate() {
+        assembler.line = i + 1;
+        let statement = parse_statement(line);
+        addresses.push(assembler.address);
+        match (statement.mnemonic.as_deref(), statement.label) {
+            (Some("EQU"), Some(name)) => {
+                let expression = assembler.single("EQU", &statement.operands)?;
+                match assembler.evaluate(expression) {
+                    Ok(value) => assembler.define(name, value)?,
+                    Err(_) => assembler.pending.push((i + 1, name, expression, assembler.address)),
+                }
+                continue;
+            },
+            (Some("EQU"), None) => return Err(error(i + 1, "EQU without a name".into())),
+            (_, Some(name)) => assembler.define(name, assembler.address)?,
+            _ => {},
+        }
+        match statement.mnemonic.as_deref() {
+            None => {},
+            Some("END") => break,
+            Some("ORG") => assembler.address = assembler.evaluate(assembler.single("ORG", &statement.operands)?)?,
+            Some(mnemonic) => assembler.address += assembler.size(mnemonic, &statement.operands)?,
+        }
+        if ! (0..=0x10000).contains(&assembler.address) {
+            return Err(error(i + 1, format!("Address out of range: {:X}", assembler.address)));
+        }
+    }
+    assembler.resolve_pending()?;
+
+    // Second pass: the bytes
+    let mut memory = vec![0u8; 0x10000];
+    let (mut low, mut high) = (0x10000usize, 0usize);
+    let mut listing = String::new();
+    for (i, line) in lines.iter().copied().enumerate() {
+        assembler.line = i + 1;
+        let statement = parse_statement(line);
+        let address = addresses.get(i).copied().unwrap_or(0) as usize;
+        assembler.address = address as i64;
+        let bytes = match statement.mnemonic.as_deref() {
+            Some("END") | Some("EQU") | Some("ORG") | None => Vec::new(),
+            Some(mnemonic) if i < addresses.len() => assembler.bytes(mnemonic, &statement.operands)?,
+            // After END
+            Some(_) => Vec::new(),
+        };
+        if address + bytes.len() > memory.len() {
+            return Err(error(i + 1, format!("Address out of range: {:X}", address + bytes.len())));
+        }
+        memory[address..address + bytes.len()].copy_from_slice(&bytes);
+        if ! bytes.is_empty() {
+            low = low.min(address);
+            high = high.max(address + bytes.len());
+        }
+        listing.push_str(&listing_line(address, &bytes, line));
+    }
+
+    let symbols: BTreeMap<String, u16> = assembler.symbols.values()
+        .map(|(name, value)| (name.clone(), *value as u16))
+        .collect();
+    if ! symbols.is_empty() {
+        listing.push_str("\nLabels:\n");
+        for (name, value) in &symbols {
+            listing.push_str(&format!("{:24}{:04X}\n", name, value));
+        }
+    }
+    let bytes = if low < high { memory[low..high].to_vec() } else { Vec::new() };
+    Ok(Program { origin: if low < high { low as u16 } else { 0 }, bytes, symbols, listing })
+}
+
+/// A line of the listing: the address and the first four bytes, then the source. When
+/// there are more bytes, ". . ." and the last four, or less.
+fn listing_line(address: usize, bytes: &[u8], source: &str) -> String {
+    let source = source.trim_end();
+    if source.is_empty() && bytes.is_empty() {
+        return "\n".into();
+    }
+    if bytes.is_empty() {
+        return format!("\t{:16}{}\n", "", source);
+    }
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+    let mut result = format!("{:04X}\t{:16}{}\n", address, hex(&bytes[..bytes.len().min(4)]), source);
+    if bytes.len() > 4 {
+        let last = (bytes.len() - 1) / 4 * 4;
+        result.push_str(&format!("\t. . .\n{:04X}\t{}\n", address + last, hex(&bytes[last..])));
+    }
+    result
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    /// `$`
+    Here,
+    Operator(String),
+    Open,
+    Close,
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let mut result = Vec::new();
+    let chars: Vec<char> = expression.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' {
+            // A character, '' being a quote
+            let end = (i + 1..chars.len()).find(|j| chars[*j] == '\'' && chars.get(j + 1) != Some(&'\''))
+                .ok_or_else(|| format!("Unterminated string: {}", expression))?;
+            let text: String = chars[i + 1..end].iter().collect::<String>().replace("''", "'");
+            let value = text.bytes().fold(0i64, |acc, b| acc << 8 | b as i64);
+            if text.is_empty() || text.len() > 2 {
+                return Err(format!("Invalid character constant: {}", expression));
+            }
+            result.push(Token::Number(value));
+            i = end + 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' || c == '?' || c == '@' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '?' || chars[i] == '@') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect::<String>().to_ascii_uppercase();
+            if c.is_ascii_digit() {
+                result.push(Token::Number(parse_number(&word)?));
+            } else if ["MOD", "AND", "OR", "XOR", "SHL", "SHR", "NOT", "HIGH", "LOW"].contains(&word.as_str()) {
+                result.push(Token::Operator(word));
+            } else {
+                result.push(Token::Symbol(word));
+            }
+        } else {
+            result.push(match c {
+                '$' => Token::Here,
+                '(' => Token::Open,
+                ')' => Token::Close,
+                '+' | '-' | '*' | '/' => Token::Operator(c.to_string()),
+                _ => return Err(format!("Invalid character in expression: {}", c)),
+            });
+            i += 1;
+        }
+    }
+    Ok(result)
+}
+
+/// A number with an optional radix suffix: H, B, O or Q, D.
+fn parse_number(word: &str) -> Result<i64, String> {
+    let (digits, radix) = match word.chars().last() {
+        Some('H') => (&word[..word.len() - 1], 16),
+        Some('B') => (&word[..word.len() - 1], 2),
+        Some('O') | Some('Q') => (&word[..word.len() - 1], 8),
+        Some('D') => (&word[..word.len() - 1], 10),
+        _ => (word, 10),
+    };
+    i64::from_str_radix(digits, radix).map_err(|_| format!("Invalid number: {}", word))
+}
+
+fn overflow() -> String {
+    "Overflow in expression".into()
+}
+
+struct Parser<'a, 'b> {
+    tokens: Vec<Token>,
+    position: usize,
+    assembler: &'b Assembler<'a>,
+}
+
+/// The binary operators by increasing precedence
+const PRECEDENCES: [&[&str]; 4] = [&["OR", "XOR"], &["AND"], &["+", "-"], &["*", "/", "MOD", "SHL", "SHR"]];
+
+impl Parser<'_, '_> {
+    fn next(&mut self) -> Option<Token> {
+        let result = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        result
+    }
+
+    fn peek_operator(&self, level: usize) -> Option<String> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(o)) if PRECEDENCES[level].contains(&o.as_str()) => Some(o.clone()),
+            _ => None,
+        }
+    }
+
+    /// Precedence climbing, starting with the operators of `level`.
+    fn expression(&mut self, level: usize) -> Result<i64, String> {
+        if level == PRECEDENCES.len() {
+            return self.unary();
+        }
+        let mut result = self.expression(level + 1)?;
+        while let Some(operator) = self.peek_operator(level) {
+            self.position += 1;
+            let right = self.expression(level + 1)?;
+            result = match operator.as_str() {
+                "OR" => result | right,
+                "XOR" => result ^ right,
+                "AND" => result & right,
+                "+" => result.checked_add(right).ok_or_else(overflow)?,
+                "-" => result.checked_sub(right).ok_or_else(overflow)?,
+                "*" => result.checked_mul(right).ok_or_else(overflow)?,
+                "/" | "MOD" if right == 0 => return Err("Division by zero".into()),
+                "/" => result.checked_div(right).ok_or_else(overflow)?,
+                "MOD" => result.checked_rem(right).ok_or_else(overflow)?,
+                "SHL" => result << (right & 63),
+                _ => result >> (right & 63),
+            };
+        }
+        Ok(result)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.next() {
+            Some(Token::Operator(o)) if o == "-" => self.unary()?.checked_neg().ok_or_else(overflow),
+            Some(Token::Operator(o)) if o == "+" => self.unary(),
+            Some(Token::Operator(o)) if o == "NOT" => Ok(! self.unary()? & 0xffff),
+            Some(Token::Operator(o)) if o == "HIGH" => Ok(self.unary()? >> 8 & 0xff),
+            Some(Token::Operator(o)) if o == "LOW" => Ok(self.unary()? & 0xff),
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Here) => Ok(self.assembler.address),
+            Some(Token::Symbol(name)) => self.assembler.symbols.get(&name).map(|(_, value)| *value)
+                .ok_or_else(|| format!("Undefined symbol: {}", name)),
+            Some(Token::Open) => {
+                let result = self.expression(0)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(result),
+                    _ => Err("Missing )".into()),
+                }
+            },
+            _ => Err("Invalid expression".into()),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! A Debug Adapter Protocol server for editors, see `emulator::dap` for the arguments of
+//! the launch request.
+//!
+//! ```text
+//! dap [--port PORT]
+//! ```
+
+use std::io;
+use std::net::TcpListener;
+use std::process::exit;
+
+use emulator::dap;
+
+const USAGE: &str = "Usage: dap [--port PORT]
+
+Serve the Debug Adapter Protocol on stdin and stdout, or on localhost:PORT.";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+fn main() {
+    let mut port = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = Some(args.next()
+                .and_then(|p| p.parse::<u16>().ok())
+                .unwrap_or_else(|| usage_error("Missing or invalid value for --port"))),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                exit(0);
+            },
+            _ => usage_error(&format!("Unknown option: {}", arg)),
+        }
+    }
+    let result = match port {
+        Some(port) => TcpListener::bind(("127.0.0.1", port))
+            .and_then(|listener| listener.accept())
+            .and_then(|(stream, _)| dap::serve(stream.try_clone()?, stream)),
+        None => dap::serve(io::stdin(), io::stdout()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
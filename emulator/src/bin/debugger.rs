@@ -0,0 +1,299 @@
+//! A console debugger for Space Invaders and other 8080 programs.
+//!
+//! ```text
+//! debugger [options] [rom]
+//! ```
+//!
+//! Type `help` at the prompt for the commands.
+
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+use emulator::debugger::{parse_number, Debugger, Stop};
+use emulator::emulator::Emulator;
+use emulator::error::EmulatorError;
+use emulator::gdb;
+use emulator::hooks::Access;
+use emulator::memory::Memory;
+use emulator::snapshot::Snapshot;
+use emulator::symbols::Symbols;
+
+const USAGE: &str = "Usage: debugger [options] [rom]
+
+Debug the Space Invaders ROM (default: space-invaders.rom) from the console.
+
+Options:
+  --snapshot FILE         Start from that snapshot
+  --program FILE          Debug that program instead of a ROM, without the interrupts
+                          of the Space Invaders board
+  --origin ADDRESS        Where to load the program and start it (default: 100)
+  --symbols FILE          Name the addresses with a symbol file, see emulator::symbols
+  --gdb PORT              Serve the GDB remote protocol on localhost:PORT instead of the console";
+
+const HELP: &str = "Commands (numbers and addresses are hexadecimal, addresses can also be symbols
+such as DrawShieldPl1 or DrawShieldPl1+3):
+  s, step [N]             Run N instructions (default: 1)
+  n, next                 Step over calls and RSTs
+  o, out                  Run until the current subroutine returns
+  c, continue [N]         Run until a breakpoint, Ctrl-C or N instructions
+  u, until ADDRESS        Run until PC reaches ADDRESS (run to cursor)
+  b, break ADDRESS [if CONDITION]
+                          Stop before the instruction at ADDRESS, e.g. b 1a5f if a == 3 && [20c0] != 0
+  d, delete [ADDRESS]     Delete a breakpoint, or all of them
+  disable ADDRESS, enable ADDRESS
+  bl, breakpoints         List the breakpoints
+  watch ADDRESS [LENGTH] [r|w|rw]
+                          Stop after the instructions that read or write (default) LENGTH
+                          bytes from ADDRESS (default: 1)
+  unwatch ID              Delete a watchpoint
+  wl, watchpoints         List the watchpoints
+  r, registers            Show the registers and flags
+  set REGISTER VALUE      Change a register (A-L, BC, DE, HL, SP, PC, PSW) or a flag (S Z AC P CY)
+  x ADDRESS [LENGTH]      Dump memory (default: 64 bytes)
+  w ADDRESS BYTE...       Write bytes to memory
+  l, list [ADDRESS]       Disassemble around PC or ADDRESS
+  h, help                 Show this help
+  q, quit                 Leave the debugger
+An empty line repeats the last step, next, out or continue.";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+fn create(args: &[String]) -> Result<(Debugger, Option<u16>), EmulatorError> {
+    let mut rom = None;
+    let mut gdb_port = None;
+    let mut snapshot = None;
+    let mut program = None;
+    let mut origin = 0x100;
+    let mut symbols = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--snapshot" => snapshot = Some(value()),
+            "--program" => program = Some(value()),
+            "--origin" => origin = parse_number(&value()).unwrap_or_else(|e| usage_error(&e)),
+            "--symbols" => symbols = Some(value()),
+            "--gdb" => gdb_port = Some(value().parse().unwrap_or_else(|_| usage_error(&format!("Invalid port for {}", arg)))),
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                exit(0);
+            },
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => rom = Some(arg.clone()),
+        }
+    }
+    let mut debugger = match program {
+        Some(file_name) => {
+            let mut memory = Memory::new();
+            memory.read_file(&file_name, origin as usize)?;
+            let mut debugger = Debugger::new(Emulator::new(Box::new(memory), origin as usize));
+            debugger.set_interrupts(false);
+            debugger
+        },
+        None => Debugger::new(Emulator::new_space_invaders_from(rom.as_deref().unwrap_or("space-invaders.rom"))?),
+    };
+    if let Some(file_name) = snapshot {
+        debugger.emulator_mut().restore(&Snapshot::load(&file_name)?)?;
+    }
+    if let Some(file_name) = symbols {
+        debugger.set_symbols(Symbols::load(&file_name)?);
+    }
+    Ok((debugger, gdb_port))
+}
+
+/// The instruction at PC and the registers.
+fn show_position(debugger: &Debugger) {
+    let instruction = debugger.current_instruction();
+    println!("{}: {:24} {}", debugger.describe(instruction.address), debugger.format_instruction(&instruction),
+        debugger.registers_view());
+}
+
+fn show_stop(debugger: &Debugger, stop: Result<Stop, EmulatorError>) {
+    match stop {
+        Ok(Stop::Breakpoint(address)) => println!("Breakpoint at {}", debugger.describe(address)),
+        Ok(Stop::Halted) => println!("Halted"),
+        Ok(Stop::Interrupted) => println!("Interrupted"),
+        Ok(Stop::Watchpoint(event)) => println!("Watchpoint: {}", event),
+        Ok(Stop::Limit) | Ok(Stop::Done) => {},
+        Err(e) => println!("{}", e),
+    }
+    show_position(debugger);
+}
+
+fn address_argument(debugger: &Debugger, args: &[&str], default: Option<u16>) -> Result<u16, String> {
+    match args.first() {
+        Some(arg) => debugger.parse_address(arg),
+        None => default.ok_or_else(|| "Missing address".to_string()),
+    }
+}
+
+fn count_argument(args: &[&str]) -> Result<Option<u64>, String> {
+    args.first().map(|arg| parse_number(arg).map(|n| n as u64)).transpose()
+}
+
+/// Run one command, returns false to quit.
+fn run_command(debugger: &mut Debugger, line: &str) -> Result<bool, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (command, args) = match words.split_first() {
+        Some((command, args)) => (*command, args),
+        None => return Ok(true),
+    };
+    match command {
+        "s" | "step" => {
+            let count = count_argument(args)?.unwrap_or(1);
+            let stop = debugger.run(Some(count));
+            show_stop(debugger, stop);
+        },
+        "n" | "next" => {
+            let stop = debugger.step_over(None);
+            show_stop(debugger, stop);
+        },
+        "o" | "out" => {
+            let stop = debugger.step_out(None);
+            show_stop(debugger, stop);
+        },
+        "c" | "continue" => {
+            let limit = count_argument(args)?;
+            let stop = debugger.run(limit);
+            show_stop(debugger, stop);
+        },
+        "u" | "until" => {
+            let address = address_argument(debugger, args, None)?;
+            let stop = debugger.run_to(address, None);
+            show_stop(debugger, stop);
+        },
+        "b" | "break" => {
+            let address = address_argument(debugger, args, Some(debugger.pc()))?;
+            let condition = match args.iter().position(|a| *a == "if") {
+                Some(i) => Some(debugger.parse_condition(&args[i + 1..].join(" "))?),
+                None if args.len() > 1 => return Err(format!("Expected if: {}", args[1])),
+                None => None,
+            };
+            debugger.add_breakpoint(address, condition);
+        },
+        "d" | "delete" => match args.first() {
+            Some(_) => if ! debugger.remove_breakpoint(address_argument(debugger, args, None)?) {
+                return Err("No breakpoint there".into());
+            },
+            None => debugger.clear_breakpoints(),
+        },
+        "enable" | "disable" => {
+            if ! debugger.enable_breakpoint(address_argument(debugger, args, None)?, command == "enable") {
+                return Err("No breakpoint there".into());
+            }
+        },
+        "bl" | "breakpoints" => for breakpoint in debugger.breakpoints() {
+            println!("{}{}{} hits: {}", debugger.describe(breakpoint.address),
+                if breakpoint.enabled { "" } else { " (disabled)" },
+                breakpoint.condition.as_ref().map(|c| format!(" if {}", c)).unwrap_or_default(),
+                breakpoint.hits);
+        },
+        "watch" => {
+            let address = address_argument(debugger, args, None)?;
+            let length = match args.get(1).filter(|a| ! ["r", "w", "rw"].contains(a)) {
+                Some(length) => parse_number(length)?.max(1),
+                None => 1,
+            };
+            let accesses = match args.last().filter(|_| args.len() > 1) {
+                Some(&"r") => vec![Access::Read],
+                Some(&"rw") => vec![Access::Read, Access::Write],
+                Some(&"w") | None => vec![Access::Write],
+                Some(_) if args.len() == 2 => vec![Access::Write],
+                Some(other) => return Err(format!("Expected r, w or rw: {}", other)),
+            };
+            let range = address..=address.saturating_add(length - 1);
+            for access in accesses {
+                let id = debugger.add_watchpoint(access, range.clone());
+                println!("Watchpoint {}", id);
+            }
+        },
+        "unwatch" => {
+            let id = args.first().and_then(|id| id.parse().ok()).ok_or("Usage: unwatch ID")?;
+            if ! debugger.remove_watchpoint(id) {
+                return Err("No such watchpoint".into());
+            }
+        },
+        "wl" | "watchpoints" => for watchpoint in debugger.watchpoints() {
+            println!("{}: {:?} {}-{:04x}", watchpoint.id, watchpoint.access,
+                debugger.describe(*watchpoint.range.start()), watchpoint.range.end());
+        },
+        "r" | "registers" => println!("{}", debugger.registers_view()),
+        "set" => match args {
+            [register, value] => debugger.set_register(register, parse_number(value)?)?,
+            _ => return Err("Usage: set REGISTER VALUE".into()),
+        },
+        "x" => {
+            let address = address_argument(debugger, args, None)?;
+            let length = args.get(1).map(|a| parse_number(a)).transpose()?.unwrap_or(0x40);
+            print!("{}", debugger.memory_view(address, length as usize));
+        },
+        "w" => {
+            let address = address_argument(debugger, args, None)?;
+            let bytes = args[1..].iter().map(|b| parse_number(b).map(|v| v as u8)).collect::<Result<Vec<u8>, String>>()?;
+            debugger.write_memory(address, &bytes);
+        },
+        "l" | "list" => {
+            let address = address_argument(debugger, args, Some(debugger.pc()))?;
+            for instruction in debugger.disassemble_around(address, 5, 10) {
+                let marker = if instruction.address == debugger.pc() { "=>" } else if
+                    debugger.breakpoints().any(|b| b.address == instruction.address) { " *" } else { "  " };
+                if let Some(symbol) = debugger.symbols().get(instruction.address) {
+                    println!("{}:{}", symbol.name, symbol.comment.as_ref().map(|c| format!(" ; {}", c)).unwrap_or_default());
+                }
+                let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{} {:04x}: {:9} {}", marker, instruction.address, bytes.join(" "),
+                    debugger.format_instruction(&instruction));
+            }
+        },
+        "h" | "help" => println!("{}", HELP),
+        "q" | "quit" => return Ok(false),
+        _ => return Err(format!("Unknown command: {}, type help for the list", command)),
+    }
+    Ok(true)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (mut debugger, gdb_port) = create(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(2);
+    });
+    if let Some(port) = gdb_port {
+        eprintln!("Waiting for GDB on localhost:{}", port);
+        if let Err(e) = gdb::listen(&mut debugger, &format!("127.0.0.1:{}", port)) {
+            eprintln!("{}", e);
+            exit(1);
+        }
+        return;
+    }
+    let handle = debugger.break_handle();
+    if let Err(e) = ctrlc::set_handler(move || handle.store(true, std::sync::atomic::Ordering::Relaxed)) {
+        eprintln!("Ctrl-C won't interrupt the program: {}", e);
+    }
+
+    show_position(&debugger);
+    let mut last_command = String::new();
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let mut line = line.trim().to_string();
+        if line.is_empty() {
+            line = last_command.clone();
+        } else if ["s", "step", "n", "next", "o", "out", "c", "continue"].contains(&line.split_whitespace().next().unwrap()) {
+            last_command = line.clone();
+        }
+        match run_command(&mut debugger, &line) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(message) => println!("{}", message),
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Run Space Invaders without a window, for CI and scripts.
+//!
+//! ```text
+//! headless [options] [rom]
+//! ```
+//!
+//! Exit codes: 0 when all the frames ran, 1 on an emulation error, 2 on a usage or I/O
+//! error.
+
+use std::fs;
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use emulator::coverage::Coverage;
+use emulator::crash::CRASH_DUMP_FILE;
+use emulator::emulator::Emulator;
+use emulator::error::EmulatorError;
+use emulator::memory::ROM_SIZE;
+use emulator::monitor::MonitorConfig;
+use emulator::movie::Movie;
+use emulator::recording::AvRecorder;
+use emulator::screen::{self, GifRecorder};
+use emulator::sound::SOUNDS_DIRECTORY;
+use emulator::snapshot::Snapshot;
+use emulator::source_map::SourceMap;
+use emulator::symbols::Symbols;
+use emulator::trace::{parse_range, FileSink, TraceConfig, Tracer};
+
+const USAGE: &str = "Usage: headless [options] [rom]
+
+Run the Space Invaders ROM (default: space-invaders.rom) without a window.
+
+Options:
+  --frames N              Number of frames to run (default: 600)
+  --realtime              Run at 60 frames per second instead of as fast as possible
+  --input FRAME:NAME:on|off
+                          Press or release an input at the beginning of that frame
+  --script FILE           Read inputs from a file, one \"FRAME NAME on|off\" per line
+  --snapshot FILE         Start from that snapshot, or from the one of a crash dump
+  --movie FILE            Replay that movie
+  --record FILE           Record the run as a movie
+  --framebuffer FILE      Write the final screen as a PBM image
+  --png FILE              Write the final screen, in colour, as a PNG image
+  --gif FILE              Record the frames to an animated GIF
+  --gif-frames FIRST:LAST Only record these frames (default: all of them)
+  --video NAME            Record the frames to NAME.y4m and the sounds to NAME.wav
+  --sounds DIR            Where the samples of the sounds are (default: sounds)
+  --ram FILE              Write the final RAM (0x2000-0x3fff)
+  --ports FILE            Write the final ports and registers (default: stdout)
+  --trace FILE            Write the executed instructions to FILE, see emulator::trace
+  --trace-range FIRST-LAST
+                          Only trace the instructions in that range, can be repeated
+  --trace-start ADDRESS   Start tracing when PC reaches ADDRESS
+  --trace-count N         Stop tracing after N instructions
+  --profile FILE          Write where the cycles went, by subroutine and by address
+  --profile-folded FILE   Write the cycles by stack of calls, for flamegraph.pl or inferno
+  --coverage FILE         Write which bytes ran or were read, see emulator::coverage
+  --listing FILE          Name the subroutines of the profile with the labels of a listing
+  --symbols FILE          Name the addresses of the trace, the profile and the crash dump
+                          with a symbol file, see emulator::symbols
+  --monitor               Check that the code stays in the ROM, the stack in the work RAM,
+                          that nothing writes to the ROM and that the interrupts are
+                          enabled again within a second, and print a summary of the
+                          violations, see emulator::monitor
+  --soak SEED             Press random inputs, the same for the same seed, and monitor,
+                          for soak tests, e.g. with --frames 5000000
+  --crash-dump FILE       Where to write the last instructions, the stack, the last port
+                          accesses and a snapshot on an emulation error, see
+                          emulator::crash (default: crash-dump.txt)
+
+Addresses are hexadecimal.
+
+Inputs: coin, 1p, 2p, fire, left, right, p2fire, p2left, p2right, tilt
+
+Exit codes: 0 on success, 1 on an emulation error or a violation, 2 on a usage or I/O error";
+
+/// The name, port and bit of each input
+const INPUTS: [(&str, u8, u8); 10] = [
+    ("coin", 1, 0),
+    ("2p", 1, 1),
+    ("1p", 1, 2),
+    ("fire", 1, 4),
+    ("left", 1, 5),
+    ("right", 1, 6),
+    ("tilt", 2, 2),
+    ("p2fire", 2, 4),
+    ("p2left", 2, 5),
+    ("p2right", 2, 6),
+];
+
+struct Input {
+    frame: u64,
+    port: u8,
+    bit: u8,
+    on: bool,
+}
+
+#[derive(Default)]
+struct Options {
+    rom: Option<String>,
+    frames: u64,
+    realtime: bool,
+    inputs: Vec<Input>,
+    snapshot: Option<String>,
+    movie: Option<String>,
+    record: Option<String>,
+    framebuffer: Option<String>,
+    png: Option<String>,
+    gif: Option<String>,
+    gif_frames: Option<(u64, u64)>,
+    video: Option<String>,
+    sounds: Option<String>,
+    ram: Option<String>,
+    ports: Option<String>,
+    trace: Option<String>,
+    trace_config: TraceConfig,
+    profile: Option<String>,
+    profile_folded: Option<String>,
+    listing: Option<String>,
+    symbols: Option<String>,
+    coverage: Option<String>,
+    crash_dump: Option<String>,
+    monitor: bool,
+    soak: Option<u64>,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+fn fail(error: EmulatorError) -> ! {
+    eprintln!("{}", error);
+    exit(match error {
+        EmulatorError::Io { .. } => 2,
+        _ => 1,
+    });
+}
+
+/// Parse "FRAME NAME on|off", with the fields separated by `separator`.
+fn parse_input(s: &str, separator: char) -> Result<Input, String> {
+    let fields: Vec<&str> = s.split(separator).filter(|f| ! f.is_empty()).collect();
+    let error = || format!("Invalid input: {}", s);
+    if fields.len() != 3 {
+        return Err(error());
+    }
+    let frame = fields[0].parse::<u64>().map_err(|_| error())?;
+    let (_, port, bit) = INPUTS.iter().find(|(name, _, _)| *name == fields[1])
+        .ok_or_else(|| format!("Unknown input: {}", fields[1]))?;
+    let on = match fields[2] {
+        "on" => true,
+        "off" => false,
+        _ => return Err(error()),
+    };
+    Ok(Input { frame, port: *port, bit: *bit, on })
+}
+
+fn parse_options() -> Options {
+    let mut result = Options { frames: 600, .. Default::default() };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--frames" => {
+                let v = value();
+                result.frames = v.parse().unwrap_or_else(|_| usage_error(&format!("Invalid number of frames: {}", v)));
+            },
+            "--realtime" => result.realtime = true,
+            "--input" => {
+                let input = parse_input(&value(), ':').unwrap_or_else(|e| usage_error(&e));
+                result.inputs.push(input);
+            },
+            "--script" => {
+                let file_name = value();
+                let script = fs::read_to_string(&file_name).unwrap_or_else(|e| fail(EmulatorError::Io {
+                    file_name: file_name.clone(), message: e.to_string()
+                }));
+                for line in script.lines().map(str::trim).filter(|l| ! l.is_empty() && ! l.starts_with('#')) {
+                    let input = parse_input(line, ' ').unwrap_or_else(|e| usage_error(&e));
+                    result.inputs.push(input);
+                }
+            },
+            "--snapshot" => result.snapshot = Some(value()),
+            "--movie" => result.movie = Some(value()),
+            "--record" => result.record = Some(value()),
+            "--framebuffer" => result.framebuffer = Some(value()),
+            "--png" => result.png = Some(value()),
+            "--gif" => result.gif = Some(value()),
+            "--gif-frames" => {
+                let v = value();
+                let span = v.split_once(':').and_then(|(first, last)| {
+                    Some((first.parse::<u64>().ok()?, last.parse::<u64>().ok()?))
+                });
+                result.gif_frames = Some(span.unwrap_or_else(|| usage_error(&format!("Invalid frames: {}", v))));
+            },
+            "--video" => result.video = Some(value()),
+            "--sounds" => result.sounds = Some(value()),
+            "--ram" => result.ram = Some(value()),
+            "--ports" => result.ports = Some(value()),
+            "--trace" => result.trace = Some(value()),
+            "--trace-range" => {
+                let range = parse_range(&value()).unwrap_or_else(|e| usage_error(&e));
+                result.trace_config.ranges.push(range);
+            },
+            "--trace-start" => {
+                let range = parse_range(&value()).unwrap_or_else(|e| usage_error(&e));
+                result.trace_config.start = Some(*range.start());
+            },
+            "--trace-count" => {
+                let v = value();
+                result.trace_config.count = Some(v.parse().unwrap_or_else(|_| usage_error(&format!("Invalid count: {}", v))));
+            },
+            "--profile" => result.profile = Some(value()),
+            "--profile-folded" => result.profile_folded = Some(value()),
+            "--listing" => result.listing = Some(value()),
+            "--symbols" => result.symbols = Some(value()),
+            "--coverage" => result.coverage = Some(value()),
+            "--crash-dump" => result.crash_dump = Some(value()),
+            "--monitor" => result.monitor = true,
+            "--soak" => {
+                let v = value();
+                result.soak = Some(v.parse().unwrap_or_else(|_| usage_error(&format!("Invalid seed: {}", v))));
+                result.monitor = true;
+            },
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                exit(0);
+            },
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => result.rom = Some(arg),
+        }
+    }
+    result
+}
+
+/// The inputs of the soak tests: a coin and a start now and then, and the fire and
+/// movement buttons changing every few frames, from a xorshift generator.
+struct RandomInputs {
+    state: u64,
+}
+
+impl RandomInputs {
+    fn new(seed: u64) -> RandomInputs {
+        RandomInputs { state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1 }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// The inputs to press during the next frames, as (port, bit, on).
+    fn frame(&mut self, frame: u64) -> Vec<(u8, u8, bool)> {
+        if ! frame.is_multiple_of(8) {
+            return Vec::new();
+        }
+        let random = self.next();
+        let mut result: Vec<(u8, u8, bool)> = INPUTS.iter()
+            .filter(|(name, _, _)| ["fire", "left", "right", "p2fire", "p2left", "p2right"].contains(name))
+            .enumerate()
+            .map(|(i, (_, port, bit))| (*port, *bit, random >> i & 1 == 1))
+            .collect();
+        // A coin and the start of a game about every 30 seconds
+        let start = random >> 8 & 0xff < 3;
+        for name in ["coin", "1p", "2p"] {
+            let (_, port, bit) = INPUTS.iter().find(|(n, _, _)| *n == name).unwrap();
+            result.push((*port, *bit, start && (name != "2p" || random >> 16 & 1 == 1)));
+        }
+        result
+    }
+}
+
+fn write(file_name: &str, bytes: &[u8]) -> Result<(), EmulatorError> {
+    fs::write(file_name, bytes).map_err(|e| EmulatorError::Io {
+        file_name: file_name.into(), message: e.to_string()
+    })
+}
+
+fn run(options: &Options) -> Result<(), EmulatorError> {
+    let mut emulator = Emulator::new_space_invaders_from(
+        options.rom.as_deref().unwrap_or("space-invaders.rom"))?;
+    if let Some(file_name) = &options.snapshot {
+        emulator.restore(&Snapshot::load(file_name)?)?;
+    }
+    if let Some(file_name) = &options.movie {
+        emulator.play(Movie::load(file_name)?)?;
+    }
+    if options.record.is_some() {
+        emulator.start_recording(true);
+    }
+    let symbols = match &options.symbols {
+        Some(file_name) => Symbols::load(file_name)?,
+        None => Symbols::new(),
+    };
+    if let Some(file_name) = &options.trace {
+        let sink = FileSink::create(file_name)?.with_symbols(symbols.clone());
+        emulator.enable_trace(Tracer::new(options.trace_config.clone(), sink));
+    }
+    let labels = match &options.listing {
+        Some(file_name) => Some(SourceMap::load(file_name, false)?),
+        None => None,
+    };
+    if options.coverage.is_some() {
+        emulator.enable_coverage(Coverage::new());
+    }
+    if options.profile.is_some() || options.profile_folded.is_some() {
+        emulator.enable_profiler();
+    }
+    if options.monitor {
+        emulator.enable_monitor(MonitorConfig::space_invaders());
+    }
+    let mut random_inputs = options.soak.map(RandomInputs::new);
+
+    let frame_duration = Duration::from_micros(1_000_000 / 60);
+    let start = Instant::now();
+    let shared_state = emulator.shared_state();
+    let mut gif = match &options.gif {
+        Some(file_name) => Some(GifRecorder::create(file_name)?),
+        None => None,
+    };
+    let mut video = match &options.video {
+        Some(name) => Some(AvRecorder::create(&format!("{}.y4m", name), &format!("{}.wav", name),
+            options.sounds.as_deref().unwrap_or(SOUNDS_DIRECTORY))?),
+        None => None,
+    };
+    let (gif_first, gif_last) = options.gif_frames.unwrap_or((0, u64::MAX));
+    for frame in 0..options.frames {
+        let random = random_inputs.as_mut().map(|inputs| inputs.frame(frame)).unwrap_or_default();
+        let inputs = options.inputs.iter().filter(|input| input.frame == frame)
+            .map(|input| (input.port, input.bit, input.on));
+        for (port, bit, on) in random.into_iter().chain(inputs) {
+            let mut state = shared_state.lock().unwrap();
+            if port == 1 {
+                state.set_bit_in_1(bit, on);
+            } else {
+                state.set_bit_in_2(bit, on);
+            }
+        }
+        if let Err(e) = emulator.run_one_frame() {
+            let file_name = options.crash_dump.as_deref().unwrap_or(CRASH_DUMP_FILE);
+            match emulator.crash_dump(&e).save(file_name, &symbols) {
+                Ok(()) => eprintln!("Crash dump written to {}", file_name),
+                Err(e) => eprintln!("{}", e),
+            }
+            return Err(e);
+        }
+        if let Some(recorder) = gif.as_mut() {
+            if (gif_first..=gif_last).contains(&frame) {
+                recorder.add_frame(&shared_state.lock().unwrap().graphic_memory())?;
+            }
+        }
+        if let Some(recorder) = video.as_mut() {
+            recorder.add_frame(&shared_state.lock().unwrap())?;
+        }
+        if options.realtime {
+            let target = frame_duration * (frame + 1) as u32;
+            let elapsed = start.elapsed();
+            if elapsed < target {
+                thread::sleep(target - elapsed);
+            }
+        }
+    }
+
+    if let (Some(file_name), Some(movie)) = (&options.record, emulator.stop_recording()) {
+        movie.save(file_name)?;
+    }
+    if let Some(recorder) = gif {
+        recorder.finish()?;
+    }
+    if let Some(recorder) = video {
+        recorder.finish()?;
+    }
+    if let Some(tracer) = emulator.disable_trace() {
+        tracer.finish()?;
+    }
+    if let (Some(file_name), Some(coverage)) = (&options.coverage, emulator.disable_coverage()) {
+        coverage.save(file_name)?;
+    }
+    if let Some(profiler) = emulator.disable_profiler() {
+        let names = |address: u16| symbols.label(address)
+            .or_else(|| labels.as_ref().and_then(|labels| labels.label(address)).map(str::to_string));
+        if let Some(file_name) = &options.profile {
+            write(file_name, profiler.report(&names, 50).as_bytes())?;
+        }
+        if let Some(file_name) = &options.profile_folded {
+            write(file_name, profiler.folded(&names).as_bytes())?;
+        }
+    }
+    let snapshot = emulator.snapshot();
+    let graphic_memory = shared_state.lock().unwrap().graphic_memory();
+    if let Some(file_name) = &options.framebuffer {
+        write(file_name, &screen::to_pbm(&graphic_memory))?;
+    }
+    if let Some(file_name) = &options.png {
+        screen::save_png(file_name, &graphic_memory)?;
+    }
+    if let Some(file_name) = &options.ram {
+        write(file_name, &snapshot.memory[ROM_SIZE..0x4000])?;
+    }
+    let ports = format!("in_1={:02x}\nin_2={:02x}\nout_3={:02x}\nout_5={:02x}\n\
+            shift_register={:04x}\nshift_register_offset={}\nframes={}\nregisters={}\n",
+        snapshot.in_1, snapshot.in_2, snapshot.out_3, snapshot.out_5,
+        snapshot.shift_register, snapshot.shift_register_offset, options.frames,
+        snapshot.registers);
+    match &options.ports {
+        Some(file_name) => write(file_name, ports.as_bytes())?,
+        None => print!("{}", ports),
+    }
+    if let Some(monitor) = emulator.disable_monitor() {
+        let elapsed = start.elapsed().as_secs_f64();
+        println!("Time: {:.1}s ({:.0} frames per second)", elapsed, monitor.frames() as f64 / elapsed);
+        print!("{}", monitor.summary());
+        if monitor.total() > 0 {
+            exit(1);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = parse_options();
+    if let Err(e) = run(&options) {
+        fail(e);
+    }
+}
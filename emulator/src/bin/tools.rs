@@ -0,0 +1,287 @@
+//! Command line tools for 8080 programs.
+//!
+//! ```text
+//! tools <command> [options]
+//! ```
+//!
+//! Exit codes: 0 on success, 1 when the command fails, 2 on a usage or I/O error.
+
+use std::fs;
+use std::process::exit;
+
+use emulator::assembler;
+use emulator::coverage::Coverage;
+use emulator::disassembler::Analysis;
+use emulator::emulator::Emulator;
+use emulator::error::EmulatorError;
+use emulator::memory::Memory;
+use emulator::snapshot::Snapshot;
+use emulator::symbols::Symbols;
+use emulator::trace::{TraceConfig, Tracer};
+use emulator::trace_diff::TraceDiff;
+
+const USAGE: &str = "Usage: tools <command> [options]
+
+Commands:
+  assemble [options] SOURCE
+                          Assemble an 8080 source file, see the documentation of
+                          emulator::assembler for its syntax
+    -o FILE               Write the binary to FILE (default: SOURCE with a .bin extension)
+    -l FILE               Write a listing to FILE
+  disassemble [options] ROM
+                          Disassemble a ROM by following the code from its entry points
+                          (0x0000, 0x0008 and 0x0010), the rest of it is shown as data
+    --origin ADDRESS      Address of the first byte of the ROM (default: 0)
+    --entry ADDRESS       Another entry point, can be repeated
+    --symbols FILE        Name the routines and variables with a symbol file, see
+                          emulator::symbols
+    -o FILE               Write the listing to FILE instead of stdout
+  trace-diff [options] REFERENCE [ROM]
+                          Run the ROM (default: space-invaders.rom) and compare each
+                          instruction with a reference trace, see emulator::trace_diff for
+                          its format. Stops at the first divergence and shows it.
+    --program FILE        Run that program instead of a ROM, without interrupts
+    --origin ADDRESS      Where to load the program and start it (default: 100)
+    --snapshot FILE       Start from that snapshot
+    --context N           How many previous instructions to show (default: 20)
+  coverage-listing [options] COVERAGE [ROM]
+                          Disassemble the ROM (default: space-invaders.rom) with a coverage
+                          map written by headless --coverage, marking the code that never
+                          ran and the data that was read
+    --origin ADDRESS      Address of the first byte of the ROM (default: 0)
+    --entry ADDRESS       Another entry point, can be repeated
+    --symbols FILE        Name the routines and variables with a symbol file
+    -o FILE               Write the listing to FILE instead of stdout
+  coverage-merge -o FILE COVERAGE...
+                          Combine the coverage maps of several runs into one
+
+Addresses are hexadecimal, with or without a 0x prefix.
+
+Exit codes: 0 on success, 1 when the command fails (e.g. the traces diverge), 2 on a
+usage or I/O error";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+fn fail(error: EmulatorError) -> ! {
+    eprintln!("{}", error);
+    exit(match error {
+        EmulatorError::Io { .. } => 2,
+        _ => 1,
+    });
+}
+
+fn parse_address(s: &str) -> u16 {
+    u16::from_str_radix(s.trim_start_matches("0x"), 16)
+        .unwrap_or_else(|_| usage_error(&format!("Invalid address: {}", s)))
+}
+
+fn read(file_name: &str) -> Result<Vec<u8>, EmulatorError> {
+    fs::read(file_name).map_err(|e| EmulatorError::Io { file_name: file_name.into(), message: e.to_string() })
+}
+
+/// Print `text` or write it to `file_name`.
+fn output(file_name: Option<&str>, text: &str) -> Result<(), EmulatorError> {
+    match file_name {
+        Some(file_name) => fs::write(file_name, text).map_err(|e| EmulatorError::Io {
+            file_name: file_name.into(), message: e.to_string()
+        }),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+fn assemble(mut args: impl Iterator<Item = String>) -> Result<(), EmulatorError> {
+    let mut source = None;
+    let mut output_file = None;
+    let mut listing_file = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "-o" => output_file = Some(value()),
+            "-l" => listing_file = Some(value()),
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => source = Some(arg),
+        }
+    }
+    let source = source.unwrap_or_else(|| usage_error("Missing source"));
+    let text = String::from_utf8_lossy(&read(&source)?).into_owned();
+    let program = assembler::assemble(&text)?;
+    let output_file = output_file.unwrap_or_else(|| {
+        std::path::Path::new(&source).with_extension("bin").to_string_lossy().into_owned()
+    });
+    fs::write(&output_file, &program.bytes).map_err(|e| EmulatorError::Io {
+        file_name: output_file.clone(), message: e.to_string()
+    })?;
+    if let Some(file_name) = listing_file {
+        output(Some(&file_name), &program.listing)?;
+    }
+    eprintln!("{} bytes at {:04X}", program.bytes.len(), program.origin);
+    Ok(())
+}
+
+fn disassemble(mut args: impl Iterator<Item = String>) -> Result<(), EmulatorError> {
+    let mut rom = None;
+    let mut origin = 0;
+    let mut entry_points = vec![0x0000, 0x0008, 0x0010];
+    let mut output_file = None;
+    let mut symbols = Symbols::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--origin" => origin = parse_address(&value()),
+            "--entry" => entry_points.push(parse_address(&value())),
+            "--symbols" => symbols = Symbols::load(&value())?,
+            "-o" => output_file = Some(value()),
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+    let rom = rom.unwrap_or_else(|| usage_error("Missing ROM"));
+    let bytes = read(&rom)?;
+    if origin != 0 {
+        entry_points[0] = origin;
+    }
+    let mut analysis = Analysis::new(&bytes, origin, &entry_points);
+    analysis.set_symbols(symbols);
+    output(output_file.as_deref(), &analysis.listing())
+}
+
+fn trace_diff(mut args: impl Iterator<Item = String>) -> Result<(), EmulatorError> {
+    let mut files = Vec::new();
+    let mut program = None;
+    let mut origin = 0x100;
+    let mut snapshot = None;
+    let mut context = 20;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--program" => program = Some(value()),
+            "--origin" => origin = parse_address(&value()),
+            "--snapshot" => snapshot = Some(value()),
+            "--context" => {
+                let v = value();
+                context = v.parse().unwrap_or_else(|_| usage_error(&format!("Invalid number: {}", v)));
+            },
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+    let mut files = files.into_iter();
+    let reference = files.next().unwrap_or_else(|| usage_error("Missing reference"));
+    let rom = files.next();
+    let diff = TraceDiff::load(&reference, context)?;
+
+    let mut emulator = match &program {
+        Some(file_name) => {
+            let mut memory = Memory::new();
+            memory.read_file(file_name, origin as usize)?;
+            Emulator::new(Box::new(memory), origin as usize)
+        },
+        None => Emulator::new_space_invaders_from(rom.as_deref().unwrap_or("space-invaders.rom"))?,
+    };
+    if let Some(file_name) = snapshot {
+        emulator.restore(&Snapshot::load(&file_name)?)?;
+    }
+    emulator.enable_trace(Tracer::new(TraceConfig::default(), diff.clone()));
+    let mut result = Ok(());
+    while ! diff.is_done() && result.is_ok() {
+        result = match program {
+            // Without interrupts, a halted program is over
+            Some(_) if emulator.registers().halted => break,
+            Some(_) => emulator.step().map(|_| ()),
+            None => emulator.run_one_frame().map(|_| ()),
+        };
+    }
+    if let Some(divergence) = diff.divergence() {
+        print!("{}", divergence);
+        exit(1);
+    }
+    if let Err(e) = result {
+        println!("The emulator stopped after {} instructions: {}\nPrevious instructions:", diff.matched(), e);
+        for entry in diff.history() {
+            println!("  {}", entry);
+        }
+        exit(1);
+    }
+    if diff.matched() < diff.len() {
+        println!("The program halted after {} of the {} instructions of the reference", diff.matched(), diff.len());
+        exit(1);
+    }
+    println!("{} instructions match the reference", diff.matched());
+    Ok(())
+}
+
+fn coverage_listing(mut args: impl Iterator<Item = String>) -> Result<(), EmulatorError> {
+    let mut files = Vec::new();
+    let mut origin = 0;
+    let mut entry_points = vec![0x0000, 0x0008, 0x0010];
+    let mut output_file = None;
+    let mut symbols = Symbols::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--origin" => origin = parse_address(&value()),
+            "--entry" => entry_points.push(parse_address(&value())),
+            "--symbols" => symbols = Symbols::load(&value())?,
+            "-o" => output_file = Some(value()),
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+    let mut files = files.into_iter();
+    let coverage = Coverage::load(&files.next().unwrap_or_else(|| usage_error("Missing coverage")))?;
+    let bytes = read(&files.next().unwrap_or_else(|| "space-invaders.rom".into()))?;
+    if origin != 0 {
+        entry_points[0] = origin;
+    }
+    output(output_file.as_deref(), &coverage.listing(&bytes, origin, &entry_points, &symbols))
+}
+
+fn coverage_merge(mut args: impl Iterator<Item = String>) -> Result<(), EmulatorError> {
+    let mut files = Vec::new();
+    let mut output_file = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage_error(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "-o" => output_file = Some(value()),
+            _ if arg.starts_with('-') => usage_error(&format!("Unknown option: {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+    let output_file = output_file.unwrap_or_else(|| usage_error("Missing output file"));
+    if files.is_empty() {
+        usage_error("Missing coverage");
+    }
+    let mut result = Coverage::new();
+    for file_name in &files {
+        result.merge(&Coverage::load(file_name)?);
+    }
+    result.save(&output_file)?;
+    eprintln!("Merged {} coverage maps into {}", files.len(), output_file);
+    Ok(())
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let result = match args.next().as_deref() {
+        Some("assemble") => assemble(args),
+        Some("disassemble") => disassemble(args),
+        Some("trace-diff") => trace_diff(args),
+        Some("coverage-listing") => coverage_listing(args),
+        Some("coverage-merge") => coverage_merge(args),
+        Some("--help") | Some("-h") => {
+            println!("{}", USAGE);
+            exit(0);
+        },
+        Some(command) => usage_error(&format!("Unknown command: {}", command)),
+        None => usage_error("Missing command"),
+    };
+    if let Err(e) = result {
+        fail(e);
+    }
+}
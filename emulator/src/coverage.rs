@@ -0,0 +1,178 @@
+//! Code coverage: which bytes ran as opcodes, which ran as operands and which were read
+//! as data. Enabled with `Emulator::enable_coverage()`.
+//!
+//! The map is saved as text, one line per address that was used, with a letter per use
+//! and `.` for the others:
+//!
+//! ```text
+//! 1a5f x..
+//! 1a60 .o.
+//! 1fe0 ..r
+//! ```
+//!
+//! `x` for an opcode, `o` for an operand and `r` for a data read. The maps of several
+//! runs add up with `merge()`.
+
+use std::fmt::Write;
+use std::fs;
+use std::ops::RangeInclusive;
+use crate::disassembler::Analysis;
+use crate::error::EmulatorError;
+use crate::memory::MEMORY_SIZE;
+use crate::symbols::Symbols;
+
+/// Executed as the first byte of an instruction
+pub const OPCODE: u8 = 1;
+/// Executed as the second or third byte of an instruction
+pub const OPERAND: u8 = 2;
+/// Read by an instruction, e.g. `LDA` or `MOV A,M`
+pub const READ: u8 = 4;
+
+const LETTERS: [(u8, char); 3] = [(OPCODE, 'x'), (OPERAND, 'o'), (READ, 'r')];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    flags: Vec<u8>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { flags: vec![0; MEMORY_SIZE] }
+    }
+
+    /// The uses of an address, a combination of `OPCODE`, `OPERAND` and `READ`.
+    pub fn flags(&self, address: u16) -> u8 {
+        self.flags[address as usize]
+    }
+
+    pub(crate) fn mark(&mut self, address: usize, flags: u8) {
+        self.flags[address & (MEMORY_SIZE - 1)] |= flags;
+    }
+
+    pub fn is_executed(&self, address: u16) -> bool {
+        self.flags(address) & (OPCODE | OPERAND) != 0
+    }
+
+    /// Add the uses of another run.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (flags, other) in self.flags.iter_mut().zip(&other.flags) {
+            *flags |= other;
+        }
+    }
+
+    /// Number of bytes in `range` with any of `flags`.
+    pub fn count(&self, range: RangeInclusive<u16>, flags: u8) -> usize {
+        range.filter(|address| self.flags(*address) & flags != 0).count()
+    }
+
+    /// The ranges of consecutive addresses in `range` for which `predicate` is true.
+    pub fn ranges(&self, range: RangeInclusive<u16>, predicate: impl Fn(u16) -> bool) -> Vec<RangeInclusive<u16>> {
+        let mut result: Vec<RangeInclusive<u16>> = Vec::new();
+        for address in range.filter(|address| predicate(*address)) {
+            match result.last_mut() {
+                Some(last) if *last.end() as u32 + 1 == address as u32 => *last = *last.start()..=address,
+                _ => result.push(address..=address),
+            }
+        }
+        result
+    }
+
+    /// The map in the text format described above.
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+        for (address, flags) in self.flags.iter().enumerate().filter(|(_, flags)| **flags != 0) {
+            let letters: String = LETTERS.iter()
+                .map(|(flag, letter)| if flags & flag != 0 { *letter } else { '.' })
+                .collect();
+            let _ = writeln!(result, "{:04x} {}", address, letters);
+        }
+        result
+    }
+
+    pub fn parse(text: &str) -> Result<Coverage, String> {
+        let mut result = Coverage::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("Invalid coverage at line {}: {}", i + 1, line);
+            let (address, letters) = line.split_once(' ').ok_or_else(error)?;
+            let address = usize::from_str_radix(address, 16).map_err(|_| error())?;
+            let mut flags = 0;
+            for letter in letters.trim().chars().filter(|c| *c != '.') {
+                let (flag, _) = LETTERS.iter().find(|(_, l)| *l == letter).ok_or_else(error)?;
+                flags |= flag;
+            }
+            if address >= MEMORY_SIZE {
+                return Err(error());
+            }
+            result.mark(address, flags);
+        }
+        Ok(result)
+    }
+
+    pub fn load(file_name: &str) -> Result<Coverage, EmulatorError> {
+        let io_error = |message: String| EmulatorError::Io { file_name: file_name.into(), message };
+        let text = fs::read_to_string(file_name).map_err(|e| io_error(e.to_string()))?;
+        Coverage::parse(&text).map_err(io_error)
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<(), EmulatorError> {
+        fs::write(file_name, self.to_text())
+            .map_err(|e| EmulatorError::Io { file_name: file_name.into(), message: e.to_string() })
+    }
+
+    /// The disassembly of `bytes`, loaded at `origin`, with the coverage of each line.
+    ///
+    /// The code is followed from `entry_points` and from the executed opcodes it doesn't
+    /// reach, so the targets of `PCHL` are disassembled too. The instructions that never ran are marked
+    /// `never executed`, the data that was read `read`, and a summary at the top lists the
+    /// never executed ranges of code. The labels are named with `symbols`.
+    pub fn listing(&self, bytes: &[u8], origin: u16, entry_points: &[u16], symbols: &Symbols) -> String {
+        let last = (origin as usize + bytes.len()).saturating_sub(1).min(MEMORY_SIZE - 1) as u16;
+        let mut entries = entry_points.to_vec();
+        let mut analysis = Analysis::new(bytes, origin, &entries);
+        let mut next = Some(origin);
+        while let Some(address) = next.and_then(|next| (next..=last)
+                .find(|address| self.flags(*address) & OPCODE != 0 && ! analysis.is_code(*address))) {
+            entries.push(address);
+            analysis = Analysis::new(bytes, origin, &entries);
+            next = address.checked_add(1);
+        }
+        analysis.set_symbols(symbols.clone());
+        let never_executed = self.ranges(origin..=last, |address| analysis.is_code(address) && ! self.is_executed(address));
+
+        let total = bytes.len().max(1) as f64;
+        let percent = |count: usize| count as f64 * 100.0 / total;
+        let executed = self.count(origin..=last, OPCODE | OPERAND);
+        let read = self.count(origin..=last, READ);
+        let never: usize = never_executed.iter().map(|range| range.len()).sum();
+        let mut result = format!("; Executed: {} bytes ({:.2}%)\n; Read as data: {} bytes ({:.2}%)\n\
+            ; Never executed code: {} bytes ({:.2}%) in {} ranges\n",
+            executed, percent(executed), read, percent(read), never, percent(never), never_executed.len());
+        for range in &never_executed {
+            let _ = writeln!(result, ";   {:04X}-{:04X} {}", range.start(), range.end(),
+                symbols.location(*range.start()).or_else(|| analysis.labels.range(..=*range.start()).next_back()
+                    .map(|(_, (_, name))| name.clone())).unwrap_or_default());
+        }
+        result.push('\n');
+        result.push_str(&analysis.listing_with(&|address, size| {
+            let addresses = address..=address.wrapping_add(size as u16 - 1);
+            if analysis.is_code(address) && ! self.is_executed(address) {
+                "  <<< never executed".to_string()
+            } else if ! analysis.is_code(address) && self.count(addresses, READ) > 0 {
+                "  <<< read".to_string()
+            } else {
+                String::new()
+            }
+        }));
+        result
+    }
+}
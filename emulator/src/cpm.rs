@@ -0,0 +1,108 @@
+//! A minimal CP/M environment, enough to run the classic 8080 diagnostic programs
+//! (`cpudiag`, 8080PRE, TST8080, CPUTEST, 8080EXM). These are `.COM` files loaded at 0x100
+//! which print through the BDOS entry point at 0x0005 and exit with a warm boot (a jump
+//! to 0x0000).
+//!
+//! Only the console output functions are supported:
+//! - 2: print the character in E
+//! - 9: print the string at DE, terminated by `$`
+//!
+//! `Cpm::run()` returns an error for a string without a `$` in the 64K of the memory, and
+//! for a program still running after `max_cycles` cycles.
+
+use crate::emulator::{Emulator, StepStatus};
+use crate::error::EmulatorError;
+use crate::memory::{Memory, MEMORY_SIZE};
+use crate::opcodes::{JMP, RET};
+
+/// Where CP/M loads programs
+pub const TPA: usize = 0x100;
+const WBOOT: usize = 0x0000;
+const BDOS: usize = 0x0005;
+/// Address of the actual BDOS code. Programs read it at 0x0006 to find the top of
+/// their memory, so it's also where their stack starts.
+const BDOS_RET: usize = 0xf000;
+/// Enough for all the exercisers but 8080EXM, which runs for about 24 billion cycles
+pub const DEFAULT_MAX_CYCLES: u64 = 1_000_000_000;
+
+pub struct Cpm {
+    emulator: Emulator,
+    output: String,
+    max_cycles: u64,
+}
+
+impl Cpm {
+    pub fn new(program: &[u8]) -> Cpm {
+        let mut memory = Memory::new();
+        for (i, byte) in program.iter().enumerate() {
+            memory.write(TPA + i, *byte);
+        }
+        // 0005: JMP BDOS_RET, where a RET returns to the caller once we've handled the call
+        memory.write(BDOS, JMP);
+        memory.write(BDOS + 1, (BDOS_RET & 0xff) as u8);
+        memory.write(BDOS + 2, (BDOS_RET >> 8) as u8);
+        memory.write(BDOS_RET, RET);
+        // Like the CCP, start the program with a stack holding a return address to the
+        // warm boot, so it can also exit with a RET
+        let mut emulator = Emulator::new(Box::new(memory), TPA);
+        emulator.state_mut().sp = BDOS_RET - 2;
+        Cpm {
+            emulator,
+            output: String::new(),
+            max_cycles: DEFAULT_MAX_CYCLES,
+        }
+    }
+
+    pub fn from_file(file_name: &str) -> Result<Cpm, EmulatorError> {
+        let program = std::fs::read(file_name).map_err(|e| EmulatorError::Io {
+            file_name: file_name.into(), message: e.to_string()
+        })?;
+        Ok(Cpm::new(&program))
+    }
+
+    /// The number of cycles after which `run()` gives up on the program.
+    pub fn set_max_cycles(&mut self, max_cycles: u64) {
+        self.max_cycles = max_cycles;
+    }
+
+    /// Run until the program warm boots (or halts) and return everything it printed.
+    pub fn run(&mut self) -> Result<&str, EmulatorError> {
+        loop {
+            if self.emulator.cycles() >= self.max_cycles {
+                return Err(EmulatorError::Cpm(format!("still running after {} cycles", self.max_cycles)));
+            }
+            let registers = self.emulator.registers();
+            match registers.pc {
+                WBOOT => break,
+                BDOS => {
+                    match registers.c {
+                        0 => break,
+                        2 => self.output.push(registers.e as char),
+                        9 => {
+                            let memory = self.emulator.memory();
+                            let start = Memory::to_word(registers.e, registers.d);
+                            let length = (0..MEMORY_SIZE)
+                                .position(|i| memory.read((start + i) % MEMORY_SIZE) == b'$')
+                                .ok_or_else(|| EmulatorError::Cpm(format!("no $ at the end of the string at {:04x}", start)))?;
+                            self.output.extend((0..length).map(|i| memory.read((start + i) % MEMORY_SIZE) as char));
+                        },
+                        _ => {},
+                    }
+                },
+                _ => {},
+            }
+            if self.emulator.step()?.status == StepStatus::Halted {
+                break;
+            }
+        }
+        Ok(&self.output)
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+}
@@ -0,0 +1,228 @@
+//! Crash dumps: what the machine was doing when the emulation failed, written to a text
+//! file so that each crash can be studied and replayed.
+//!
+//! The emulator keeps the last instructions it ran, with their registers, and the last
+//! accesses to the ports in ring buffers (see `Emulator::set_history_size()`).
+//! `Emulator::crash_dump()` puts them together with a snapshot of the machine:
+//!
+//! ```text
+//! ; 8080 crash dump
+//! Error: Unsupported IN port 7 (db at 1a62)    a:00 b:00 ...
+//!
+//! Last 256 instructions, the oldest first:
+//! 0000042316 1A5F: 3A C0 20  LDA     20C0H      A=00 BC=0000 DE=1B00 HL=2402 SP=23FE F=46 .Z.P.
+//! ...
+//!
+//! Stack, from SP=23FE:
+//! 23FE: 0AE8  returns after 0AE5: CALL    1A5FH
+//!
+//! Last 64 port accesses, the oldest first:
+//! 0000042300 1A4C: OUT 06 = 00
+//!
+//! Snapshot:
+//! 3830383053...
+//! ```
+//!
+//! The instructions are in the format of the traces (see `trace`), and the stack words
+//! that follow a `CALL` or a `RST` are shown as return addresses. The snapshot is the one
+//! of `snapshot`, in hexadecimal: `Snapshot::load()` takes crash dumps too, to start again
+//! from the state of the crash, e.g. with `headless --snapshot crash-dump.txt`.
+
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use crate::disassembler::decode;
+use crate::error::EmulatorError;
+use crate::memory::MEMORY_SIZE;
+use crate::snapshot::Snapshot;
+use crate::state::Registers;
+use crate::symbols::Symbols;
+use crate::trace::TraceEntry;
+
+/// The first line of the crash dumps
+pub const MAGIC: &str = "; 8080 crash dump";
+/// Where the frontends write the crash dumps by default
+pub const CRASH_DUMP_FILE: &str = "crash-dump.txt";
+/// The default number of instructions kept by the emulator
+pub const DEFAULT_HISTORY: usize = 256;
+/// The number of port accesses kept by the emulator
+pub const PORT_HISTORY: usize = 64;
+/// The number of words of the stack shown
+const STACK_WORDS: usize = 16;
+const SNAPSHOT_HEADER: &str = "Snapshot:";
+
+/// Keeps the last `capacity` values, cheaply enough to push one per instruction.
+#[derive(Clone, Debug)]
+pub(crate) struct Ring<T> {
+    values: Vec<T>,
+    capacity: usize,
+    /// Where the next value goes once the buffer is full
+    next: usize,
+}
+
+impl<T: Clone> Ring<T> {
+    pub(crate) fn new(capacity: usize) -> Ring<T> {
+        Ring { values: Vec::with_capacity(capacity), capacity, next: 0 }
+    }
+
+    pub(crate) fn push(&mut self, value: T) {
+        if self.values.len() < self.capacity {
+            self.values.push(value);
+        } else if self.capacity > 0 {
+            self.values[self.next] = value;
+            self.next = (self.next + 1) % self.capacity;
+        }
+    }
+
+    /// The values, the oldest first.
+    pub(crate) fn to_vec(&self) -> Vec<T> {
+        let (newest, oldest) = self.values.split_at(self.next);
+        oldest.iter().chain(newest).cloned().collect()
+    }
+}
+
+/// An instruction of the history, decoded only when it's shown.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HistoryEntry {
+    pub cycles: u64,
+    /// The opcode and the two bytes after it, whatever the size of the instruction
+    pub bytes: [u8; 3],
+    pub registers: Registers,
+}
+
+impl HistoryEntry {
+    pub(crate) fn to_trace_entry(self) -> TraceEntry {
+        TraceEntry {
+            cycles: self.cycles,
+            instruction: decode(&self.bytes, self.registers.pc as u16),
+            registers: self.registers,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PortAccess {
+    In,
+    Out,
+}
+
+/// An `IN` or an `OUT`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PortEvent {
+    /// The cycles run by the emulator before the instruction
+    pub cycles: u64,
+    pub pc: u16,
+    pub access: PortAccess,
+    pub port: u8,
+    /// The value read or written
+    pub value: u8,
+}
+
+impl fmt::Display for PortEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.access {
+            PortAccess::In => "IN ",
+            PortAccess::Out => "OUT",
+        };
+        write!(f, "{:010} {:04X}: {} {:02X} = {:02X}", self.cycles, self.pc, access, self.port, self.value)
+    }
+}
+
+/// A word of the stack.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StackEntry {
+    pub address: u16,
+    pub value: u16,
+    /// The address of the `CALL` or `RST` before `value`, if there's one, which makes it
+    /// a return address
+    pub caller: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrashDump {
+    pub error: EmulatorError,
+    /// The last instructions, the oldest first
+    pub history: Vec<TraceEntry>,
+    /// The last port accesses, the oldest first
+    pub ports: Vec<PortEvent>,
+    pub snapshot: Snapshot,
+}
+
+impl CrashDump {
+    /// The words of the stack, from SP up.
+    pub fn stack(&self) -> Vec<StackEntry> {
+        let memory = &self.snapshot.memory;
+        let byte = |address: u16| memory[address as usize % MEMORY_SIZE];
+        let sp = self.snapshot.registers.sp;
+        (sp..(sp + 2 * STACK_WORDS).min(MEMORY_SIZE - 1)).step_by(2).map(|address| {
+            let address = address as u16;
+            let value = byte(address) as u16 | (byte(address + 1) as u16) << 8;
+            // CALL (and its undocumented copies) and the conditional calls, or RST
+            let call = value.wrapping_sub(3);
+            let caller = if byte(call) & 0xcf == 0xcd || byte(call) & 0xc7 == 0xc4 {
+                Some(call)
+            } else if byte(value.wrapping_sub(1)) & 0xc7 == 0xc7 {
+                Some(value.wrapping_sub(1))
+            } else {
+                None
+            };
+            StackEntry { address, value, caller }
+        }).collect()
+    }
+
+    /// The dump in the format described above, with the operands and the callers named
+    /// with `symbols`.
+    pub fn to_text(&self, symbols: &Symbols) -> String {
+        let label = |address: u16| symbols.label(address);
+        let mut result = format!("{}\nError: {}\n\nLast {} instructions, the oldest first:\n",
+            MAGIC, self.error, self.history.len());
+        for entry in &self.history {
+            let _ = writeln!(result, "{}", entry.format(&label));
+        }
+        let _ = writeln!(result, "\nStack, from SP={:04X}:", self.snapshot.registers.sp);
+        for entry in self.stack() {
+            let _ = write!(result, "{:04X}: {:04X}", entry.address, entry.value);
+            if let Some(caller) = entry.caller {
+                let start = caller as usize;
+                let bytes = &self.snapshot.memory[start..(start + 3).min(MEMORY_SIZE)];
+                let location = symbols.location(caller).map(|l| format!(" <{}>", l)).unwrap_or_default();
+                let _ = write!(result, "  returns after {:04X}{}: {}", caller, location,
+                    decode(bytes, caller).format(&label));
+            }
+            result.push('\n');
+        }
+        let _ = writeln!(result, "\nLast {} port accesses, the oldest first:", self.ports.len());
+        for event in &self.ports {
+            let _ = writeln!(result, "{}", event);
+        }
+        let _ = writeln!(result, "\n{}", SNAPSHOT_HEADER);
+        for chunk in self.snapshot.to_bytes().chunks(32) {
+            let line: String = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let _ = writeln!(result, "{}", line);
+        }
+        result
+    }
+
+    pub fn save(&self, file_name: &str, symbols: &Symbols) -> Result<(), EmulatorError> {
+        fs::write(file_name, self.to_text(symbols))
+            .map_err(|e| EmulatorError::Io { file_name: file_name.into(), message: e.to_string() })
+    }
+}
+
+/// The snapshot of a crash dump.
+pub fn parse_snapshot(text: &str) -> Result<Snapshot, EmulatorError> {
+    let invalid = |message: &str| EmulatorError::InvalidSnapshot(format!("crash dump: {}", message));
+    let mut lines = text.lines().skip_while(|line| line.trim() != SNAPSHOT_HEADER);
+    if lines.next().is_none() {
+        return Err(invalid("no snapshot"));
+    }
+    let mut bytes = Vec::new();
+    for line in lines.map(str::trim).filter(|line| ! line.is_empty()) {
+        for i in (0..line.len()).step_by(2) {
+            let byte = line.get(i..i + 2).and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| invalid("invalid hexadecimal"))?;
+            bytes.push(byte);
+        }
+    }
+    Snapshot::from_bytes(&bytes)
+}
@@ -0,0 +1,392 @@
+//! A Debug Adapter Protocol server, to debug 8080 programs from an editor (e.g. VS Code)
+//! at the level of their source, with the listing of the assembler to map PCs to lines.
+//!
+//! The `launch` request takes these arguments:
+//! - `rom`: a ROM to run on the Space Invaders board, with its interrupts, or
+//! - `program` and `origin` (default: 0x100): a program to load and start at `origin`,
+//!   without interrupts,
+//! - `listing`: the listing of the ROM or program, e.g. `cpudiag.lst` or one written by
+//!   `tools assemble -l`,
+//! - `source`: the source file the listing was made from; without it, the editor shows
+//!   the listing,
+//! - `symbols`: a symbol file (see `symbols`) naming the routines the listing doesn't
+//!   have labels for, and the addresses of the conditions and of `evaluate`,
+//! - `stopOnEntry`, and `interrupts` to turn the interrupts of the board on or off.
+//!
+//! The stack frames are the calls followed by `Debugger` since the start, named after the
+//! labels of the listing. The variables are the registers and the flags, which can be
+//! changed. Step in and step over run instructions until PC reaches another line of the
+//! source, step over and step out run called subroutines to their end.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::debugger::{self, parse_number, Debugger, Stop};
+use crate::disassembler::hex;
+use crate::emulator::Emulator;
+use crate::error::EmulatorError;
+use crate::memory::Memory;
+use crate::source_map::SourceMap;
+use crate::symbols::Symbols;
+
+/// The variable references of the two scopes
+const REGISTERS: i64 = 1;
+const FLAGS: i64 = 2;
+
+const REGISTER_VARIABLES: [&str; 12] = ["A", "B", "C", "D", "E", "H", "L", "BC", "DE", "HL", "SP", "PC"];
+const FLAG_VARIABLES: [&str; 6] = ["S", "Z", "AC", "P", "CY", "EI"];
+
+/// What to do once a request has its response.
+enum Then {
+    Nothing,
+    /// Send the `initialized` event, the client can send its breakpoints
+    Initialized,
+    /// Start the program after the configuration
+    Start,
+    Run(Execution),
+    Quit,
+}
+
+#[derive(Clone, Copy)]
+enum Execution { Continue, Next, StepIn, StepOut }
+
+/// The break handle of the debugger, for the thread reading the requests to stop a run
+/// on a `pause` request. There's no debugger until the `launch` request.
+type BreakHandle = Arc<Mutex<Option<Arc<AtomicBool>>>>;
+
+/// Serve a client until it disconnects, on stdin and stdout or on a TCP connection.
+pub fn serve(input: impl Read + Send + 'static, output: impl Write) -> io::Result<()> {
+    let break_handle = BreakHandle::default();
+    let requests = spawn_reader(input, break_handle.clone());
+    let mut session = Session {
+        output,
+        seq: 0,
+        debugger: None,
+        break_handle,
+        source_map: SourceMap::default(),
+        source: Value::Null,
+        line_breakpoints: Vec::new(),
+        stop_on_entry: false,
+    };
+    for request in requests {
+        if request["type"] != "request" {
+            continue;
+        }
+        let command = request["command"].as_str().unwrap_or_default().to_string();
+        let (body, then) = match session.handle(&command, &request["arguments"]) {
+            Ok(result) => result,
+            Err(message) => {
+                session.send(json!({
+                    "type": "response", "request_seq": request["seq"], "success": false,
+                    "command": command, "message": message,
+                    "body": { "error": { "id": 1, "format": message } },
+                }))?;
+                continue;
+            }
+        };
+        session.send(json!({
+            "type": "response", "request_seq": request["seq"], "success": true, "command": command, "body": body,
+        }))?;
+        match then {
+            Then::Nothing => {},
+            Then::Initialized => session.event("initialized", json!({}))?,
+            Then::Start if session.stop_on_entry => session.stopped(Ok(Stop::Done), "entry")?,
+            Then::Start => session.execute(Execution::Continue)?,
+            Then::Run(execution) => session.execute(execution)?,
+            Then::Quit => break,
+        }
+    }
+    Ok(())
+}
+
+/// Read the requests in a thread, so that a `pause` can stop the program while the main
+/// thread runs it.
+fn spawn_reader(input: impl Read + Send + 'static, break_handle: BreakHandle) -> Receiver<Value> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        while let Some(message) = read_message(&mut input) {
+            if message["command"] == "pause" {
+                if let Some(handle) = break_handle.lock().unwrap().as_ref() {
+                    handle.store(true, Ordering::Relaxed);
+                }
+            }
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// A message: headers, an empty line, then `Content-Length` bytes of JSON.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+struct Session<W: Write> {
+    output: W,
+    seq: u64,
+    debugger: Option<Debugger>,
+    break_handle: BreakHandle,
+    source_map: SourceMap,
+    /// The `Source` of the frames and breakpoints
+    source: Value,
+    /// The addresses of the breakpoints set on lines
+    line_breakpoints: Vec<u16>,
+    stop_on_entry: bool,
+}
+
+impl<W: Write> Session<W> {
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn stopped(&mut self, stop: Result<Stop, EmulatorError>, step_reason: &str) -> io::Result<()> {
+        let (reason, text) = match stop {
+            Ok(Stop::Done) | Ok(Stop::Limit) => (step_reason, None),
+            Ok(Stop::Breakpoint(_)) => ("breakpoint", None),
+            Ok(Stop::Interrupted) => ("pause", None),
+            Ok(Stop::Halted) => ("pause", Some("Halted".to_string())),
+            Ok(Stop::Watchpoint(event)) => ("data breakpoint", Some(event.to_string())),
+            Err(e) => ("exception", Some(e.to_string())),
+        };
+        self.event("stopped", json!({
+            "reason": reason, "description": text, "text": text, "threadId": 1, "allThreadsStopped": true,
+        }))
+    }
+
+    fn debugger(&mut self) -> Result<&mut Debugger, String> {
+        self.debugger.as_mut().ok_or_else(|| "No program, launch one first".to_string())
+    }
+
+    fn handle(&mut self, command: &str, arguments: &Value) -> Result<(Value, Then), String> {
+        Ok(match command {
+            "initialize" => (json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsSetVariable": true,
+                "supportsEvaluateForHovers": true,
+            }), Then::Nothing),
+            "launch" => {
+                self.launch(arguments)?;
+                (json!({}), Then::Initialized)
+            },
+            "setBreakpoints" => (self.set_breakpoints(arguments)?, Then::Nothing),
+            "configurationDone" => (json!({}), Then::Start),
+            "threads" => (json!({ "threads": [{ "id": 1, "name": "8080" }] }), Then::Nothing),
+            "stackTrace" => (self.stack_trace()?, Then::Nothing),
+            "scopes" => (json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS, "expensive": false },
+            ]}), Then::Nothing),
+            "variables" => (self.variables(arguments["variablesReference"].as_i64())?, Then::Nothing),
+            "setVariable" => {
+                let name = arguments["name"].as_str().unwrap_or_default();
+                let value = parse_number(arguments["value"].as_str().unwrap_or_default())?;
+                self.debugger()?.set_register(name, value)?;
+                (json!({ "value": self.value(name)? }), Then::Nothing)
+            },
+            "evaluate" => {
+                let expression = arguments["expression"].as_str().unwrap_or_default().trim();
+                (json!({ "result": self.value(expression)?, "variablesReference": 0 }), Then::Nothing)
+            },
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                self.debugger()?;
+                let execution = match command {
+                    "continue" => Execution::Continue,
+                    "next" => Execution::Next,
+                    "stepIn" => Execution::StepIn,
+                    _ => Execution::StepOut,
+                };
+                (json!({ "allThreadsContinued": true }), Then::Run(execution))
+            },
+            // The reader thread stopped the run already
+            "pause" => (json!({}), Then::Nothing),
+            "disconnect" | "terminate" => (json!({}), Then::Quit),
+            _ => return Err(format!("Unsupported request: {}", command)),
+        })
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let string = |name: &str| arguments[name].as_str().map(str::to_string);
+        let mut debugger = match (string("rom"), string("program")) {
+            (Some(rom), _) => Debugger::new(Emulator::new_space_invaders_from(&rom).map_err(|e| e.to_string())?),
+            (None, Some(program)) => {
+                let origin = match &arguments["origin"] {
+                    Value::Number(n) => n.as_u64().ok_or("Invalid origin")? as u16,
+                    Value::String(s) => parse_number(s)?,
+                    _ => 0x100,
+                };
+                let mut memory = Memory::new();
+                memory.read_file(&program, origin as usize).map_err(|e| e.to_string())?;
+                let mut debugger = Debugger::new(Emulator::new(Box::new(memory), origin as usize));
+                debugger.set_interrupts(false);
+                debugger
+            },
+            (None, None) => return Err("Missing rom or program".into()),
+        };
+        if let Some(interrupts) = arguments["interrupts"].as_bool() {
+            debugger.set_interrupts(interrupts);
+        }
+        if let Some(listing) = string("listing") {
+            let source = string("source");
+            self.source_map = SourceMap::load(&listing, source.is_none()).map_err(|e| e.to_string())?;
+            let path = source.unwrap_or(listing);
+            let name = std::path::Path::new(&path).file_name().map(|n| n.to_string_lossy().into_owned());
+            self.source = json!({ "name": name, "path": path });
+        }
+        if let Some(symbols) = string("symbols") {
+            debugger.set_symbols(Symbols::load(&symbols).map_err(|e| e.to_string())?);
+        }
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        *self.break_handle.lock().unwrap() = Some(debugger.break_handle());
+        self.debugger = Some(debugger);
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let debugger = self.debugger.as_mut().ok_or("No program, launch one first")?;
+        for address in self.line_breakpoints.drain(..) {
+            debugger.remove_breakpoint(address);
+        }
+        let mut result = Vec::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0) as usize;
+            let condition = match breakpoint["condition"].as_str().filter(|c| ! c.trim().is_empty()) {
+                Some(condition) => match debugger.parse_condition(condition) {
+                    Ok(condition) => Some(condition),
+                    Err(message) => {
+                        result.push(json!({ "verified": false, "line": line, "message": message }));
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            match self.source_map.address(line) {
+                Some((line, address)) => {
+                    debugger.add_breakpoint(address, condition);
+                    self.line_breakpoints.push(address);
+                    result.push(json!({ "verified": true, "line": line, "source": self.source }));
+                },
+                None => result.push(json!({ "verified": false, "line": line, "message": "No code at or after this line" })),
+            }
+        }
+        Ok(json!({ "breakpoints": result }))
+    }
+
+    /// The name of the subroutine at `address`
+    fn routine(&self, address: u16) -> String {
+        let symbol = || self.debugger.as_ref().and_then(|debugger| debugger.symbols().label(address));
+        self.source_map.label(address).map(str::to_string).or_else(symbol)
+            .unwrap_or_else(|| format!("{:04X}", address))
+    }
+
+    fn frame(&self, id: usize, name: String, pc: u16) -> Value {
+        let mut frame = json!({
+            "id": id, "name": name, "line": 0, "column": 0,
+            "instructionPointerReference": format!("0x{:04X}", pc),
+        });
+        if let Some(line) = self.source_map.line(pc) {
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+            frame["source"] = self.source.clone();
+        }
+        frame
+    }
+
+    /// The current PC, then the caller of each call, innermost first.
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let debugger = self.debugger.as_ref().ok_or("No program, launch one first")?;
+        let calls = debugger.call_stack();
+        let name = |index: usize| match index {
+            0 => {
+                let address = calls.first().map_or(debugger.pc(), |call| call.caller);
+                self.source_map.label_before(address).map(|(name, _)| name.to_string())
+                    .or_else(|| debugger.symbols().routine(address).map(|symbol| symbol.name.clone()))
+                    .unwrap_or_else(|| "main".to_string())
+            },
+            _ => self.routine(calls[index - 1].target),
+        };
+        let mut frames = vec![self.frame(0, name(calls.len()), debugger.pc())];
+        for (index, call) in calls.iter().enumerate().rev() {
+            frames.push(self.frame(calls.len() - index, name(index), call.caller));
+        }
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    /// A register, a flag or a byte of memory (`[2400]` or `[isrDelay]`), in hexadecimal.
+    fn value(&mut self, name: &str) -> Result<String, String> {
+        let debugger = self.debugger()?;
+        if let Some(address) = name.strip_prefix('[').and_then(|n| n.strip_suffix(']')) {
+            return Ok(hex(debugger.read_memory(debugger.parse_address(address.trim())?, 1)[0] as u16, 2));
+        }
+        let value = debugger::register(&debugger.registers(), name).ok_or(format!("Unknown register: {}", name))?;
+        Ok(match name.to_ascii_uppercase().as_str() {
+            flag if FLAG_VARIABLES.contains(&flag) => value.to_string(),
+            pair if pair.len() == 2 || pair == "PSW" => hex(value, 4),
+            _ => hex(value, 2),
+        })
+    }
+
+    fn variables(&mut self, reference: Option<i64>) -> Result<Value, String> {
+        let names: &[&str] = match reference {
+            Some(REGISTERS) => &REGISTER_VARIABLES,
+            Some(FLAGS) => &FLAG_VARIABLES,
+            _ => return Err("Unknown variables".into()),
+        };
+        let variables = names.iter()
+            .map(|name| Ok(json!({ "name": name, "value": self.value(name)?, "variablesReference": 0 })))
+            .collect::<Result<Vec<Value>, String>>()?;
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn execute(&mut self, execution: Execution) -> io::Result<()> {
+        let source_map = &self.source_map;
+        let debugger = match self.debugger.as_mut() {
+            Some(debugger) => debugger,
+            None => return Ok(()),
+        };
+        let line = source_map.line(debugger.pc());
+        let depth = debugger.call_stack().len();
+        // Another line, or the same one from another call
+        let new_line = move |debugger: &Debugger| source_map.line(debugger.pc())
+            .is_some_and(|l| Some(l) != line || debugger.call_stack().len() != depth);
+        let stop = match execution {
+            Execution::Continue => debugger.run(None),
+            Execution::StepIn => debugger.run_until(None, new_line),
+            Execution::Next => debugger.run_until(None, |debugger| debugger.call_stack().len() <= depth && new_line(debugger)),
+            Execution::StepOut => debugger.step_out(None),
+        };
+        self.stopped(stop, "step")
+    }
+}
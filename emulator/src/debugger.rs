@@ -0,0 +1,615 @@
+//! A debugger around `Emulator::step`, for frontends to drive: the console REPL in
+//! `bin/debugger.rs`, and the GDB and DAP servers.
+//!
+//! `Debugger` owns the emulator and runs it one instruction at a time, with breakpoints
+//! checked before each instruction. It raises the two interrupts of each frame at the
+//! same cycles as `Emulator::run_one_frame()`, so the game runs as it normally does;
+//! programs that don't expect interrupts (e.g. CP/M ones) can turn them off.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::disassembler::{self, Flow, Instruction};
+use crate::emulator::{Emulator, StepStatus, CYCLES_PER_FRAME};
+use crate::error::EmulatorError;
+use crate::hooks::{Access, HookId, MemoryEvent};
+use crate::state::Registers;
+use crate::symbols::Symbols;
+
+/// Why the debugger gave the control back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Stop {
+    /// The step, step over, step out or run to address is done
+    Done,
+    /// A breakpoint was hit, the instruction at that address hasn't run yet
+    Breakpoint(u16),
+    /// The CPU executed HLT and no interrupt can wake it up
+    Halted,
+    /// Someone called `request_break()`, or the emulator is paused
+    Interrupted,
+    /// The maximum number of instructions ran
+    Limit,
+    /// The instruction that just ran made that access to a watched range
+    Watchpoint(MemoryEvent),
+}
+
+/// Compares a register, flag or memory byte to a value, e.g. `A == 3F`, `HL >= 2400`,
+/// `[20C0] != 0` or `Z == 1`. Several comparisons can be combined with `&&`. The numbers
+/// are hexadecimal, register names take precedence (`0A` is a number, `A` the register).
+/// With `parse_with_symbols()`, the addresses and numbers can be symbols too, e.g.
+/// `[isrDelay] == 0`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    text: String,
+    comparisons: Vec<(Value, Comparison, Value)>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Comparison { Equal, NotEqual, Less, LessOrEqual, Greater, GreaterOrEqual }
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Register(String),
+    Memory(u16),
+    Number(u16),
+}
+
+/// The names accepted by `Condition` and `Debugger::set_register()`
+pub const REGISTER_NAMES: [&str; 20] = ["A", "B", "C", "D", "E", "H", "L", "F", "BC", "DE", "HL", "SP", "PC",
+    "PSW", "S", "Z", "AC", "P", "CY", "EI"];
+
+pub fn parse_number(s: &str) -> Result<u16, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    let digits = digits.strip_suffix(['h', 'H']).unwrap_or(digits);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid number: {}", s))
+}
+
+/// The value of a register, a pair or a flag.
+pub fn register(registers: &Registers, name: &str) -> Option<u16> {
+    let pair = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+    let flag = |bit: u8| (registers.flags >> bit & 1) as u16;
+    Some(match name.to_ascii_uppercase().as_str() {
+        "A" => registers.a as u16,
+        "B" => registers.b as u16,
+        "C" => registers.c as u16,
+        "D" => registers.d as u16,
+        "E" => registers.e as u16,
+        "H" => registers.h as u16,
+        "L" => registers.l as u16,
+        "F" => registers.flags as u16,
+        "BC" => pair(registers.b, registers.c),
+        "DE" => pair(registers.d, registers.e),
+        "HL" => pair(registers.h, registers.l),
+        "SP" => registers.sp as u16,
+        "PC" => registers.pc as u16,
+        "PSW" => pair(registers.a, registers.flags),
+        "S" => flag(7),
+        "Z" => flag(6),
+        "AC" => flag(4),
+        "P" => flag(2),
+        "CY" => flag(0),
+        "EI" => registers.enable_interrupts as u16,
+        _ => return None,
+    })
+}
+
+/// Change a register, a pair or a flag.
+pub fn set_register(registers: &mut Registers, name: &str, value: u16) -> Result<(), String> {
+    let (high, low) = ((value >> 8) as u8, value as u8);
+    let mut flag = |bit: u8| registers.flags = registers.flags & ! (1 << bit) | ((value != 0) as u8) << bit;
+    match name.to_ascii_uppercase().as_str() {
+        "S" => flag(7),
+        "Z" => flag(6),
+        "AC" => flag(4),
+        "P" => flag(2),
+        "CY" => flag(0),
+        "A" => registers.a = low,
+        "B" => registers.b = low,
+        "C" => registers.c = low,
+        "D" => registers.d = low,
+        "E" => registers.e = low,
+        "H" => registers.h = low,
+        "L" => registers.l = low,
+        "F" => registers.flags = low,
+        "BC" => { registers.b = high; registers.c = low; },
+        "DE" => { registers.d = high; registers.e = low; },
+        "HL" => { registers.h = high; registers.l = low; },
+        "SP" => registers.sp = value as usize,
+        "PC" => registers.pc = value as usize,
+        "PSW" => { registers.a = high; registers.flags = low; },
+        "EI" => registers.enable_interrupts = value != 0,
+        _ => return Err(format!("Unknown register: {}", name)),
+    }
+    Ok(())
+}
+
+impl Value {
+    fn parse(s: &str, symbols: &Symbols) -> Result<Value, String> {
+        let s = s.trim();
+        let number = |s: &str| if symbols.is_empty() { parse_number(s) } else { symbols.parse_address(s) };
+        if let Some(address) = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            Ok(Value::Memory(number(address.trim())?))
+        } else if REGISTER_NAMES.contains(&s.to_ascii_uppercase().as_str()) {
+            Ok(Value::Register(s.to_ascii_uppercase()))
+        } else {
+            Ok(Value::Number(number(s)?))
+        }
+    }
+
+    fn evaluate(&self, emulator: &Emulator, registers: &Registers) -> u16 {
+        match self {
+            Value::Register(name) => register(registers, name).unwrap_or(0),
+            Value::Memory(address) => emulator.read_memory(*address) as u16,
+            Value::Number(n) => *n,
+        }
+    }
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition, String> {
+        Condition::parse_with_symbols(text, &Symbols::new())
+    }
+
+    pub fn parse_with_symbols(text: &str, symbols: &Symbols) -> Result<Condition, String> {
+        let mut comparisons = Vec::new();
+        for part in text.split("&&") {
+            // The two character operators first, so that <= isn't read as <
+            let operators = [("==", Comparison::Equal), ("!=", Comparison::NotEqual),
+                ("<=", Comparison::LessOrEqual), (">=", Comparison::GreaterOrEqual),
+                ("<", Comparison::Less), (">", Comparison::Greater)];
+            let (left, comparison, right) = operators.iter()
+                .find_map(|(operator, comparison)| part.split_once(operator).map(|(l, r)| (l, *comparison, r)))
+                .ok_or_else(|| format!("Invalid condition: {}", part.trim()))?;
+            comparisons.push((Value::parse(left, symbols)?, comparison, Value::parse(right, symbols)?));
+        }
+        Ok(Condition { text: text.trim().to_string(), comparisons })
+    }
+
+    pub fn is_true(&self, emulator: &Emulator) -> bool {
+        let registers = emulator.registers();
+        self.comparisons.iter().all(|(left, comparison, right)| {
+            let (left, right) = (left.evaluate(emulator, &registers), right.evaluate(emulator, &registers));
+            match comparison {
+                Comparison::Equal => left == right,
+                Comparison::NotEqual => left != right,
+                Comparison::Less => left < right,
+                Comparison::LessOrEqual => left <= right,
+                Comparison::Greater => left > right,
+                Comparison::GreaterOrEqual => left >= right,
+            }
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// Only stop when it's true
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+    /// How many times the debugger stopped there
+    pub hits: u64,
+}
+
+/// Stops after the instructions that access `range`, built on `Emulator::add_hook()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Watchpoint {
+    /// The hook, which identifies the watchpoint
+    pub id: HookId,
+    pub access: Access,
+    pub range: RangeInclusive<u16>,
+}
+
+/// A subroutine call that hasn't returned yet, made by a CALL, a RST or an interrupt.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Call {
+    /// The address of the call instruction, or of the instruction that was interrupted
+    pub caller: u16,
+    /// The address of the subroutine
+    pub target: u16,
+    /// Where the subroutine returns to
+    pub return_address: u16,
+    /// SP once the return address is pushed
+    pub sp: u16,
+}
+
+pub struct Debugger {
+    emulator: Emulator,
+    breakpoints: BTreeMap<u16, Breakpoint>,
+    interrupts: bool,
+    /// The cycles run in the current frame, and whether the interrupt of its middle
+    /// was raised
+    frame_cycles: u64,
+    half_frame: bool,
+    frames: u64,
+    instructions: u64,
+    break_requested: Arc<AtomicBool>,
+    call_stack: Vec<Call>,
+    symbols: Symbols,
+    watchpoints: Vec<Watchpoint>,
+    /// The first watched access of the instruction running, set by the hooks
+    watch_hit: Arc<Mutex<Option<MemoryEvent>>>,
+}
+
+impl Debugger {
+    pub fn new(emulator: Emulator) -> Debugger {
+        Debugger {
+            emulator,
+            breakpoints: BTreeMap::new(),
+            interrupts: true,
+            frame_cycles: 0,
+            half_frame: false,
+            frames: 0,
+            instructions: 0,
+            break_requested: Arc::new(AtomicBool::new(false)),
+            call_stack: Vec::new(),
+            symbols: Symbols::new(),
+            watchpoints: Vec::new(),
+            watch_hit: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The names the views and `parse_address()` use.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// An address typed by the user: a symbol, a symbol and an offset, or a number.
+    pub fn parse_address(&self, s: &str) -> Result<u16, String> {
+        self.symbols.parse_address(s)
+    }
+
+    pub fn parse_condition(&self, text: &str) -> Result<Condition, String> {
+        Condition::parse_with_symbols(text, &self.symbols)
+    }
+
+    /// `address` followed by its location in the symbols, e.g. "01f2 <DrawShieldPl1+3>".
+    pub fn describe(&self, address: u16) -> String {
+        match self.symbols.location(address) {
+            Some(location) => format!("{:04x} <{}>", address, location),
+            None => format!("{:04x}", address),
+        }
+    }
+
+    /// An instruction with the names of its operands.
+    pub fn format_instruction(&self, instruction: &Instruction) -> String {
+        instruction.format(&|address| self.symbols.label(address))
+    }
+
+    pub fn emulator(&self) -> &Emulator {
+        &self.emulator
+    }
+
+    pub fn emulator_mut(&mut self) -> &mut Emulator {
+        &mut self.emulator
+    }
+
+    pub fn into_emulator(self) -> Emulator {
+        self.emulator
+    }
+
+    /// Whether to raise the interrupts of the Space Invaders board, on by default.
+    pub fn set_interrupts(&mut self, interrupts: bool) {
+        self.interrupts = interrupts;
+    }
+
+    /// Number of frames and instructions run by the debugger.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.emulator.registers().pc as u16
+    }
+
+    /// Stop a `run()` from another thread (e.g. on Ctrl-C).
+    pub fn break_handle(&self) -> Arc<AtomicBool> {
+        self.break_requested.clone()
+    }
+
+    pub fn request_break(&self) {
+        self.break_requested.store(true, Ordering::Relaxed);
+    }
+
+    //
+    // Breakpoints
+    //
+
+    /// Add or replace the breakpoint at `address`.
+    pub fn add_breakpoint(&mut self, address: u16, condition: Option<Condition>) {
+        self.breakpoints.insert(address, Breakpoint { address, condition, enabled: true, hits: 0 });
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn enable_breakpoint(&mut self, address: u16, enabled: bool) -> bool {
+        match self.breakpoints.get_mut(&address) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.values()
+    }
+
+    /// Watch the reads or writes (or executions, for a range of addresses) of `range`.
+    pub fn add_watchpoint(&mut self, access: Access, range: RangeInclusive<u16>) -> HookId {
+        let hit = self.watch_hit.clone();
+        let id = self.emulator.add_hook(access, range.clone(), move |event| {
+            hit.lock().unwrap().get_or_insert(*event);
+        });
+        self.watchpoints.push(Watchpoint { id, access, range });
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: HookId) -> bool {
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+        self.emulator.remove_hook(id)
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Whether execution should stop before the instruction at PC.
+    fn breakpoint_hit(&mut self) -> Option<u16> {
+        let pc = self.pc();
+        let breakpoint = self.breakpoints.get(&pc)?;
+        if breakpoint.enabled && breakpoint.condition.as_ref().is_none_or(|c| c.is_true(&self.emulator)) {
+            self.breakpoints.get_mut(&pc).unwrap().hits += 1;
+            Some(pc)
+        } else {
+            None
+        }
+    }
+
+    //
+    // Execution
+    //
+
+    /// The calls that haven't returned yet, the innermost last. They're followed from the
+    /// start of the debugger: a return pops the calls whose return address was above SP.
+    pub fn call_stack(&self) -> &[Call] {
+        &self.call_stack
+    }
+
+    /// Pop the calls that returned, then push one if a call just pushed its return address.
+    fn track_call(&mut self, caller: u16, sp: u16, return_address: u16, is_call: bool) {
+        let registers = self.emulator.registers();
+        let new_sp = registers.sp as u16;
+        while self.call_stack.last().is_some_and(|call| call.sp < new_sp) {
+            self.call_stack.pop();
+        }
+        if is_call && new_sp == sp.wrapping_sub(2) {
+            self.call_stack.push(Call { caller, target: registers.pc as u16, return_address, sp: new_sp });
+        }
+    }
+
+    /// Run one instruction, then the interrupt that's due if any. Breakpoints are ignored,
+    /// watchpoints aren't.
+    pub fn step(&mut self) -> Result<Stop, EmulatorError> {
+        let instruction = self.current_instruction();
+        let sp = self.emulator.registers().sp as u16;
+        if ! self.watchpoints.is_empty() {
+            *self.watch_hit.lock().unwrap() = None;
+        }
+        let result = self.emulator.step()?;
+        match result.status {
+            StepStatus::Paused => return Ok(Stop::Interrupted),
+            StepStatus::Halted if ! self.interrupts || ! self.emulator.registers().enable_interrupts =>
+                return Ok(Stop::Halted),
+            _ => {},
+        }
+        self.instructions += 1;
+        let is_call = matches!(instruction.flow(), Flow::Call(_));
+        self.track_call(instruction.address, sp, instruction.next_address(), is_call);
+        if self.interrupts {
+            // Same timing as Emulator::run_one_frame()
+            self.frame_cycles += result.cycles as u64;
+            if ! self.half_frame && self.frame_cycles >= CYCLES_PER_FRAME / 2 {
+                self.half_frame = true;
+                self.interrupt(1);
+            } else if self.frame_cycles >= CYCLES_PER_FRAME {
+                self.frame_cycles = 0;
+                self.half_frame = false;
+                self.frames += 1;
+                self.interrupt(2);
+            }
+        }
+        match self.watch_hit.lock().unwrap().take() {
+            Some(event) => Ok(Stop::Watchpoint(event)),
+            None => Ok(Stop::Done),
+        }
+    }
+
+    fn interrupt(&mut self, number: u8) {
+        let registers = self.emulator.registers();
+        self.emulator.interrupt(number);
+        let pc = registers.pc as u16;
+        self.track_call(pc, registers.sp as u16, pc, registers.enable_interrupts);
+    }
+
+    /// Run until `done` returns true after an instruction, a breakpoint is hit or `limit`
+    /// instructions ran. The breakpoint at the current PC, if any, is ignored so that
+    /// execution can resume from it.
+    pub fn run_until(&mut self, limit: Option<u64>, mut done: impl FnMut(&Debugger) -> bool)
+            -> Result<Stop, EmulatorError> {
+        self.break_requested.store(false, Ordering::Relaxed);
+        let mut count = 0;
+        loop {
+            if count > 0 {
+                if let Some(address) = self.breakpoint_hit() {
+                    return Ok(Stop::Breakpoint(address));
+                }
+            }
+            if limit.is_some_and(|limit| count >= limit) {
+                return Ok(Stop::Limit);
+            }
+            if self.break_requested.swap(false, Ordering::Relaxed) {
+                return Ok(Stop::Interrupted);
+            }
+            match self.step()? {
+                Stop::Done => {},
+                stop => return Ok(stop),
+            }
+            count += 1;
+            if done(self) {
+                return Ok(Stop::Done);
+            }
+        }
+    }
+
+    /// Run until a breakpoint, or at most `limit` instructions.
+    pub fn run(&mut self, limit: Option<u64>) -> Result<Stop, EmulatorError> {
+        self.run_until(limit, |_| false)
+    }
+
+    /// Run until PC reaches `address` (run to cursor) or a breakpoint.
+    pub fn run_to(&mut self, address: u16, limit: Option<u64>) -> Result<Stop, EmulatorError> {
+        self.run_until(limit, |debugger| debugger.pc() == address)
+    }
+
+    /// Run the next instruction, or the whole subroutine if it's a call or RST.
+    pub fn step_over(&mut self, limit: Option<u64>) -> Result<Stop, EmulatorError> {
+        let instruction = self.current_instruction();
+        match instruction.flow() {
+            Flow::Call(_) => {
+                let next = instruction.next_address() as usize;
+                let sp = self.emulator.registers().sp;
+                self.run_until(limit, |debugger| {
+                    let registers = debugger.registers();
+                    registers.pc == next && registers.sp >= sp
+                })
+            },
+            _ => self.step(),
+        }
+    }
+
+    /// Run until the current subroutine returns to its caller.
+    pub fn step_out(&mut self, limit: Option<u64>) -> Result<Stop, EmulatorError> {
+        let sp = self.emulator.registers().sp;
+        let is_return = |instruction: Instruction| matches!(instruction.flow(), Flow::Return | Flow::ConditionalReturn);
+        let mut returning = is_return(self.current_instruction());
+        self.run_until(limit, |debugger| {
+            // A return that popped the address pushed before we started
+            let done = returning && debugger.registers().sp > sp;
+            returning = is_return(debugger.current_instruction());
+            done
+        })
+    }
+
+    //
+    // Views
+    //
+
+    fn instruction_at(emulator: &Emulator, address: u16) -> Instruction {
+        let bytes: Vec<u8> = (0..3).map(|i| emulator.read_memory(address.wrapping_add(i))).collect();
+        disassembler::decode(&bytes, address)
+    }
+
+    pub fn current_instruction(&self) -> Instruction {
+        Debugger::instruction_at(&self.emulator, self.pc())
+    }
+
+    /// The registers and flags: the view of `State::disassemble` followed by the flags and
+    /// interrupt state it doesn't show.
+    pub fn registers_view(&self) -> String {
+        let registers = self.emulator.registers();
+        format!("{} AC={} EI={}{}", self.emulator.state().disassemble(),
+            registers.flags >> 4 & 1, registers.enable_interrupts as u8,
+            if registers.halted { " halted" } else { "" })
+    }
+
+    /// `length` bytes from `address`
+    pub fn read_memory(&self, address: u16, length: usize) -> Vec<u8> {
+        (0..length).map(|i| self.emulator.read_memory(address.wrapping_add(i as u16))).collect()
+    }
+
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.emulator.write_memory(address.wrapping_add(i as u16), *byte);
+        }
+    }
+
+    /// A hex dump of memory, 16 bytes per line, with the ASCII characters.
+    pub fn memory_view(&self, address: u16, length: usize) -> String {
+        let mut result = String::new();
+        for (i, line) in self.read_memory(address, length).chunks(16).enumerate() {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = line.iter()
+                .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                .collect();
+            result.push_str(&format!("{:04x}: {:48} {}\n",
+                address.wrapping_add(i as u16 * 16), hex.join(" "), text));
+        }
+        result
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.emulator.registers()
+    }
+
+    pub fn set_register(&mut self, name: &str, value: u16) -> Result<(), String> {
+        let mut registers = self.emulator.registers();
+        set_register(&mut registers, name, value)?;
+        self.emulator.set_registers(&registers);
+        Ok(())
+    }
+
+    /// The instructions around `address`: `before` of them (none if the bytes before
+    /// can't be decoded so that they end at `address`), the one at `address` and `after`.
+    pub fn disassemble_around(&self, address: u16, before: usize, after: usize) -> Vec<Instruction> {
+        // Decode from several starting points before `address`, keep the ones that end
+        // exactly at `address` and pick the most common last instructions: decoding
+        // resynchronizes after a few instructions, so most starting points agree
+        let mut votes: Vec<(Vec<Instruction>, usize)> = Vec::new();
+        for distance in 1..=before * 3 + 16 {
+            let start = address.wrapping_sub(distance as u16);
+            let instructions = disassembler::disassemble(&self.read_memory(start, distance), start);
+            let aligned = instructions.last().is_some_and(|i| i.next_address() == address && ! i.is_data());
+            if aligned && instructions.len() >= before {
+                let tail = instructions[instructions.len() - before..].to_vec();
+                match votes.iter_mut().find(|(t, _)| *t == tail) {
+                    Some((_, count)) => *count += 1,
+                    None => votes.push((tail, 1)),
+                }
+            }
+        }
+        let mut result = votes.into_iter().max_by_key(|(_, count)| *count)
+            .map(|(tail, _)| tail)
+            .unwrap_or_default();
+        let mut next = address;
+        for _ in 0..=after {
+            let instruction = Debugger::instruction_at(&self.emulator, next);
+            next = instruction.next_address();
+            result.push(instruction);
+        }
+        result
+    }
+}
@@ -0,0 +1,380 @@
+//! 8080 disassembler, driven by the opcode table in `opcodes`.
+//!
+//! `disassemble()` decodes a sequence of bytes linearly. `Analysis` does a recursive
+//! descent from entry points instead: it follows jumps, calls and RSTs to find which bytes
+//! are code, and `Analysis::listing()` prints the result as a source file, with labels
+//! for the targets and the remaining bytes as data.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use crate::opcodes::OPCODES;
+use crate::symbols::{Symbols, SymbolKind};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Register { A, B, C, D, E, H, L, M }
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegisterPair { B, D, H, SP, PSW }
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    RegisterPair(RegisterPair),
+    Immediate8(u8),
+    Immediate16(u16),
+    /// The address of a jump, a call or a memory access
+    Address(u16),
+    Port(u8),
+    /// The vector of RST, 0 to 7
+    Vector(u8),
+}
+
+/// Where the execution can go after an instruction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Flow {
+    /// The next instruction
+    Next,
+    Jump(u16),
+    /// The target or the next instruction
+    ConditionalJump(u16),
+    /// The target, then the next instruction when it returns
+    Call(u16),
+    Return,
+    /// The caller or the next instruction
+    ConditionalReturn,
+    /// PCHL: a target only known at run time
+    Indirect,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// `DB` for the trailing bytes of an incomplete instruction
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+}
+
+/// The instructions that take register pairs: `B` is BC for them, not the register B.
+const PAIR_MNEMONICS: [&str; 8] = ["LXI", "DAD", "INX", "DCX", "LDAX", "STAX", "PUSH", "POP"];
+
+/// Numbers in the Intel style: hexadecimal with a H suffix, and a leading 0 when they
+/// start with a letter, e.g. 0FFH.
+pub fn hex(value: u16, digits: usize) -> String {
+    let result = format!("{:0digits$X}H", value, digits = digits);
+    if result.starts_with(|c: char| c.is_ascii_alphabetic()) { format!("0{}", result) } else { result }
+}
+
+impl Operand {
+    /// Parse an operand of the opcode table. `d8`, `d16`, `a16` and `p8` stand for the
+    /// bytes that follow the opcode.
+    fn from_template(template: &str, pair: bool, byte1: u8, word: u16) -> Operand {
+        use Register::*;
+        match template {
+            "d8" => Operand::Immediate8(byte1),
+            "p8" => Operand::Port(byte1),
+            "d16" => Operand::Immediate16(word),
+            "a16" => Operand::Address(word),
+            "SP" => Operand::RegisterPair(RegisterPair::SP),
+            "PSW" => Operand::RegisterPair(RegisterPair::PSW),
+            "B" if pair => Operand::RegisterPair(RegisterPair::B),
+            "D" if pair => Operand::RegisterPair(RegisterPair::D),
+            "H" if pair => Operand::RegisterPair(RegisterPair::H),
+            "A" => Operand::Register(A),
+            "B" => Operand::Register(B),
+            "C" => Operand::Register(C),
+            "D" => Operand::Register(D),
+            "E" => Operand::Register(E),
+            "H" => Operand::Register(H),
+            "L" => Operand::Register(L),
+            "M" => Operand::Register(M),
+            n => Operand::Vector(n.parse().unwrap_or_else(|_| panic!("Unknown operand {}", n))),
+        }
+    }
+
+    /// Format the operand, using `label` for the addresses that have one.
+    pub fn format(&self, label: &dyn Fn(u16) -> Option<String>) -> String {
+        match self {
+            Operand::Register(r) => format!("{:?}", r),
+            Operand::RegisterPair(p) => format!("{:?}", p),
+            Operand::Immediate8(v) | Operand::Port(v) => hex(*v as u16, 2),
+            Operand::Immediate16(v) => hex(*v, 4),
+            Operand::Address(a) => label(*a).unwrap_or_else(|| hex(*a, 4)),
+            Operand::Vector(n) => n.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format(&|_| None))
+    }
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+
+    pub fn is_data(&self) -> bool {
+        self.mnemonic == "DB"
+    }
+
+    pub fn flow(&self) -> Flow {
+        let target = self.operands.iter().find_map(|o| match o {
+            Operand::Address(a) => Some(*a),
+            Operand::Vector(n) => Some((*n as u16) << 3),
+            _ => None,
+        });
+        let conditional = self.mnemonic.len() > 1 &&
+            ["NZ", "Z", "NC", "C", "PO", "PE", "P", "M"].contains(&&self.mnemonic[1..]);
+        match (self.mnemonic, &self.mnemonic[..1]) {
+            ("JMP", _) => Flow::Jump(target.unwrap()),
+            ("CALL", _) | ("RST", _) => Flow::Call(target.unwrap()),
+            ("RET", _) => Flow::Return,
+            ("PCHL", _) => Flow::Indirect,
+            (_, "J") if conditional => Flow::ConditionalJump(target.unwrap()),
+            (_, "C") if conditional => Flow::Call(target.unwrap()),
+            (_, "R") if conditional => Flow::ConditionalReturn,
+            _ => Flow::Next,
+        }
+    }
+
+    /// The instruction in assembly syntax, using `label` for the addresses that have one.
+    pub fn format(&self, label: &dyn Fn(u16) -> Option<String>) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|o| o.format(label)).collect();
+        if operands.is_empty() {
+            self.mnemonic.to_string()
+        } else {
+            format!("{:8}{}", self.mnemonic, operands.join(","))
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format(&|_| None))
+    }
+}
+
+/// Decode the instruction at the beginning of `bytes`, which is at `address`. If `bytes`
+/// is too short for the whole instruction, it's returned as a single `DB`.
+pub fn decode(bytes: &[u8], address: u16) -> Instruction {
+    let opcode = &OPCODES[&bytes[0]];
+    if bytes.len() < opcode.size {
+        return Instruction {
+            address, bytes: vec![bytes[0]], mnemonic: "DB",
+            operands: vec![Operand::Immediate8(bytes[0])],
+        };
+    }
+    let byte1 = if opcode.size > 1 { bytes[1] } else { 0 };
+    let word = if opcode.size > 2 { (bytes[2] as u16) << 8 | byte1 as u16 } else { 0 };
+    let (mnemonic, operands) = match opcode.name.split_once(' ') {
+        Some((mnemonic, operands)) => (mnemonic, operands.split(',').collect()),
+        None => (opcode.name, Vec::new()),
+    };
+    let pair = PAIR_MNEMONICS.contains(&mnemonic);
+    Instruction {
+        address,
+        bytes: bytes[..opcode.size].to_vec(),
+        mnemonic,
+        operands: operands.into_iter().map(|o| Operand::from_template(o, pair, byte1, word)).collect(),
+    }
+}
+
+/// Decode all of `bytes`, which start at `origin`, as consecutive instructions.
+pub fn disassemble(bytes: &[u8], origin: u16) -> Vec<Instruction> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let instruction = decode(&bytes[i..], origin.wrapping_add(i as u16));
+        i += instruction.size();
+        result.push(instruction);
+    }
+    result
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum LabelKind {
+    /// A jump target
+    Label,
+    Subroutine,
+    /// One of the entry points of the analysis, e.g. an interrupt handler
+    Entry,
+}
+
+/// The result of a recursive descent disassembly of a ROM.
+pub struct Analysis {
+    origin: u16,
+    bytes: Vec<u8>,
+    /// The instructions reached from the entry points, by address
+    pub instructions: BTreeMap<u16, Instruction>,
+    pub labels: BTreeMap<u16, (LabelKind, String)>,
+    /// Names that replace the generated labels, see `set_symbols()`
+    symbols: Symbols,
+}
+
+impl Analysis {
+    /// Follow the code from `entry_points`, which are labeled `reset`, `rst_<n>` for the RST
+    /// vectors, or `entry_<address>`.
+    pub fn new(bytes: &[u8], origin: u16, entry_points: &[u16]) -> Analysis {
+        let mut result = Analysis {
+            origin, bytes: bytes.to_vec(), instructions: BTreeMap::new(), labels: BTreeMap::new(),
+            symbols: Symbols::new(),
+        };
+        let end = origin as usize + bytes.len();
+        let contains = |address: u16| (origin as usize..end).contains(&(address as usize));
+        // Bytes that belong to an instruction
+        let mut code: BTreeSet<u16> = BTreeSet::new();
+        let mut to_visit: Vec<u16> = Vec::new();
+        for entry in entry_points {
+            result.labels.insert(*entry, (LabelKind::Entry, Analysis::name(LabelKind::Entry, *entry)));
+            to_visit.push(*entry);
+        }
+        while let Some(mut address) = to_visit.pop() {
+            while contains(address) && ! code.contains(&address) {
+                let offset = (address - origin) as usize;
+                let instruction = decode(&bytes[offset..], address);
+                if instruction.is_data() {
+                    break;
+                }
+                let mut targets = Vec::new();
+                let mut continues = true;
+                match instruction.flow() {
+                    Flow::Jump(target) => {
+                        targets.push((LabelKind::Label, target));
+                        continues = false;
+                    },
+                    Flow::ConditionalJump(target) => targets.push((LabelKind::Label, target)),
+                    Flow::Call(target) => targets.push((LabelKind::Subroutine, target)),
+                    Flow::Return | Flow::Indirect => continues = false,
+                    Flow::Next | Flow::ConditionalReturn => {},
+                }
+                for (kind, target) in targets {
+                    let label = result.labels.entry(target).or_insert((kind, Analysis::name(kind, target)));
+                    if kind > label.0 {
+                        *label = (kind, Analysis::name(kind, target));
+                    }
+                    to_visit.push(target);
+                }
+                // The end can be past the end of the memory, at the top of it
+                for a in address as usize..(address as usize + instruction.size()).min(end) {
+                    code.insert(a as u16);
+                }
+                let next = instruction.next_address();
+                result.instructions.insert(address, instruction);
+                if ! continues || next < address {
+                    break;
+                }
+                address = next;
+            }
+        }
+        result
+    }
+
+    fn name(kind: LabelKind, address: u16) -> String {
+        match kind {
+            LabelKind::Entry if address == 0 => "reset".to_string(),
+            LabelKind::Entry if address.is_multiple_of(8) && address < 0x40 => format!("rst_{}", address >> 3),
+            LabelKind::Entry => format!("entry_{:04x}", address),
+            LabelKind::Subroutine => format!("sub_{:04x}", address),
+            LabelKind::Label => format!("lbl_{:04x}", address),
+        }
+    }
+
+    /// Name the code and data with `symbols`: the code symbols at the beginning of an
+    /// instruction become its label, the other symbols are defined with `EQU`.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Whether a symbol is a label of the listing rather than an `EQU`.
+    fn is_label(&self, address: u16) -> bool {
+        self.instructions.contains_key(&address)
+            && self.symbols.get(address).is_none_or(|symbol| symbol.kind == SymbolKind::Code)
+    }
+
+    /// The label of an address, if it's the beginning of an instruction, or its symbol.
+    pub fn label(&self, address: u16) -> Option<String> {
+        match self.symbols.get(address) {
+            Some(symbol) => Some(symbol.name.clone()),
+            None => self.labels.get(&address)
+                .filter(|_| self.instructions.contains_key(&address))
+                .map(|(_, name)| name.clone()),
+        }
+    }
+
+    /// Whether the byte at that address belongs to an instruction.
+    pub fn is_code(&self, address: u16) -> bool {
+        self.instructions.range(..=address).next_back()
+            .is_some_and(|(_, i)| (address as usize) < i.address as usize + i.size())
+    }
+
+    /// The whole ROM as a source file: instructions with labels, and `DB` lines for the
+    /// bytes that weren't reached. Each line has the address and bytes in a comment.
+    pub fn listing(&self) -> String {
+        self.listing_with(&|_, _| String::new())
+    }
+
+    /// `listing()`, with the text `annotate` returns for the address and size of each
+    /// line at the end of its comment.
+    pub fn listing_with(&self, annotate: &dyn Fn(u16, usize) -> String) -> String {
+        let label = |address: u16| self.label(address);
+        let mut result = String::new();
+        for symbol in self.symbols.iter().filter(|symbol| ! self.is_label(symbol.address)) {
+            let comment = symbol.comment.as_ref().map(|c| format!(" ; {}", c)).unwrap_or_default();
+            result.push_str(&format!("{:16}EQU     {}{}\n", symbol.name, hex(symbol.address, 4), comment));
+        }
+        if ! result.is_empty() {
+            result.push('\n');
+        }
+        result.push_str(&format!("{:8}ORG     {}\n", "", hex(self.origin, 4)));
+        let mut i = 0;
+        while i < self.bytes.len() {
+            let address = self.origin.wrapping_add(i as u16);
+            if let Some(instruction) = self.instructions.get(&address) {
+                if let Some(name) = self.label(address).filter(|_| self.is_label(address)) {
+                    let comment = self.symbols.get(address).and_then(|symbol| symbol.comment.as_ref());
+                    result.push_str(&format!("\n{}:{}\n", name, comment.map(|c| format!(" ; {}", c)).unwrap_or_default()));
+                }
+                let bytes: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                result.push_str(&format!("{:8}{:31} ; {:04X}  {}{}\n",
+                    "", instruction.format(&label), address, bytes.join(" "), annotate(address, instruction.size())));
+                // The instructions that overlap this one, e.g. the target of a jump into its
+                // operand, aren't listed: their bytes are data, and their labels are defined
+                let end = address as usize + instruction.size();
+                for (overlapping, _) in self.instructions.range(address..).skip(1)
+                        .take_while(|(a, _)| (**a as usize) < end) {
+                    if let Some(name) = self.label(*overlapping).filter(|_| self.is_label(*overlapping)) {
+                        result.push_str(&format!("{:16}EQU     {}\n", name, hex(*overlapping, 4)));
+                    }
+                }
+                i += instruction.size();
+            } else {
+                // Data until the next instruction, at most 8 bytes per line. There's none at
+                // `i`, so this takes at least one byte.
+                let mut data = Vec::new();
+                while i < self.bytes.len() && data.len() < 8
+                        && ! self.instructions.contains_key(&self.origin.wrapping_add(i as u16)) {
+                    data.push(self.bytes[i]);
+                    i += 1;
+                }
+                let values: Vec<String> = data.iter().map(|b| hex(*b as u16, 2)).collect();
+                let text: String = data.iter()
+                    .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+                    .collect();
+                result.push_str(&format!("{:8}{:31} ; {:04X}  {}{}\n",
+                    "", format!("DB      {}", values.join(",")), address, text, annotate(address, data.len())));
+            }
+        }
+        result
+    }
+}
@@ -1,32 +1,34 @@
-use crate::{opcodes, VERBOSE_DISASSEMBLE, VERBOSE_DISASSEMBLE_SECTION, DISASSEMBLE_SECTION_START, DISASSEMBLE_SECTION_END};
-use crate::memory::Memory;
+use crate::opcodes;
+use crate::disassembler;
+use crate::memory::{Memory, MEMORY_SIZE, ROM_SIZE};
 use crate::state::*;
-use crate::emulator_state::SharedState;
+use crate::emulator_state::{Command, SharedState};
+use crate::error::EmulatorError;
+use crate::snapshot::{crc32, Snapshot};
+use crate::movie::{Movie, MoviePlayer};
+use crate::rewind::{RewindBuffer, RewindConfig};
+use crate::screen::GifRecorder;
+use crate::recording::AvRecorder;
+use crate::sound::SOUNDS_DIRECTORY;
+use crate::trace::{TraceEntry, Tracer};
+use crate::profiler::Profiler;
+use crate::coverage::Coverage;
+use crate::hooks::{Access, HookId, MemoryEvent};
+use crate::monitor::{Monitor, MonitorConfig};
+use crate::crash::{CrashDump, HistoryEntry, PortAccess, PortEvent, Ring, DEFAULT_HISTORY, PORT_HISTORY};
 use std::thread;
 use wasm_timer::SystemTime;
 use std::time::{Duration};
-use std::sync::Mutex;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
 use wasm_bindgen::prelude::*;
-use once_cell::sync::OnceCell;
-
-static SHARED_STATE: OnceCell<Mutex<SharedState>> = OnceCell::new();
-
-// lazy_static! {
-//     pub(crate) static ref SHARED_STATE: Mutex<SharedState> = Mutex::new(SharedState::new());
-// }
-
-#[cfg(target_arch = "wasm32")]
-pub fn graphic_memory() -> Vec<u8> {
-    let memory = crate::memory::STATIC_MEMORY.read().unwrap();
-    memory[0x2400..0x2400 + crate::memory::GRAPHIC_MEMORY_SIZE].to_vec()
-}
 
 #[derive(PartialEq)]
 pub enum StepStatus {
     Continue,
     Paused,
-    Success(String),
-    Failure(String)
+    /// The CPU executed HLT and is waiting for an interrupt
+    Halted,
 }
 
 pub struct StepResult {
@@ -40,25 +42,92 @@ pub struct Emulator {
     state: Option<State>,
     shift_register: u16,
     shift_register_offset: u8,
-    output_buffer: Vec<char>,
+    shared_state: Arc<Mutex<SharedState>>,
+    /// Name of the ROM set loaded in memory, recorded in snapshots
+    rom_set: String,
+    /// The states of the previous frames, if rewinding is enabled
+    rewind: Option<RewindBuffer>,
+    /// The input ports 1 and 2, read once at the beginning of each frame so that a frame
+    /// only depends on these values (see `movie`). `None` outside of `run_one_frame()`.
+    frame_inputs: Option<(u8, u8)>,
+    recorder: Option<Movie>,
+    player: Option<MoviePlayer>,
+    /// The cycles run since the emulator was created
+    cycles: u64,
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
+    /// The last instructions and port accesses, for the crash dumps
+    history: Ring<HistoryEntry>,
+    port_history: Ring<PortEvent>,
+    monitor: Option<Monitor>,
 }
 
 pub const WIDTH: u16 = 224;
 pub const HEIGHT: u16 = 256;
+/// The 8080 runs at 2 MHz and the screen at 60 Hz, with an interrupt in the middle of
+/// each frame (RST 1) and one at its end (RST 2)
+pub const CYCLES_PER_FRAME: u64 = 2_000_000 / 60;
 
-#[wasm_bindgen(js_namespace = console)]
+#[wasm_bindgen]
 pub fn start_emulator_wasm() {
     println!("Starting emulator wasm");
 }
 
 #[wasm_bindgen]
-pub fn spawn_emulator() {
-    let mut emulator = Emulator::new_space_invaders();
+pub fn spawn_emulator(mut emulator: Emulator) {
     let time_per_frame_ms = 16;
+    let mut gif: Option<GifRecorder> = None;
+    let mut video: Option<AvRecorder> = None;
     loop {
         let start = SystemTime::now();
-        // Run one frame
-        let cycles = emulator.run_one_frame(false);
+        let commands = emulator.shared_state.lock().unwrap().take_commands();
+        for command in commands {
+            // A file that can't be saved or loaded is not fatal, keep playing
+            let result = match command {
+                Command::StartGif(file_name) => GifRecorder::create(&file_name).map(|g| gif = Some(g)),
+                Command::StopGif => gif.take().map_or(Ok(()), |g| g.finish()),
+                Command::StartVideo(name) => AvRecorder::create(&format!("{}.y4m", name),
+                        &format!("{}.wav", name), SOUNDS_DIRECTORY).map(|r| video = Some(r)),
+                Command::StopVideo => video.take().map_or(Ok(()), |r| r.finish()),
+                _ => emulator.run_command(&command),
+            };
+            if let Err(e) = result {
+                log(&e.to_string());
+            }
+        }
+        // Run one frame, or go back one while rewinding
+        let rewinding = emulator.shared_state.lock().unwrap().is_rewinding();
+        let result = if rewinding {
+            emulator.rewind_one_frame().map(|_| 0)
+        } else {
+            emulator.run_one_frame()
+        };
+        let cycles = match result {
+            Ok(cycles) => cycles,
+            Err(e) => {
+                log(&e.to_string());
+                #[cfg(not(target_arch = "wasm32"))]
+                match emulator.crash_dump(&e).save(crate::crash::CRASH_DUMP_FILE, &Default::default()) {
+                    Ok(()) => log(&format!("Crash dump written to {}", crate::crash::CRASH_DUMP_FILE)),
+                    Err(e) => log(&e.to_string()),
+                }
+                emulator.shared_state.lock().unwrap().set_error(e);
+                return;
+            }
+        };
+        if let Some(recorder) = gif.as_mut() {
+            let graphic_memory = emulator.shared_state.lock().unwrap().graphic_memory();
+            if let Err(e) = recorder.add_frame(&graphic_memory) {
+                log(&e.to_string());
+                gif = None;
+            }
+        }
+        if let Some(recorder) = video.as_mut() {
+            if let Err(e) = recorder.add_frame(&emulator.shared_state.lock().unwrap()) {
+                log(&e.to_string());
+                video = None;
+            }
+        }
         let elapsed = start.elapsed().unwrap().as_millis();
 
         // Wait until we reach 16ms before running the next frame.
@@ -76,19 +145,19 @@ pub fn spawn_emulator() {
                      cycles);
         }
 
-        SHARED_STATE.get().unwrap().lock().unwrap()
+        emulator.shared_state.lock().unwrap()
             .set_megahertz(cycles as f64 / after_sleep as f64);
     }
 }
 
-pub fn spawn_emulator_thread() {
+pub fn spawn_emulator_thread(emulator: Emulator) {
     //
     // Spawn the game logic in a separate thread. This logic will communicate with the
-    // main thread (and therefore, the actual graphics on your screen) via the `listener`
-    // object that this function receives in parameter.
+    // main thread (and therefore, the actual graphics on your screen) via the `SharedState`
+    // handle of the emulator it receives in parameter.
     //
     thread::spawn(move || {
-        spawn_emulator();
+        spawn_emulator(emulator);
     });
 }
 
@@ -114,79 +183,456 @@ pub fn log(s: &str) {
 
 impl Emulator {
 
-    pub fn new_space_invaders() -> Emulator {
-        let mut memory = Memory::new();
+    pub fn new_space_invaders() -> Result<Emulator, EmulatorError> {
         #[cfg(not(target_arch = "wasm32"))]
-        memory.read_file("space-invaders.rom", 0);
+        return Emulator::new_space_invaders_from("space-invaders.rom");
 
         #[cfg(target_arch = "wasm32")]
-        log("Warning: need to read the rom file in WASM mode");
+        {
+            log("Warning: need to read the rom file in WASM mode");
+            let mut result = Emulator::new(Box::new(Memory::new()), 0);
+            result.set_rom_set("invaders");
+            Ok(result)
+        }
+    }
 
-        Emulator::new(Box::new(memory), 0)
+    /// A Space Invaders machine running the ROM in that file.
+    pub fn new_space_invaders_from(rom_file: &str) -> Result<Emulator, EmulatorError> {
+        let mut memory = Memory::new();
+        memory.read_file(rom_file, 0)?;
+        let mut result = Emulator::new(Box::new(memory), 0);
+        result.set_rom_set("invaders");
+        Ok(result)
     }
 
     pub fn new(memory: Box<Memory>, pc: usize) -> Emulator {
+        let shared_state = Arc::new(Mutex::new(SharedState::new(memory.handle())));
         Emulator { memory,
             shift_register: 0,
             shift_register_offset: 0,
             state: Some(State::new(pc)),
-            output_buffer: Vec::new(),
+            shared_state,
+            rom_set: String::new(),
+            rewind: None,
+            frame_inputs: None,
+            recorder: None,
+            player: None,
+            cycles: 0,
+            tracer: None,
+            profiler: None,
+            history: Ring::new(DEFAULT_HISTORY),
+            port_history: Ring::new(PORT_HISTORY),
+            monitor: None,
+        }
+    }
+
+    pub fn rom_set(&self) -> &str {
+        &self.rom_set
+    }
+
+    pub fn set_rom_set(&mut self, rom_set: &str) {
+        self.rom_set = rom_set.into();
+    }
+
+    /// Capture the complete state of the machine.
+    pub fn snapshot(&self) -> Snapshot {
+        let shared_state = self.shared_state.lock().unwrap();
+        Snapshot {
+            rom_set: self.rom_set.clone(),
+            registers: self.registers(),
+            shift_register: self.shift_register,
+            shift_register_offset: self.shift_register_offset,
+            in_1: shared_state.get_in_1(),
+            in_2: shared_state.get_in_2(),
+            out_3: shared_state.get_out(3).unwrap(),
+            out_5: shared_state.get_out(5).unwrap(),
+            memory: self.memory.dump(),
+        }
+    }
+
+    /// Put the machine back in the state captured by `snapshot()`. Fails without touching
+    /// anything if the snapshot was taken with another ROM set.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), EmulatorError> {
+        if snapshot.rom_set != self.rom_set {
+            return Err(EmulatorError::SnapshotRomMismatch {
+                expected: self.rom_set.clone(), found: snapshot.rom_set.clone()
+            });
+        }
+        if snapshot.memory.len() != MEMORY_SIZE {
+            return Err(EmulatorError::InvalidSnapshot(
+                format!("memory is {} bytes instead of {}", snapshot.memory.len(), MEMORY_SIZE)));
+        }
+        self.state_mut().set_registers(&snapshot.registers);
+        self.shift_register = snapshot.shift_register;
+        self.shift_register_offset = snapshot.shift_register_offset;
+        self.memory.load(&snapshot.memory);
+        let mut shared_state = self.shared_state.lock().unwrap();
+        shared_state.set_in_1(snapshot.in_1);
+        shared_state.set_in_2(snapshot.in_2);
+        shared_state.set_out_3(snapshot.out_3);
+        shared_state.set_out_5(snapshot.out_5);
+        Ok(())
+    }
+
+    /// Number of cycles run since the emulator was created, which the traces show.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Trace the instructions from now on, replacing the tracer that was enabled.
+    pub fn enable_trace(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Stop tracing. The tracer is returned to be finished.
+    pub fn disable_trace(&mut self) -> Option<Tracer> {
+        self.tracer.take()
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    /// Record which bytes are executed and read from now on, adding to `coverage`, e.g.
+    /// the one of a previous run or `Coverage::new()`.
+    pub fn enable_coverage(&mut self, coverage: Coverage) {
+        self.memory.enable_coverage(coverage);
+    }
+
+    pub fn disable_coverage(&mut self) -> Option<Coverage> {
+        self.memory.disable_coverage()
+    }
+
+    /// A copy of the coverage so far.
+    pub fn coverage(&self) -> Option<Coverage> {
+        self.memory.coverage()
+    }
+
+    /// Profile the instructions from now on, see `profiler`.
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn disable_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    /// Keep the last `instructions` instructions for the crash dumps (`DEFAULT_HISTORY` by
+    /// default), none with 0. The history so far is lost.
+    pub fn set_history_size(&mut self, instructions: usize) {
+        self.history = Ring::new(instructions);
+    }
+
+    /// The last instructions, the oldest first.
+    pub fn history(&self) -> Vec<TraceEntry> {
+        self.history.to_vec().into_iter().map(HistoryEntry::to_trace_entry).collect()
+    }
+
+    /// The last `IN` and `OUT`, the oldest first.
+    pub fn port_history(&self) -> Vec<PortEvent> {
+        self.port_history.to_vec()
+    }
+
+    /// What the machine was doing when `error` happened, see `crash`.
+    pub fn crash_dump(&self, error: &EmulatorError) -> CrashDump {
+        CrashDump {
+            error: error.clone(),
+            history: self.history(),
+            ports: self.port_history(),
+            snapshot: self.snapshot(),
+        }
+    }
+
+    /// Check the invariants of `config` in `run_one_frame()` from now on, see `monitor`.
+    pub fn enable_monitor(&mut self, config: MonitorConfig) {
+        self.disable_monitor();
+        let mut monitor = Monitor::new(config);
+        let rom = monitor.config().rom.clone();
+        monitor.hook = Some(self.add_hook(Access::Write, rom, monitor.rom_hook()));
+        self.monitor = Some(monitor);
+    }
+
+    pub fn disable_monitor(&mut self) -> Option<Monitor> {
+        let mut monitor = self.monitor.take()?;
+        if let Some(hook) = monitor.hook.take() {
+            self.remove_hook(hook);
+        }
+        Some(monitor)
+    }
+
+    pub fn monitor(&self) -> Option<&Monitor> {
+        self.monitor.as_ref()
+    }
+
+    /// Start recording the state at the end of each frame, so `rewind_one_frame()` can
+    /// go back to it.
+    pub fn enable_rewind(&mut self, config: RewindConfig) {
+        self.rewind = Some(RewindBuffer::new(config));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    /// Restore the state of the machine one frame earlier. Returns false, leaving the
+    /// machine untouched, if there is nothing left to rewind.
+    pub fn rewind_one_frame(&mut self) -> Result<bool, EmulatorError> {
+        match self.rewind.as_mut().and_then(|rewind| rewind.pop()) {
+            Some(snapshot) => {
+                self.restore(&snapshot)?;
+                // Keep the movies in sync with the machine
+                if let Some(movie) = self.recorder.as_mut() {
+                    movie.frames.pop();
+                }
+                if let Some(player) = self.player.as_mut() {
+                    player.previous_frame();
+                }
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// CRC-32 of the ROM, which identifies the game movies are recorded with.
+    pub fn rom_hash(&self) -> u32 {
+        crc32(&self.memory.dump()[..ROM_SIZE])
+    }
+
+    /// Record the inputs of each frame from now on. Without a starting snapshot, the
+    /// movie can only be replayed on a machine that was just powered on.
+    pub fn start_recording(&mut self, with_snapshot: bool) {
+        let start = if with_snapshot { Some(self.snapshot()) } else { None };
+        self.recorder = Some(Movie::new(self.rom_hash(), start));
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recorder.take()
+    }
+
+    /// Restore the starting snapshot of the movie, if any, then feed its inputs to the
+    /// following frames. Once it's finished, the inputs are left alone again.
+    pub fn play(&mut self, movie: Movie) -> Result<(), EmulatorError> {
+        let rom_hash = self.rom_hash();
+        if movie.rom_hash != rom_hash {
+            return Err(EmulatorError::MovieRomMismatch { expected: rom_hash, found: movie.rom_hash });
+        }
+        if let Some(snapshot) = &movie.start {
+            self.restore(snapshot)?;
         }
+        self.player = Some(MoviePlayer::new(movie));
+        Ok(())
     }
 
-    pub fn start_emulator() -> &'static Mutex<SharedState> {
-        let _ = SHARED_STATE.set(Mutex::new(SharedState::new()));
-        spawn_emulator_thread();
-        &SHARED_STATE.get().unwrap()
+    pub fn movie_player(&self) -> Option<&MoviePlayer> {
+        self.player.as_ref()
     }
 
-    pub fn run_one_frame(&mut self, verbose: bool) -> u64 {
+    pub fn stop_playing(&mut self) {
+        self.player = None;
+    }
+
+    fn run_command(&mut self, command: &Command) -> Result<(), EmulatorError> {
+        match command {
+            Command::SaveSnapshot(file_name) => self.snapshot().save(file_name),
+            Command::LoadSnapshot(file_name) => self.restore(&Snapshot::load(file_name)?),
+            Command::StartRecording => {
+                self.start_recording(true);
+                Ok(())
+            },
+            Command::StopRecording(file_name) => match self.stop_recording() {
+                Some(movie) => movie.save(file_name),
+                None => Ok(()),
+            },
+            Command::PlayMovie(file_name) => self.play(Movie::load(file_name)?),
+            // The GIF and video recorders belong to the loop of spawn_emulator()
+            Command::StartGif(_) | Command::StopGif |
+                Command::StartVideo(_) | Command::StopVideo => Ok(()),
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.state.as_ref().unwrap().registers()
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.state_mut().set_registers(registers);
+    }
+
+    pub(crate) fn state(&self) -> &State {
+        self.state.as_ref().unwrap()
+    }
+
+    pub(crate) fn state_mut(&mut self) -> &mut State {
+        self.state.as_mut().unwrap()
+    }
+
+    pub(crate) fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn read_memory(&self, address: u16) -> u8 {
+        self.memory.peek(address as usize)
+    }
+
+    /// Write to any address, including the ROM. The hooks don't see it.
+    pub fn write_memory(&mut self, address: u16, value: u8) {
+        self.memory.poke(address as usize, value);
+    }
+
+    /// Call `callback` on the accesses of that kind to `range`, see `hooks`.
+    pub fn add_hook(&mut self, access: Access, range: RangeInclusive<u16>,
+            callback: impl FnMut(&mut MemoryEvent) + Send + 'static) -> HookId {
+        self.memory.add_hook(access, range, Box::new(callback))
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.memory.remove_hook(id)
+    }
+
+    /// The handle through which a frontend talks to this emulator: input ports, sound
+    /// ports, pause and graphic memory. Each emulator has its own.
+    pub fn shared_state(&self) -> Arc<Mutex<SharedState>> {
+        self.shared_state.clone()
+    }
+
+    /// Create a Space Invaders emulator, run it in its own thread and return the handle
+    /// to its shared state. If the emulator stops on an error, the error is stored in
+    /// that shared state.
+    pub fn start_emulator() -> Result<Arc<Mutex<SharedState>>, EmulatorError> {
+        Emulator::start_emulator_with(None)
+    }
+
+    /// Same as `start_emulator()`, recording the frames for rewinding if `rewind` is set.
+    pub fn start_emulator_with(rewind: Option<RewindConfig>)
+            -> Result<Arc<Mutex<SharedState>>, EmulatorError> {
+        let mut emulator = Emulator::new_space_invaders()?;
+        if let Some(config) = rewind {
+            emulator.enable_rewind(config);
+        }
+        let result = emulator.shared_state();
+        spawn_emulator_thread(emulator);
+        Ok(result)
+    }
+
+    pub fn run_one_frame(&mut self) -> Result<u64, EmulatorError> {
+        if let Some((in_1, in_2)) = self.player.as_mut().and_then(|p| p.next_frame()) {
+            let mut shared_state = self.shared_state.lock().unwrap();
+            for bit in 0..8 {
+                shared_state.set_bit_in_1(bit, in_1 & (1 << bit) != 0);
+                shared_state.set_bit_in_2(bit, in_2 & (1 << bit) != 0);
+            }
+        }
+        let inputs = {
+            let shared_state = self.shared_state.lock().unwrap();
+            (shared_state.get_in_1(), shared_state.get_in_2())
+        };
+        if let Some(movie) = self.recorder.as_mut() {
+            movie.frames.push(inputs);
+        }
+
+        self.frame_inputs = Some(inputs);
+        let result = self.run_frame_cycles();
+        self.frame_inputs = None;
+        let total_cycles = result?;
+
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.push(self.snapshot());
+            self.rewind = Some(rewind);
+        }
+
+        Ok(total_cycles)
+    }
+
+    fn run_frame_cycles(&mut self) -> Result<u64, EmulatorError> {
         let mut total_cycles: u64 = 0;
-        let cycle_max = 2_000_000 / 60;
-        while total_cycles < cycle_max / 2 {
-            total_cycles += self.step(verbose).cycles as u64;
+        while total_cycles < CYCLES_PER_FRAME / 2 {
+            total_cycles += self.step()?.cycles as u64;
+            self.check_monitor(Monitor::check_instruction);
         }
         self.interrupt(1);
 
-        while total_cycles < cycle_max {
-            total_cycles += self.step(verbose).cycles as u64;
+        while total_cycles < CYCLES_PER_FRAME {
+            total_cycles += self.step()?.cycles as u64;
+            self.check_monitor(Monitor::check_instruction);
         }
+        self.check_monitor(Monitor::check_frame);
         self.interrupt(2);
 
-        total_cycles
+        Ok(total_cycles)
     }
 
-    pub fn step(&mut self, _verbose: bool) -> StepResult {
-        let shared = SHARED_STATE.get().unwrap();
-        if shared.lock().unwrap().is_paused() {
-            return StepResult { status: StepStatus::Paused, cycles: 0 };
+    fn check_monitor(&mut self, check: fn(&mut Monitor, &Emulator)) {
+        if let Some(mut monitor) = self.monitor.take() {
+            check(&mut monitor, self);
+            self.monitor = Some(monitor);
+        }
+    }
+
+    pub fn step(&mut self) -> Result<StepResult, EmulatorError> {
+        if self.shared_state.lock().unwrap().is_paused() {
+            return Ok(StepResult { status: StepStatus::Paused, cycles: 0 });
         }
 
         let state = &mut self.state.as_mut().unwrap();
-        let op: u8 = self.memory.read(state.pc);
-        let opcode = OPCODES.get(&op).expect(format!("Couldn't find opcode {:02x} at pc {:04x}",
-                                                     op, state.pc).as_str());
+        if state.halted {
+            // The CPU keeps cycling while halted, so that run_one_frame() reaches the
+            // next interrupt
+            self.cycles += 4;
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.record_halted(4);
+            }
+            return Ok(StepResult { status: StepStatus::Halted, cycles: 4 });
+        }
+
+        self.memory.execute(state.pc);
+        let op: u8 = self.memory.peek(state.pc);
+        let opcode = match OPCODES.get(&op) {
+            Some(opcode) => opcode,
+            None => return Err(EmulatorError::UnknownOpcode {
+                pc: state.pc, opcode: op, registers: state.registers()
+            }),
+        };
         let mut pc_was_assigned = false;
-        let byte1 = self.memory.read(state.pc + 1);
-        let byte2 = self.memory.read(state.pc + 2);
+        let (start_pc, start_sp) = (state.pc, state.sp);
+        let byte1 = self.memory.peek(state.pc + 1);
+        let byte2 = self.memory.peek(state.pc + 2);
+        self.memory.mark_executed(state.pc, opcode.size);
         let word = Memory::to_word(byte1, byte2);
         let cycles;
 
-        unsafe {
-            if VERBOSE_DISASSEMBLE || (VERBOSE_DISASSEMBLE_SECTION &&
-                (DISASSEMBLE_SECTION_START..=DISASSEMBLE_SECTION_END).contains(&state.pc)) {
-                let line1 = self.memory.disassemble(&opcode, state.pc);
-                println!("{}    {:10}", line1.0, state.disassemble());
+        if let Some(tracer) = self.tracer.as_mut() {
+            if tracer.wants(state.pc as u16) {
+                tracer.record(&TraceEntry {
+                    cycles: self.cycles,
+                    instruction: disassembler::decode(&[op, byte1, byte2], state.pc as u16),
+                    registers: state.registers(),
+                });
             }
         }
 
-        let mut test_status = StepResult { status: StepStatus::Continue, cycles: 0};
+        self.history.push(HistoryEntry { cycles: self.cycles, bytes: [op, byte1, byte2], registers: state.registers() });
 
         use opcodes::*;
         match op {
-            NOP => {
+            NOP | NOP_08 | NOP_10 | NOP_18 | NOP_20 | NOP_28 | NOP_30 | NOP_38 => {
                 cycles = 4;
             },
+            HLT => {
+                state.halted = true;
+                cycles = 7;
+            },
             INX_B => {
                 state.c = ((state.c as u16) + 1) as u8;
                 if state.c == 0 {
@@ -217,16 +663,16 @@ impl Emulator {
                 cycles = 5;
             },
             DCX_B => {
-                state.c = ((state.c as i8) - 1) as u8;
+                state.c = state.c.wrapping_sub(1);
                 if state.c == 0xff {
-                    state.b = ((state.b as i8) - 1) as u8;
+                    state.b = state.b.wrapping_sub(1);
                 }
                 cycles = 5;
             },
             DCX_D => {
-                state.e = ((state.e as i8) - 1) as u8;
+                state.e = state.e.wrapping_sub(1);
                 if state.e == 0xff {
-                    state.d = ((state.d as i8) - 1) as u8;
+                    state.d = state.d.wrapping_sub(1);
                 }
                 cycles = 5;
             },
@@ -238,7 +684,7 @@ impl Emulator {
             },
             DCX_SP => {
                 if state.sp == 0 {
-                    state.sp = 0xffff as usize;
+                    state.sp = 0xffff;
                 } else {
                     state.sp -= 1;
                 }
@@ -258,7 +704,7 @@ impl Emulator {
                 cycles = 10;
             },
             DAD_SP => {
-                state.add_hl(state.sp as u8, state.sp as u8 - 1);
+                state.add_hl(state.sp as u8, (state.sp >> 8) as u8);
                 cycles = 10;
             },
             RAL => {
@@ -266,7 +712,7 @@ impl Emulator {
                 let hob = (state.psw.a & 0x80) >> 7;
                 let carry_value = Psw::to_u8(state.psw.carry) as u8;
                 state.psw.a = (state.psw.a << 1) | carry_value;
-                state.psw.carry = if hob == 1 { true } else { false };
+                state.psw.carry = hob == 1;
                 cycles = 4;
             },
             RAR => {
@@ -274,7 +720,7 @@ impl Emulator {
                 let lob = state.psw.a & 1;
                 let carry_value = Psw::to_u8(state.psw.carry) as u8;
                 state.psw.a = state.psw.a >> 1 | (carry_value << 7);
-                state.psw.carry = if lob == 1 { true } else { false };
+                state.psw.carry = lob == 1;
                 cycles = 4;
             },
             RRC => {
@@ -448,6 +894,9 @@ impl Emulator {
                 state.b = state.l;
                 cycles = 5;
             },
+            MOV_A_A => {
+                cycles = 5;
+            },
             MOV_A_M => {
                 state.psw.a = self.memory.read(state.m());
                 cycles = 7;
@@ -662,38 +1111,34 @@ impl Emulator {
             },
             MOV_A_C => {
                 state.psw.a = state.c;
-                cycles = 10;
+                cycles = 5;
             },
             MOV_A_D => {
                 state.psw.a = state.d;
-                cycles = 10;
+                cycles = 5;
             },
             MOV_A_E => {
                 state.psw.a = state.e;
-                cycles = 10;
+                cycles = 5;
             },
             MOV_A_H => {
                 state.psw.a = state.h;
-                cycles = 10;
+                cycles = 5;
             },
             MOV_A_L => {
                 state.psw.a = state.l;
-                cycles = 10;
+                cycles = 5;
             },
             LXI_SP => {
                 state.sp = word;
                 cycles = 10;
             },
             SUI => {
-                let value = state.psw.a as i16 - byte1 as i16;
-                state.psw.a = value as u8;
-                state.set_arithmetic_flags(value);
+                state.sub(byte1, 0);
                 cycles = 7;
             },
             SBI => {
-                let value = state.psw.a as i16 - (byte1 as i16 + state.psw.carry as i16);
-                state.psw.a = value as u8;
-                state.set_arithmetic_flags(value);
+                state.sub(byte1, Psw::to_u8(state.psw.carry));
                 cycles = 7;
             },
             ADD_A => {
@@ -825,97 +1270,82 @@ impl Emulator {
                 cycles = 7;
             }
             ADI => {
-                let value = state.psw.a as i16 + byte1 as i16;
-                state.psw.a = value as u8;
-                state.set_arithmetic_flags(value);
+                state.add(byte1, 0);
                 cycles = 7;
             },
             ACI => {
-                let value = state.psw.a as i16 + byte1 as i16 + Psw::to_u8(state.psw.carry) as i16;
-                state.psw.a = value as u8;
-                state.set_arithmetic_flags(value);
+                state.add(byte1, Psw::to_u8(state.psw.carry));
                 cycles = 7;
             },
-            JMP => {
-                if word == 0 {
-                    let output: String = self.output_buffer.clone().into_iter().collect();
-                    println!("{}", output);
-                } else {
-                    state.pc = word;
-                    pc_was_assigned = true;
-                }
+            JMP | JMP_CB => {
+                state.pc = word;
+                pc_was_assigned = true;
                 cycles = 10;
             },
             RPO => {
-                pc_was_assigned = state.ret(&mut self.memory, ! state.psw.parity);
+                pc_was_assigned = state.ret(&self.memory, ! state.psw.parity);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
             RPE => {
-                pc_was_assigned = state.ret(&mut self.memory, state.psw.parity);
+                pc_was_assigned = state.ret(&self.memory, state.psw.parity);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
             RNC => {
-                pc_was_assigned = state.ret(&mut self.memory, ! state.psw.carry);
+                pc_was_assigned = state.ret(&self.memory, ! state.psw.carry);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
             RC => {
-                pc_was_assigned = state.ret(&mut self.memory, state.psw.carry);
+                pc_was_assigned = state.ret(&self.memory, state.psw.carry);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
             RP => {
-                pc_was_assigned = state.ret(&mut self.memory, ! state.psw.sign);
+                pc_was_assigned = state.ret(&self.memory, ! state.psw.sign);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
             RM => {
-                pc_was_assigned = state.ret(&mut self.memory, state.psw.sign);
+                pc_was_assigned = state.ret(&self.memory, state.psw.sign);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
             RZ => {
-                pc_was_assigned = state.ret(&mut self.memory, state.psw.zero);
+                pc_was_assigned = state.ret(&self.memory, state.psw.zero);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
             RNZ => {
-                pc_was_assigned = state.ret(&mut self.memory, ! state.psw.zero);
+                pc_was_assigned = state.ret(&self.memory, ! state.psw.zero);
                 cycles = if pc_was_assigned { 11 } else { 5 };
             },
-            RET => {
-                pc_was_assigned = state.ret(&mut self.memory, true);
-                cycles = 11;
+            RET | RET_D9 => {
+                pc_was_assigned = state.ret(&self.memory, true);
+                cycles = 10;
             },
             POP_B => {
-                state.c = self.memory.read(state.sp);
-                state.b = self.memory.read(state.sp + 1);
-                state.sp += 2;
+                let (c, b) = state.pop(&self.memory);
+                state.c = c;
+                state.b = b;
                 cycles = 10;
             },
             POP_D => {
-                state.e = self.memory.read(state.sp);
-                state.d = self.memory.read(state.sp + 1);
-                state.sp += 2;
+                let (e, d) = state.pop(&self.memory);
+                state.e = e;
+                state.d = d;
                 cycles = 10;
             },
             POP_H => {
-                state.l = self.memory.read(state.sp);
-                state.h = self.memory.read(state.sp + 1);
-                state.sp += 2;
+                let (l, h) = state.pop(&self.memory);
+                state.l = l;
+                state.h = h;
                 cycles = 10;
             },
             PUSH_B => {
-                self.memory.write(state.sp - 1, state.b);
-                self.memory.write(state.sp - 2, state.c);
-                state.sp -= 2;
+                state.push(&mut self.memory, state.b, state.c);
                 cycles = 11;
             },
             PUSH_D => {
-                self.memory.write(state.sp - 1, state.d);
-                self.memory.write(state.sp - 2, state.e);
-                state.sp -= 2;
+                state.push(&mut self.memory, state.d, state.e);
                 cycles = 11;
             },
             PUSH_H => {
-                self.memory.write(state.sp - 1, state.h);
-                self.memory.write(state.sp - 2, state.l);
-                state.sp -= 2;
+                state.push(&mut self.memory, state.h, state.l);
                 cycles = 11;
             },
             CC => {
@@ -923,56 +1353,56 @@ impl Emulator {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             CPO => {
                 if ! state.psw.parity {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             CPE => {
                 if state.psw.parity {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             CM => {
                 if state.psw.sign {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             CP => {
                 if ! state.psw.sign {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             CNZ => {
                 if ! state.psw.zero {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             CZ => {
                 if state.psw.zero {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             CNC => {
                 if ! state.psw.carry {
                     state.call(&mut self.memory, word);
                     pc_was_assigned = true;
                 }
-                cycles = if pc_was_assigned { 11 } else { 17 };
+                cycles = if pc_was_assigned { 17 } else { 11 };
             },
             STC => {
                 state.psw.carry = true;
@@ -987,64 +1417,20 @@ impl Emulator {
                 cycles = 4;
             },
             DAA => {
-                let mut a: u16 = state.psw.a as u16;
-                // least significant bits
-                let lsb = a & 0x0f;
-                if lsb > 9 || state.psw.auxiliary_carry {
-                    a += 6;
-                    state.psw.auxiliary_carry = (lsb + 6) > 0xf;
-                };
-                // most significant bits
-                let mut msb = (a & 0xf0) >> 4;
-                if (msb > 9) || state.psw.carry { msb += 6; }
-                a = (msb << 4) | (a & 0xf);
-                state.psw.auxiliary_carry = (msb + 6) > 0xf;
-                state.set_arithmetic_flags(a as i16);
-                state.psw.a = a as u8;
-
+                state.daa();
                 cycles = 4;
             },
-            CALL => {
+            CALL | CALL_DD | CALL_ED | CALL_FD => {
+                state.call(&mut self.memory, word);
+                pc_was_assigned = true;
                 cycles = 17;
-                if cfg!(test) {
-                    // In test mode, this function returns 1 for successful test, 2 for failure
-                    if word == 5 && state.c == 9 {
-                        // print message at address word(D, E)
-                        let ind = Memory::to_word(state.e, state.d);
-                        if ind == 0x174 {
-                            test_status.status = StepStatus::Success("Success".into());
-                        } else if ind == 0x18b {
-                            let sp = state.sp + 4;
-                            let pc = Memory::to_word(self.memory.read(sp), self.memory.read(sp+1))
-                                - 6;
-                            for i in 0..20 {
-                                println!("sp + {}: {:02x}", i, self.memory.read(sp + i));
-                            }
-                            let s = format!("Failure at pc {:04x}", pc);
-                            test_status.status = StepStatus::Failure(s);
-                        } else {
-                            state.call(&mut self.memory, word);
-                            pc_was_assigned = true;
-                        }
-                    } else {
-                        state.call(&mut self.memory, word);
-                        pc_was_assigned = true;
-                    }
-                } else {
-                    state.call(&mut self.memory, word);
-                    pc_was_assigned = true;
-                }
             },
             ANI => {
-                let value = state.psw.a & byte1;
-                state.psw.a = value;
-                state.set_logic_flags(value.into());
+                state.psw.a = state.and(byte1);
                 cycles = 7;
             },
             ORI => {
-                let value = state.psw.a | byte1;
-                state.psw.a = value;
-                state.set_logic_flags(value.into());
+                state.psw.a = state.or(byte1);
                 cycles = 7;
             },
             XRI => {
@@ -1189,24 +1575,18 @@ impl Emulator {
                 cycles = 10;
             },
             XCHG => {
-                let h = state.h;
-                state.h = state.d;
-                state.d = h;
-                let l = state.l;
-                state.l = state.e;
-                state.e = l;
+                std::mem::swap(&mut state.h, &mut state.d);
+                std::mem::swap(&mut state.l, &mut state.e);
                 cycles = 4;
             },
             PUSH_PSW => {
-                self.memory.write(state.sp - 1, state.psw.a);
-                self.memory.write(state.sp - 2, (state.psw.value() & 0xff) as u8);
-                state.sp -= 2;
+                state.push(&mut self.memory, state.psw.a, (state.psw.value() & 0xff) as u8);
                 cycles = 11;
             },
             POP_PSW => {
-                state.psw.a = self.memory.read(state.sp + 1);
-                state.psw.set_flags(self.memory.read(state.sp));
-                state.sp += 2;
+                let (flags, a) = state.pop(&self.memory);
+                state.psw.a = a;
+                state.psw.set_flags(flags);
                 cycles = 10;
             },
             CPI => {
@@ -1263,19 +1643,22 @@ impl Emulator {
                 cycles = 4;
             }
             OUT => {
+                self.port_history.push(PortEvent {
+                    cycles: self.cycles, pc: state.pc as u16, access: PortAccess::Out, port: byte1, value: state.psw.a
+                });
                 match byte1 {
                     2 => {
                         self.shift_register_offset = state.psw.a & 0x7;
                     },
                     3 => {
-                        SHARED_STATE.get().unwrap().lock().unwrap().set_out_3(state.psw.a);
+                        self.shared_state.lock().unwrap().set_out_3(state.psw.a);
                     },
                     4 => {
                         self.shift_register = ((state.psw.a as u16) << 8)
                             | (self.shift_register >> 8)
                     },
                     5 => {
-                        SHARED_STATE.get().unwrap().lock().unwrap().set_out_5(state.psw.a);
+                        self.shared_state.lock().unwrap().set_out_5(state.psw.a);
                     },
                     6 => {
                         // watch dog
@@ -1289,64 +1672,67 @@ impl Emulator {
             IN => {
                 match byte1 {
                     1 => {
-                        state.psw.a = SHARED_STATE.get().unwrap().lock().unwrap().get_in_1();
+                        state.psw.a = match self.frame_inputs {
+                            Some((in_1, _)) => in_1,
+                            None => self.shared_state.lock().unwrap().get_in_1(),
+                        };
                     },
                     2 => {
-                        state.psw.a = SHARED_STATE.get().unwrap().lock().unwrap().get_in_2();
+                        state.psw.a = match self.frame_inputs {
+                            Some((_, in_2)) => in_2,
+                            None => self.shared_state.lock().unwrap().get_in_2(),
+                        };
                     },
                     3 => {
                         let shift_amount = 8 - self.shift_register_offset;
                         state.psw.a = (self.shift_register >> shift_amount) as u8;
                     },
                     _ => {
-                        panic!("Unsupported IN port: {}", byte1);
+                        return Err(EmulatorError::UnsupportedInPort {
+                            port: byte1, pc: state.pc, opcode: op, registers: state.registers()
+                        });
                     }
                 }
+                self.port_history.push(PortEvent {
+                    cycles: self.cycles, pc: state.pc as u16, access: PortAccess::In, port: byte1, value: state.psw.a
+                });
                 cycles = 10;
             }
-            RST_1 => {
-                state.call(&mut self.memory, 1 * 8);
-                pc_was_assigned = true;
-                cycles = 10;
-            }
-            RST_2 => {
-                state.call(&mut self.memory, 2 * 8);
-                pc_was_assigned = true;
-                cycles = 10;
-            }
-            RST_7 => {
-                state.call(&mut self.memory, 7 * 8);
+            RST_0 | RST_1 | RST_2 | RST_3 | RST_4 | RST_5 | RST_6 | RST_7 => {
+                state.rst(&mut self.memory, (op >> 3) & 7);
                 pc_was_assigned = true;
-                cycles = 10;
+                cycles = 11;
             }
-            _ => panic!("Don't know how to run opcode: {:02x} at {:04x}", op, state.pc),
         }
 
+        // The compiler checks that every arm above assigns `cycles`, and the single step
+        // tests compare them with the ones of an 8080, so no opcode takes 0 cycles
+
         if ! pc_was_assigned {
-            state.pc += opcode.size;
+            state.pc = (state.pc + opcode.size) & 0xffff;
         }
-
-        if cycles == 0 {
-            panic!("Cycles not assigned");
+        self.cycles += cycles as u64;
+        if let Some(profiler) = self.profiler.as_mut() {
+            // CALL (and its undocumented copies), the conditional calls and RST
+            let is_call = op & 0xcf == 0xcd || op & 0xc7 == 0xc4 || op & 0xc7 == 0xc7;
+            profiler.record(start_pc as u16, cycles, is_call, start_sp as u16, state.sp as u16, state.pc as u16);
         }
 
-        if test_status.status != StepStatus::Continue {
-            test_status
-        } else {
-            StepResult { status: StepStatus::Continue, cycles }
-        }
+        Ok(StepResult { status: StepStatus::Continue, cycles })
     }
 
-    fn interrupt(&mut self, interrupt_number: u8) {
+    pub(crate) fn interrupt(&mut self, interrupt_number: u8) {
         if self.state.as_ref().unwrap().enable_interrupts {
             self.state.as_mut().unwrap().enable_interrupts = false;
+            self.state.as_mut().unwrap().halted = false;
             // log_time(format!("Interrupt {}", interrupt_number).as_str());
             let state = self.state.as_mut().unwrap();
-            self.memory.write(state.sp - 1, ((state.pc as u16 & 0xff00) >> 8) as u8);
-            self.memory.write(state.sp - 2, (state.pc as u16 & 0xff) as u8);
-            state.sp -= 2;
+            state.push(&mut self.memory, (state.pc >> 8) as u8, state.pc as u8);
             // Interrupt 0 goes to $0, 1 to $08, 2 to $10, etc...
             state.pc = (interrupt_number as usize) << 3;
+            if let Some(profiler) = self.profiler.as_mut() {
+                profiler.record_interrupt(state.pc as u16, state.sp as u16);
+            }
         }
     }
 }
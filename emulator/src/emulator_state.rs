@@ -1,8 +1,26 @@
-use crate::memory;
-use crate::memory::GRAPHIC_MEMORY_SIZE;
+use crate::error::EmulatorError;
+use crate::memory::{MemoryHandle, GRAPHIC_MEMORY_SIZE};
 
 use wasm_bindgen::prelude::*;
 
+/// Requests from the frontend that the emulator thread handles between two frames.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    SaveSnapshot(String),
+    LoadSnapshot(String),
+    /// Record a movie starting from a snapshot of the current state
+    StartRecording,
+    /// Stop recording and save the movie in that file
+    StopRecording(String),
+    PlayMovie(String),
+    /// Record the following frames in that animated GIF
+    StartGif(String),
+    StopGif,
+    /// Record the following frames and their sounds in <name>.y4m and <name>.wav
+    StartVideo(String),
+    StopVideo,
+}
+
 #[wasm_bindgen]
 pub struct SharedState {
     megahertz: f64,
@@ -11,12 +29,14 @@ pub struct SharedState {
     out_3: u8,  // sound
     out_5: u8,  // sound
     is_paused: bool,
-
+    is_rewinding: bool,
+    error: Option<EmulatorError>,
+    commands: Vec<Command>,
+    memory: MemoryHandle,
 }
 
-#[wasm_bindgen]
 impl SharedState {
-    pub fn new() -> SharedState {
+    pub fn new(memory: MemoryHandle) -> SharedState {
         SharedState {
             megahertz: 2.0,
             in_1: 8,   // bit 3 is always 1
@@ -24,15 +44,16 @@ impl SharedState {
             out_3: 0,
             out_5: 0,
             is_paused: false,
+            is_rewinding: false,
+            error: None,
+            commands: Vec::new(),
+            memory,
         }
     }
-}
 
-impl SharedState {
-    #[cfg(not(target_arch = "wasm32"))]
     pub fn graphic_memory(&self) -> [u8; GRAPHIC_MEMORY_SIZE] {
         let mut result: [u8; GRAPHIC_MEMORY_SIZE] = [0; GRAPHIC_MEMORY_SIZE];
-        let memory = memory::STATIC_MEMORY.read().unwrap();
+        let memory = self.memory.read().unwrap();
         result.clone_from_slice(&memory[0x2400..0x2400 + GRAPHIC_MEMORY_SIZE]);
         result
     }
@@ -67,10 +88,18 @@ impl SharedState {
     ///  bit 2=Fleet movement 3     SX8 6.raw
     ///  bit 3=Fleet movement 4     SX9 7.raw
     ///  bit 4=UFO Hit              SX10 8.raw
-    pub fn get_out(&self, channel: u8) -> u8 {
-        if channel == 3 { self.out_3 }
-        else if channel == 5 { self.out_5 }
-        else { panic!("Unknown out channel: {}", channel); }
+    pub fn get_out(&self, channel: u8) -> Result<u8, EmulatorError> {
+        if channel == 3 { Ok(self.out_3) }
+        else if channel == 5 { Ok(self.out_5) }
+        else { Err(EmulatorError::UnknownOutChannel(channel)) }
+    }
+
+    pub fn set_in_1(&mut self, value: u8) {
+        self.in_1 = value;
+    }
+
+    pub fn set_in_2(&mut self, value: u8) {
+        self.in_2 = value;
     }
 
     pub fn set_bit_in_1(&mut self, bit: u8, value: bool) {
@@ -99,7 +128,18 @@ impl SharedState {
         self.in_2
     }
 
+    /// The error that stopped the emulator thread, if any.
+    pub fn error(&self) -> Option<&EmulatorError> { self.error.as_ref() }
+    pub fn set_error(&mut self, error: EmulatorError) { self.error = Some(error); }
+
+    pub fn send(&mut self, command: Command) { self.commands.push(command); }
+    pub fn take_commands(&mut self) -> Vec<Command> { std::mem::take(&mut self.commands) }
+
     pub fn is_paused(&self) -> bool { self.is_paused }
     pub fn pause(&mut self) { self.is_paused = true; }
     pub fn unpause(&mut self) { self.is_paused = false; }
+
+    /// While set, the emulator thread goes back one frame instead of running one.
+    pub fn is_rewinding(&self) -> bool { self.is_rewinding }
+    pub fn set_rewinding(&mut self, rewinding: bool) { self.is_rewinding = rewinding; }
 }
@@ -0,0 +1,70 @@
+use std::fmt;
+use crate::state::Registers;
+
+/// Everything that can go wrong while loading or running a program. The variants raised
+/// by `Emulator::step` carry the pc and opcode of the faulting instruction and a snapshot
+/// of the registers at that point, so the host can display them and decide what to do.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmulatorError {
+    UnknownOpcode { pc: usize, opcode: u8, registers: Registers },
+    UnsupportedInPort { port: u8, pc: usize, opcode: u8, registers: Registers },
+    UnknownOutChannel(u8),
+    Io { file_name: String, message: String },
+    InvalidSnapshot(String),
+    /// The snapshot was taken on a different machine than the one it's restored into
+    SnapshotRomMismatch { expected: String, found: String },
+    InvalidMovie(String),
+    /// The movie was recorded with a different ROM than the one currently loaded
+    MovieRomMismatch { expected: u32, found: u32 },
+    /// An error in the source given to `assembler::assemble`, with its line number
+    Assembly { line: usize, message: String },
+    /// A program run by `cpm::Cpm` that went wrong
+    Cpm(String),
+}
+
+impl EmulatorError {
+    pub fn pc(&self) -> Option<usize> {
+        match self {
+            EmulatorError::UnknownOpcode { pc, .. } |
+            EmulatorError::UnsupportedInPort { pc, .. } => Some(*pc),
+            _ => None,
+        }
+    }
+
+    pub fn registers(&self) -> Option<&Registers> {
+        match self {
+            EmulatorError::UnknownOpcode { registers, .. } |
+            EmulatorError::UnsupportedInPort { registers, .. } => Some(registers),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::UnknownOpcode { pc, opcode, registers } =>
+                write!(f, "Don't know how to run opcode {:02x} at {:04x}    {}", opcode, pc, registers),
+            EmulatorError::UnsupportedInPort { port, pc, opcode, registers } =>
+                write!(f, "Unsupported IN port {} ({:02x} at {:04x})    {}", port, opcode, pc, registers),
+            EmulatorError::UnknownOutChannel(channel) =>
+                write!(f, "Unknown out channel: {}", channel),
+            EmulatorError::Io { file_name, message } =>
+                write!(f, "I/O error on {}: {}", file_name, message),
+            EmulatorError::InvalidSnapshot(message) =>
+                write!(f, "Invalid snapshot: {}", message),
+            EmulatorError::SnapshotRomMismatch { expected, found } =>
+                write!(f, "Snapshot is for ROM set '{}', not '{}'", found, expected),
+            EmulatorError::InvalidMovie(message) =>
+                write!(f, "Invalid movie: {}", message),
+            EmulatorError::MovieRomMismatch { expected, found } =>
+                write!(f, "Movie was recorded with ROM {:08x}, not {:08x}", found, expected),
+            EmulatorError::Assembly { line, message } =>
+                write!(f, "Line {}: {}", line, message),
+            EmulatorError::Cpm(message) =>
+                write!(f, "CP/M: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
@@ -0,0 +1,301 @@
+//! A GDB remote serial protocol server, so that GDB (or any RSP client) can debug the
+//! emulator over TCP: `target remote localhost:1234`.
+//!
+//! The registers are the six 16 bit pairs AF, BC, DE, HL, SP and PC, in this order and
+//! little endian, which is also what the target description sent to GDB says. GDB has
+//! no 8080 architecture, so the description doesn't name one.
+//!
+//! The server supports register and memory reads and writes, software breakpoints
+//! (hardware ones are treated the same), write, read and access watchpoints, single step,
+//! continue and Ctrl-C.
+
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+use crate::debugger::{self, Debugger, Stop};
+use crate::hooks::Access;
+use crate::memory::MEMORY_SIZE;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gnu.gdb.i8080.cpu">
+    <reg name="af" bitsize="16" type="int" regnum="0"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="data_ptr"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// The registers of the `g` packet, by GDB number
+const REGISTERS: [&str; 6] = ["PSW", "BC", "DE", "HL", "SP", "PC"];
+
+/// The signals of the stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// Accept one connection on `address` (e.g. "127.0.0.1:1234") and serve it until the
+/// client detaches or disconnects.
+pub fn listen(debugger: &mut Debugger, address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    serve(debugger, stream)
+}
+
+/// Serve a client on an established connection.
+pub fn serve(debugger: &mut Debugger, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let packets = spawn_reader(stream.try_clone()?, debugger.break_handle());
+    let mut connection = Connection { stream, acknowledge: true };
+    for packet in packets {
+        let packet = match packet {
+            Some(packet) => packet,
+            // Ask for it again
+            None => {
+                if connection.acknowledge {
+                    connection.stream.write_all(b"-")?;
+                }
+                continue;
+            },
+        };
+        if connection.acknowledge {
+            connection.stream.write_all(b"+")?;
+        }
+        match packet.as_str() {
+            "k" => break,
+            "D" | "D;1" => {
+                connection.send("OK")?;
+                break;
+            },
+            "QStartNoAckMode" => {
+                connection.send("OK")?;
+                connection.acknowledge = false;
+            },
+            _ => {
+                for reply in handle(debugger, &packet) {
+                    connection.send(&reply)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Connection {
+    stream: TcpStream,
+    acknowledge: bool,
+}
+
+impl Connection {
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let mut escaped = Vec::with_capacity(data.len());
+        for b in data.bytes() {
+            if matches!(b, b'#' | b'$' | b'}' | b'*') {
+                escaped.extend_from_slice(&[b'}', b ^ 0x20]);
+            } else {
+                escaped.push(b);
+            }
+        }
+        let checksum = escaped.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        let mut packet = vec![b'$'];
+        packet.extend(escaped);
+        packet.extend(format!("#{:02x}", checksum).bytes());
+        self.stream.write_all(&packet)
+    }
+}
+
+/// Read the packets in a thread, so that a Ctrl-C can interrupt the target while the
+/// main thread runs it. GDB only sends one while the target runs, the stop reply is the
+/// one of the interrupted run. The packets with a wrong checksum are `None`.
+fn spawn_reader(stream: TcpStream, break_handle: Arc<AtomicBool>) -> Receiver<Option<String>> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut bytes = BufReader::new(stream).bytes().map_while(Result::ok);
+        while let Some(b) = bytes.next() {
+            match b {
+                0x03 => break_handle.store(true, Ordering::Relaxed),
+                b'$' => {
+                    let data: Vec<u8> = bytes.by_ref().take_while(|b| *b != b'#').collect();
+                    let checksum: Vec<u8> = bytes.by_ref().take(2).collect();
+                    let expected = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+                    let packet = from_hex(&String::from_utf8_lossy(&checksum))
+                        .filter(|checksum| checksum[..] == [expected])
+                        .map(|_| String::from_utf8_lossy(&data).into_owned());
+                    if sender.send(packet).is_err() {
+                        break;
+                    }
+                },
+                // Acknowledgements
+                _ => {},
+            }
+        }
+    });
+    receiver
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if ! s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn number(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// The stop reply, after a console output packet with the message of the error that
+/// stopped the target, if there's one.
+fn stop_reply(stop: Result<Stop, crate::error::EmulatorError>) -> Vec<String> {
+    let signal = match stop {
+        Ok(Stop::Watchpoint(event)) => {
+            let kind = if event.access == Access::Read { "rwatch" } else { "watch" };
+            return vec![format!("T{:02x}{}:{:x};", SIGTRAP, kind, event.address)];
+        },
+        Ok(Stop::Interrupted) => SIGINT,
+        Ok(_) => SIGTRAP,
+        Err(e) => return vec![format!("O{}", hex(format!("{}\n", e).as_bytes())), format!("S{:02x}", SIGILL)],
+    };
+    vec![format!("S{:02x}", signal)]
+}
+
+fn read_register(debugger: &Debugger, number: usize) -> Option<String> {
+    let value = debugger::register(&debugger.registers(), REGISTERS.get(number)?)?;
+    Some(hex(&value.to_le_bytes()))
+}
+
+fn write_register(debugger: &mut Debugger, number: usize, value: &str) -> Option<()> {
+    let bytes = from_hex(value).filter(|b| b.len() == 2)?;
+    debugger.set_register(REGISTERS.get(number)?, u16::from_le_bytes([bytes[0], bytes[1]])).ok()
+}
+
+/// The packets to reply to a packet with: one, empty for the packets that aren't
+/// supported, or the ones of `stop_reply()`.
+fn handle(debugger: &mut Debugger, packet: &str) -> Vec<String> {
+    let reply = |ok: Option<()>| if ok.is_some() { "OK".to_string() } else { "E01".to_string() };
+    let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+    let reply = match command {
+        "?" => format!("S{:02x}", SIGTRAP),
+        "g" => (0..REGISTERS.len()).filter_map(|n| read_register(debugger, n)).collect(),
+        "G" => reply((0..REGISTERS.len())
+            .map(|n| write_register(debugger, n, arguments.get(n * 4..n * 4 + 4)?))
+            .collect()),
+        "p" => number(arguments).and_then(|n| read_register(debugger, n)).unwrap_or_else(|| "E01".into()),
+        "P" => reply(arguments.split_once('=')
+            .and_then(|(n, value)| write_register(debugger, number(n)?, value))),
+        // At most the whole memory
+        "m" => arguments.split_once(',')
+            .and_then(|(address, length)| {
+                let length = number(length).filter(|length| *length <= MEMORY_SIZE)?;
+                Some(hex(&debugger.read_memory(number(address)? as u16, length)))
+            })
+            .unwrap_or_else(|| "E01".into()),
+        "M" => reply(arguments.split_once(',').and_then(|(address, rest)| {
+            let (_, data) = rest.split_once(':')?;
+            debugger.write_memory(number(address)? as u16, &from_hex(data)?);
+            Some(())
+        })),
+        "Z" | "z" => {
+            let fields: Vec<&str> = arguments.split(',').collect();
+            match (fields.first(), fields.get(1).and_then(|a| number(a))) {
+                (Some(&"0"), Some(address)) | (Some(&"1"), Some(address)) => {
+                    if command == "Z" {
+                        debugger.add_breakpoint(address as u16, None);
+                    } else {
+                        debugger.remove_breakpoint(address as u16);
+                    }
+                    "OK".into()
+                },
+                // Watchpoints: write, read, access, with the address and the length
+                (Some(&kind), Some(address)) if ["2", "3", "4"].contains(&kind) => {
+                    let length = fields.get(2).and_then(|l| number(l)).unwrap_or(1).max(1);
+                    let end = match address.checked_add(length - 1) {
+                        Some(end) => end,
+                        None => return vec!["E01".into()],
+                    };
+                    let range = address as u16..=end.min(0xffff) as u16;
+                    let accesses = match kind {
+                        "2" => vec![Access::Write],
+                        "3" => vec![Access::Read],
+                        _ => vec![Access::Read, Access::Write],
+                    };
+                    for access in accesses {
+                        if command == "Z" {
+                            debugger.add_watchpoint(access, range.clone());
+                        } else if let Some(id) = debugger.watchpoints().iter()
+                                .find(|w| w.access == access && w.range == range).map(|w| w.id) {
+                            debugger.remove_watchpoint(id);
+                        }
+                    }
+                    "OK".into()
+                },
+                _ => String::new(),
+            }
+        },
+        "s" => return step(debugger, arguments),
+        "c" => return cont(debugger, arguments),
+        "H" => "OK".into(),
+        "T" => "OK".into(),
+        "q" => query(arguments),
+        "v" => match arguments {
+            "Cont?" => "vCont;c;C;s;S".into(),
+            _ if arguments.starts_with("Cont;c") || arguments.starts_with("Cont;C") => return cont(debugger, ""),
+            _ if arguments.starts_with("Cont;s") || arguments.starts_with("Cont;S") => return step(debugger, ""),
+            _ => String::new(),
+        },
+        _ => String::new(),
+    };
+    vec![reply]
+}
+
+/// `s [address]`
+fn step(debugger: &mut Debugger, address: &str) -> Vec<String> {
+    if let Some(address) = number(address) {
+        let _ = debugger.set_register("PC", address as u16);
+    }
+    stop_reply(debugger.step())
+}
+
+/// `c [address]`
+fn cont(debugger: &mut Debugger, address: &str) -> Vec<String> {
+    if let Some(address) = number(address) {
+        let _ = debugger.set_register("PC", address as u16);
+    }
+    stop_reply(debugger.run(None))
+}
+
+fn query(query: &str) -> String {
+    match query {
+        _ if query.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".into(),
+        "Attached" => "1".into(),
+        "C" => "QC1".into(),
+        "fThreadInfo" => "m1".into(),
+        "sThreadInfo" => "l".into(),
+        _ if query.starts_with("Xfer:features:read:target.xml:") => {
+            let range = &query["Xfer:features:read:target.xml:".len()..];
+            let (offset, end) = match range.split_once(',').and_then(|(offset, length)| {
+                let offset = number(offset)?;
+                Some((offset, offset.checked_add(number(length)?)?))
+            }) {
+                Some(range) => range,
+                None => return "E01".into(),
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = end.min(TARGET_XML.len());
+            format!("{}{}", if end == TARGET_XML.len() { 'l' } else { 'm' }, &TARGET_XML[start..end])
+        },
+        _ => String::new(),
+    }
+}
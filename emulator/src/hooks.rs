@@ -0,0 +1,98 @@
+//! Callbacks on memory accesses and execution, registered with `Emulator::add_hook()`:
+//! when the score bytes are written, when a table is read, when PC reaches a routine...
+//!
+//! The accesses are the ones of the instructions: `Read` for their data reads (including
+//! the stack), `Write` for their writes, `Execute` before an instruction runs, for its
+//! address. The fetches of the opcodes and operands, and the reads and writes of the
+//! debuggers through `Emulator::read_memory()` and `write_memory()`, aren't hooked.
+//!
+//! A hook can change the value of a read or a write, e.g. for a cheat:
+//!
+//! ```
+//! # use emulator::emulator::Emulator;
+//! # use emulator::hooks::Access;
+//! # use emulator::memory::Memory;
+//! let mut emulator = Emulator::new(Box::new(Memory::new()), 0);
+//! // The number of lives of player 1 never goes down
+//! emulator.add_hook(Access::Write, 0x21ff..=0x21ff, |event| event.value = event.value.max(3));
+//! ```
+//!
+//! Without hooks, the accesses only cost the check that there are none.
+
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// An access, given to the hooks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MemoryEvent {
+    pub access: Access,
+    pub address: u16,
+    /// The value read or written, which the hook can change. For `Execute`, the opcode.
+    pub value: u8,
+    /// The address of the instruction making the access
+    pub pc: u16,
+}
+
+impl fmt::Display for MemoryEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.access {
+            Access::Read => write!(f, "{:04x} read {:02x} from {:04x}", self.pc, self.value, self.address),
+            Access::Write => write!(f, "{:04x} wrote {:02x} to {:04x}", self.pc, self.value, self.address),
+            Access::Execute => write!(f, "executed {:04x}", self.address),
+        }
+    }
+}
+
+/// Identifies a hook, to remove it.
+pub type HookId = usize;
+
+struct Hook {
+    id: HookId,
+    access: Access,
+    range: RangeInclusive<u16>,
+    callback: Box<dyn FnMut(&mut MemoryEvent) + Send>,
+}
+
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hooks: Vec<Hook>,
+    next_id: HookId,
+    /// The address of the instruction running
+    pub(crate) pc: u16,
+}
+
+impl Hooks {
+    pub(crate) fn add(&mut self, access: Access, range: RangeInclusive<u16>,
+            callback: Box<dyn FnMut(&mut MemoryEvent) + Send>) -> HookId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.hooks.push(Hook { id, access, range, callback });
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: HookId) -> bool {
+        let count = self.hooks.len();
+        self.hooks.retain(|hook| hook.id != id);
+        self.hooks.len() < count
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Call the hooks of that access and address, returns the value they leave.
+    pub(crate) fn call(&mut self, access: Access, address: u16, value: u8) -> u8 {
+        let mut event = MemoryEvent { access, address, value, pc: self.pc };
+        for hook in self.hooks.iter_mut().filter(|hook| hook.access == access && hook.range.contains(&address)) {
+            (hook.callback)(&mut event);
+        }
+        event.value
+    }
+}
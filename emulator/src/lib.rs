@@ -1,21 +1,59 @@
+pub mod assembler;
+pub mod coverage;
+pub mod cpm;
+pub mod crash;
+pub mod dap;
+pub mod debugger;
+pub mod disassembler;
 pub mod emulator;
+pub mod error;
+pub mod gdb;
+pub mod hooks;
 pub mod memory;
+pub mod monitor;
 pub mod state;
 pub mod emulator_state;
+pub mod movie;
 pub mod opcodes;
+pub mod profiler;
+pub mod recording;
+pub mod rewind;
+pub mod screen;
+pub mod snapshot;
+pub mod source_map;
+pub mod sound;
+pub mod symbols;
+pub mod trace;
+pub mod trace_diff;
+#[cfg(test)]
 mod test;
-
-#[allow(dead_code)]
-const VERBOSE: bool = false;
-static mut VERBOSE_DISASSEMBLE: bool = false;
-#[allow(dead_code)]
-const VERBOSE_GRAPHIC: bool = true;
-const VERBOSE_DISASSEMBLE_SECTION: bool = false;
-const DISASSEMBLE_SECTION_START: usize = 0x1439;
-const DISASSEMBLE_SECTION_END: usize = 0x1447;
-// const DISASSEMBLE_SECTION_START: usize = 0x1439;
-// const DISASSEMBLE_SECTION_END: usize = 0x1447;
-#[allow(dead_code)]
-const VERBOSE_MEMORY: bool = false;
-
-
+#[cfg(test)]
+mod test_assembler;
+#[cfg(test)]
+mod test_coverage;
+#[cfg(test)]
+mod test_cpm;
+#[cfg(test)]
+mod test_crash;
+#[cfg(test)]
+mod test_dap;
+#[cfg(test)]
+mod test_debugger;
+#[cfg(test)]
+mod test_flags;
+#[cfg(test)]
+mod test_hooks;
+#[cfg(test)]
+mod test_monitor;
+#[cfg(test)]
+mod test_profiler;
+#[cfg(test)]
+mod test_single_step;
+#[cfg(test)]
+mod test_snapshot;
+#[cfg(test)]
+mod test_symbols;
+#[cfg(test)]
+mod test_trace;
+#[cfg(test)]
+mod test_util;
@@ -1,11 +1,17 @@
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::Read;
+use crate::error::EmulatorError;
 use crate::state::State;
-use crate::opcodes::Opcode;
-use std::sync::{RwLock};
-use lazy_static::lazy_static;
+use crate::coverage::{self, Coverage};
+use crate::disassembler;
+use crate::hooks::{Access, HookId, Hooks, MemoryEvent};
+use crate::symbols::Symbols;
+use std::sync::{Arc, RwLock};
 
-const MEMORY_SIZE: usize = 0x10000;
+pub const MEMORY_SIZE: usize = 0x10000;
+/// The ROM occupies 0x0000-0x1fff, RAM starts right after
+pub const ROM_SIZE: usize = 0x2000;
 pub const SCREEN_WIDTH: usize = 0x20;  // 0x20 bytes (256 pixels)
 pub const SCREEN_HEIGHT: usize = 0xe0;
 pub const GRAPHIC_MEMORY_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
@@ -16,69 +22,169 @@ pub trait GraphicRenderer: Send {
     fn display(&self);
 }
 
-static _STATIC_MEMORY: [u8; MEMORY_SIZE] = [0; MEMORY_SIZE];
+/// A handle to the 64K address space of one machine. Each `Memory` owns its own, and
+/// the `SharedState` of the same emulator keeps a clone so the frontend can read the
+/// graphic memory from another thread.
+pub type MemoryHandle = Arc<RwLock<[u8; MEMORY_SIZE]>>;
 
-lazy_static! {
-    pub(crate) static ref STATIC_MEMORY: RwLock<[u8; MEMORY_SIZE]> = RwLock::new(_STATIC_MEMORY);
+pub struct Memory {
+    memory: MemoryHandle,
+    /// Records the data reads when enabled, see `Emulator::enable_coverage()`
+    coverage: Option<RefCell<Coverage>>,
+    /// `None` when there are no hooks, see `Emulator::add_hook()`
+    hooks: Option<RefCell<Hooks>>,
 }
 
-pub struct Memory {
-    pub verbose: bool,
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
         Memory {
-            verbose: false,
+            memory: Arc::new(RwLock::new([0; MEMORY_SIZE])),
+            coverage: None,
+            hooks: None,
         }
     }
 
-    pub fn read_file(&mut self, file_name: &str, start: usize) {
-        let mut file = File::open(file_name).expect("Couldn't open file");
+    pub fn handle(&self) -> MemoryHandle {
+        self.memory.clone()
+    }
+
+    pub fn read_file(&mut self, file_name: &str, start: usize) -> Result<(), EmulatorError> {
+        let io_error = |e: std::io::Error| EmulatorError::Io {
+            file_name: file_name.into(), message: e.to_string()
+        };
+        let mut file = File::open(file_name).map_err(io_error)?;
         let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).expect("Couldn't read file");
+        file.read_to_end(&mut buffer).map_err(io_error)?;
 
-        let mut state = State::default();
-        state.pc = start;
+        let state = State::new(start);
 
-        let mut i: usize = 0;
-        for byte in buffer {
+        for (i, byte) in buffer.into_iter().enumerate() {
             self.write(i + state.pc, byte);
-            i += 1;
         }
+        Ok(())
     }
 
+    /// A write by an instruction, which the hooks see. Addresses wrap around at 64K, like
+    /// on the 8080.
     pub(crate) fn write(&mut self, address: usize, value: u8) {
-        STATIC_MEMORY.write().unwrap()[address] = value;
-        if self.verbose {
-            println!("    mem[{:04x}={:02X}]", address, value    );
-        }
+        let address = address & (MEMORY_SIZE - 1);
+        let value = match &mut self.hooks {
+            Some(hooks) => hooks.get_mut().call(Access::Write, address as u16, value),
+            None => value,
+        };
+        self.poke(address, value);
+    }
+
+    /// A write that isn't an instruction's, e.g. of a debugger.
+    pub(crate) fn poke(&mut self, address: usize, value: u8) {
+        self.memory.write().unwrap()[address & (MEMORY_SIZE - 1)] = value;
     }
 
+    /// A read by an instruction, which counts for the coverage and the hooks.
     pub(crate) fn read(&self, i: usize) -> u8 {
-        STATIC_MEMORY.read().unwrap()[i]
+        if let Some(coverage) = &self.coverage {
+            coverage.borrow_mut().mark(i, coverage::READ);
+        }
+        let value = self.peek(i);
+        match &self.hooks {
+            Some(hooks) => hooks.borrow_mut().call(Access::Read, (i & (MEMORY_SIZE - 1)) as u16, value),
+            None => value,
+        }
+    }
+
+    /// A read that isn't a data access: instruction fetches, debuggers, etc...
+    pub(crate) fn peek(&self, i: usize) -> u8 {
+        self.memory.read().unwrap()[i & (MEMORY_SIZE - 1)]
     }
 
     pub(crate) fn read_word(&self, b0: u8, b1: u8) -> u8 {
-        STATIC_MEMORY.read().unwrap()[Memory::to_word(b0, b1)]
+        self.read(Memory::to_word(b0, b1))
     }
 
     pub(crate) fn write_word(&mut self, b0: u8, b1: u8, value: u8) {
-        let address = Memory::to_word(b0, b1);
-        STATIC_MEMORY.write().unwrap()[address] = value;
+        self.write(Memory::to_word(b0, b1), value);
+    }
+
+    pub(crate) fn enable_coverage(&mut self, coverage: Coverage) {
+        self.coverage = Some(RefCell::new(coverage));
+    }
+
+    pub(crate) fn disable_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take().map(RefCell::into_inner)
     }
 
-    pub(crate) fn disassemble(&self, opcode: &Opcode, pc: usize) -> (String, usize) {
-        let formatted_opcode = match opcode.size {
-            1 => opcode.display1(),
-            2 => opcode.display2(self.read(pc + 1)),
-            _ => opcode.display3(self.read(pc + 1), self.read(pc + 2)),
+    pub(crate) fn coverage(&self) -> Option<Coverage> {
+        self.coverage.as_ref().map(|coverage| coverage.borrow().clone())
+    }
+
+    /// Mark the bytes of the instruction at `pc` as executed.
+    pub(crate) fn mark_executed(&self, pc: usize, size: usize) {
+        if let Some(coverage) = &self.coverage {
+            let mut coverage = coverage.borrow_mut();
+            coverage.mark(pc, coverage::OPCODE);
+            for i in 1..size {
+                coverage.mark(pc + i, coverage::OPERAND);
+            }
+        }
+    }
+
+    pub(crate) fn add_hook(&mut self, access: Access, range: std::ops::RangeInclusive<u16>,
+            callback: Box<dyn FnMut(&mut MemoryEvent) + Send>) -> HookId {
+        self.hooks.get_or_insert_with(Default::default).get_mut().add(access, range, callback)
+    }
+
+    pub(crate) fn remove_hook(&mut self, id: HookId) -> bool {
+        let hooks = match &mut self.hooks {
+            Some(hooks) => hooks.get_mut(),
+            None => return false,
         };
-        let result = format!("{:04x}: {}", pc, formatted_opcode);
-        (result, opcode.size)
+        let removed = hooks.remove(id);
+        if hooks.is_empty() {
+            self.hooks = None;
+        }
+        removed
+    }
+
+    /// The instruction at `pc` is about to run: call the execute hooks, and tell the
+    /// others which instruction makes the accesses.
+    pub(crate) fn execute(&mut self, pc: usize) {
+        if let Some(hooks) = &mut self.hooks {
+            let opcode = self.memory.read().unwrap()[pc & (MEMORY_SIZE - 1)];
+            let hooks = hooks.get_mut();
+            hooks.pc = pc as u16;
+            hooks.call(Access::Execute, pc as u16, opcode);
+        }
+    }
+
+    /// A copy of the whole address space.
+    pub(crate) fn dump(&self) -> Vec<u8> {
+        self.memory.read().unwrap().to_vec()
+    }
+
+    /// Replace the whole address space, `bytes` must be `MEMORY_SIZE` long.
+    pub(crate) fn load(&mut self, bytes: &[u8]) {
+        self.memory.write().unwrap().copy_from_slice(bytes);
+    }
+
+    /// The instruction at `pc`, with its address and bytes, and its size. The operands
+    /// and PC are shown with their names in `symbols`.
+    pub fn disassemble(&self, pc: usize, symbols: &Symbols) -> (String, usize) {
+        let bytes: Vec<u8> = (0..3).map(|i| self.peek(pc + i)).collect();
+        let instruction = disassembler::decode(&bytes, pc as u16);
+        let hex: Vec<String> = instruction.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let label = |address: u16| symbols.label(address);
+        let location = symbols.location(pc as u16).map(|name| format!(" <{}>", name)).unwrap_or_default();
+        let result = format!("{:04x}{}: {:10} {:16}", pc, location, hex.join(" "), instruction.format(&label));
+        (result, instruction.size())
     }
 
     pub(crate) fn to_word(b1: u8, b2: u8) -> usize {
-        return ((b2 as u16) << 8 | b1 as u16) as usize;
+        ((b2 as u16) << 8 | b1 as u16) as usize
     }
 }
@@ -0,0 +1,245 @@
+//! A monitor of the invariants of the game, for long soak tests: code running away into
+//! the RAM, a stack overflowing, a write to the ROM or interrupts that are never enabled
+//! again are bugs of the emulator (or of the ROM) that can take hours of play to show.
+//!
+//! Enabled with `Emulator::enable_monitor()`, the monitor checks after each instruction
+//! run by `run_one_frame()` that:
+//!
+//! - PC is in `MonitorConfig::code`,
+//! - SP is in `MonitorConfig::stack`,
+//! - nothing is written to `MonitorConfig::rom` (with a hook, see `hooks`),
+//!
+//! and at the end of each frame that the interrupts weren't disabled for more than
+//! `MonitorConfig::interrupt_frames` frames in a row.
+//!
+//! Each violation is counted. The first ones are kept with the registers and the last
+//! instructions before them, from the history of the emulator (see `crash`). PC and SP are
+//! reported when they leave their range, not at each instruction while they're out of it,
+//! and SP only once the program has set it.
+
+use std::fmt;
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::emulator::Emulator;
+use crate::hooks::{HookId, MemoryEvent};
+use crate::state::Registers;
+use crate::trace::TraceEntry;
+
+/// The number of violations kept with their context
+const REPORTED: usize = 20;
+/// The number of instructions before a violation kept with it
+const CONTEXT: usize = 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonitorConfig {
+    /// Where the code is
+    pub code: RangeInclusive<u16>,
+    /// Where the stack can go, including the address it starts from
+    pub stack: RangeInclusive<u16>,
+    /// The memory that must not be written
+    pub rom: RangeInclusive<u16>,
+    /// How many frames in a row the interrupts can stay disabled
+    pub interrupt_frames: u64,
+}
+
+impl MonitorConfig {
+    /// Space Invaders: the code in the ROM at 0x0000-0x1fff, the stack in the work RAM,
+    /// from 0x2400 down.
+    pub fn space_invaders() -> MonitorConfig {
+        MonitorConfig { code: 0..=0x1fff, stack: 0x2000..=0x2400, rom: 0..=0x1fff, interrupt_frames: 60 }
+    }
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        MonitorConfig::space_invaders()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ViolationKind {
+    /// PC left the code, to that address
+    Pc(u16),
+    /// SP left the stack, to that address
+    Sp(u16),
+    RomWrite { address: u16, value: u8 },
+    /// The interrupts have been disabled for that many frames
+    Interrupts(u64),
+}
+
+impl ViolationKind {
+    const DESCRIPTIONS: [&'static str; 4] = [
+        "PC outside of the code", "SP outside of the stack", "Writes to the ROM", "Interrupts disabled too long",
+    ];
+
+    fn index(&self) -> usize {
+        match self {
+            ViolationKind::Pc(_) => 0,
+            ViolationKind::Sp(_) => 1,
+            ViolationKind::RomWrite { .. } => 2,
+            ViolationKind::Interrupts(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ViolationKind::Pc(pc) => write!(f, "PC outside of the code: {:04x}", pc),
+            ViolationKind::Sp(sp) => write!(f, "SP outside of the stack: {:04x}", sp),
+            ViolationKind::RomWrite { address, value } => write!(f, "Write of {:02x} to the ROM at {:04x}", value, address),
+            ViolationKind::Interrupts(frames) => write!(f, "Interrupts disabled for {} frames", frames),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    /// The frame it happened in, counted from when the monitor was enabled
+    pub frame: u64,
+    /// The cycles run by the emulator
+    pub cycles: u64,
+    /// The registers after the instruction that made it
+    pub registers: Registers,
+    /// The last instructions, the oldest first
+    pub context: Vec<TraceEntry>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Frame {}, cycle {}: {}\n    {}", self.frame, self.cycles, self.kind, self.registers)?;
+        for entry in &self.context {
+            writeln!(f, "    {}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Monitor {
+    config: MonitorConfig,
+    frames: u64,
+    counts: [u64; 4],
+    violations: Vec<Violation>,
+    pc_in_code: bool,
+    /// False until the program sets SP, which it starts without
+    sp_in_stack: bool,
+    /// Frames in a row with the interrupts disabled
+    disabled_frames: u64,
+    /// The writes to the ROM seen by the hook since the last check, and whether there
+    /// are any, which is cheaper to check after each instruction
+    rom_writes: Arc<Mutex<Vec<MemoryEvent>>>,
+    rom_written: Arc<AtomicBool>,
+    pub(crate) hook: Option<HookId>,
+}
+
+impl Monitor {
+    pub fn new(config: MonitorConfig) -> Monitor {
+        Monitor {
+            config, frames: 0, counts: [0; 4], violations: Vec::new(), pc_in_code: true, sp_in_stack: false,
+            disabled_frames: 0, rom_writes: Arc::new(Mutex::new(Vec::new())),
+            rom_written: Arc::new(AtomicBool::new(false)), hook: None,
+        }
+    }
+
+    pub fn config(&self) -> &MonitorConfig {
+        &self.config
+    }
+
+    /// The callback of the write hook on the ROM.
+    pub(crate) fn rom_hook(&self) -> impl FnMut(&mut MemoryEvent) + Send + 'static {
+        let (rom_writes, rom_written) = (self.rom_writes.clone(), self.rom_written.clone());
+        move |event| {
+            rom_writes.lock().unwrap().push(*event);
+            rom_written.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The frames checked so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The number of violations.
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The number of violations of each kind, with its description.
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        ViolationKind::DESCRIPTIONS.iter().copied().zip(self.counts.iter().copied()).collect()
+    }
+
+    /// The first violations, with their context.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    fn report(&mut self, kind: ViolationKind, emulator: &Emulator) {
+        self.counts[kind.index()] += 1;
+        if self.violations.len() < REPORTED {
+            let history = emulator.history();
+            self.violations.push(Violation {
+                kind,
+                frame: self.frames,
+                cycles: emulator.cycles(),
+                registers: emulator.registers(),
+                context: history[history.len().saturating_sub(CONTEXT)..].to_vec(),
+            });
+        }
+    }
+
+    /// Check the invariants after an instruction.
+    pub(crate) fn check_instruction(&mut self, emulator: &Emulator) {
+        let registers = emulator.registers();
+        let (pc, sp) = (registers.pc as u16, registers.sp as u16);
+        let pc_in_code = self.config.code.contains(&pc);
+        if ! pc_in_code && self.pc_in_code {
+            self.report(ViolationKind::Pc(pc), emulator);
+        }
+        self.pc_in_code = pc_in_code;
+        let sp_in_stack = self.config.stack.contains(&sp);
+        if ! sp_in_stack && self.sp_in_stack {
+            self.report(ViolationKind::Sp(sp), emulator);
+        }
+        self.sp_in_stack = sp_in_stack;
+        if ! self.rom_written.load(Ordering::Relaxed) {
+            return;
+        }
+        self.rom_written.store(false, Ordering::Relaxed);
+        let rom_writes = std::mem::take(&mut *self.rom_writes.lock().unwrap());
+        for event in rom_writes {
+            self.report(ViolationKind::RomWrite { address: event.address, value: event.value }, emulator);
+        }
+    }
+
+    /// Check the invariants at the end of a frame, before its last interrupt.
+    pub(crate) fn check_frame(&mut self, emulator: &Emulator) {
+        if emulator.registers().enable_interrupts {
+            self.disabled_frames = 0;
+        } else {
+            self.disabled_frames += 1;
+            if self.disabled_frames == self.config.interrupt_frames + 1 {
+                self.report(ViolationKind::Interrupts(self.disabled_frames), emulator);
+            }
+        }
+        self.frames += 1;
+    }
+
+    /// The number of frames and of violations, by kind, then the first violations.
+    pub fn summary(&self) -> String {
+        let mut result = format!("Frames: {}\nViolations: {}\n", self.frames, self.total());
+        for (description, count) in self.counts() {
+            let _ = writeln!(result, "  {}: {}", description, count);
+        }
+        if ! self.violations.is_empty() {
+            let _ = writeln!(result, "\nThe first {} violations:", self.violations.len());
+        }
+        for violation in &self.violations {
+            let _ = write!(result, "\n{}", violation);
+        }
+        result
+    }
+}
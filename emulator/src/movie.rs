@@ -0,0 +1,134 @@
+//! Movies: the inputs of each frame, recorded so a game can be replayed exactly. Space
+//! Invaders is only influenced by the input ports 1 and 2, and `run_one_frame()` reads them
+//! once at the beginning of each frame, so replaying their values frame by frame from the
+//! same starting state reproduces the game bit for bit.
+//!
+//! File format, little endian:
+//!
+//! | Size  | Content                                                            |
+//! |-------|--------------------------------------------------------------------|
+//! | 8     | Magic: `8080MOVI`                                                  |
+//! | 2     | Format version                                                     |
+//! | 4     | CRC-32 of the ROM (0x0000-0x1fff)                                  |
+//! | 4 + n | Starting snapshot: length (0 if none), then the snapshot file      |
+//! | 4     | Number of frames                                                   |
+//! | 2 * n | Ports 1 and 2 for each frame                                       |
+//! | 4     | CRC-32 of all the previous bytes                                   |
+//!
+//! Without a starting snapshot, the movie starts when the machine is powered on.
+
+use std::fs;
+use crate::error::EmulatorError;
+use crate::snapshot::{crc32, Reader, Snapshot};
+
+const MAGIC: &[u8; 8] = b"8080MOVI";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_hash: u32,
+    pub start: Option<Snapshot>,
+    /// The values of the ports 1 and 2 during each frame
+    pub frames: Vec<(u8, u8)>,
+}
+
+fn invalid(message: &str) -> EmulatorError {
+    EmulatorError::InvalidMovie(message.into())
+}
+
+impl Movie {
+    pub fn new(rom_hash: u32, start: Option<Snapshot>) -> Movie {
+        Movie { rom_hash, start, frames: Vec::new() }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.extend_from_slice(MAGIC);
+        result.extend_from_slice(&VERSION.to_le_bytes());
+        result.extend_from_slice(&self.rom_hash.to_le_bytes());
+        let snapshot = self.start.as_ref().map(|s| s.to_bytes()).unwrap_or_default();
+        result.extend_from_slice(&(snapshot.len() as u32).to_le_bytes());
+        result.extend_from_slice(&snapshot);
+        result.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        for (in_1, in_2) in &self.frames {
+            result.push(*in_1);
+            result.push(*in_2);
+        }
+        let crc = crc32(&result);
+        result.extend_from_slice(&crc.to_le_bytes());
+        result
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, EmulatorError> {
+        if bytes.len() < MAGIC.len() + 2 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a movie"));
+        }
+        let (content, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32(content).to_le_bytes() != crc {
+            return Err(invalid("wrong checksum"));
+        }
+        let mut reader = Reader::new(content, MAGIC.len(), invalid);
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let rom_hash = reader.u32()?;
+        let length = reader.u32()? as usize;
+        let start = if length == 0 { None } else {
+            Some(Snapshot::from_bytes(reader.take(length)?)?)
+        };
+        let count = reader.u32()? as usize;
+        let frames = reader.take(count * 2)?.chunks(2).map(|c| (c[0], c[1])).collect();
+        if reader.position != content.len() {
+            return Err(invalid("unexpected data after the frames"));
+        }
+        Ok(Movie { rom_hash, start, frames })
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<(), EmulatorError> {
+        fs::write(file_name, self.to_bytes()).map_err(|e| EmulatorError::Io {
+            file_name: file_name.into(), message: e.to_string()
+        })
+    }
+
+    pub fn load(file_name: &str) -> Result<Movie, EmulatorError> {
+        let bytes = fs::read(file_name).map_err(|e| EmulatorError::Io {
+            file_name: file_name.into(), message: e.to_string()
+        })?;
+        Movie::from_bytes(&bytes)
+    }
+}
+
+/// Feeds the frames of a movie to the emulator, one per `run_one_frame()`.
+pub struct MoviePlayer {
+    movie: Movie,
+    frame: usize,
+}
+
+impl MoviePlayer {
+    pub fn new(movie: Movie) -> MoviePlayer {
+        MoviePlayer { movie, frame: 0 }
+    }
+
+    /// The index of the next frame to play.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.frames.len()
+    }
+
+    pub(crate) fn next_frame(&mut self) -> Option<(u8, u8)> {
+        let result = self.movie.frames.get(self.frame).copied();
+        if result.is_some() {
+            self.frame += 1;
+        }
+        result
+    }
+
+    /// Go back one frame, when the emulator rewinds.
+    pub(crate) fn previous_frame(&mut self) {
+        self.frame = self.frame.saturating_sub(1);
+    }
+}
@@ -1,33 +1,16 @@
 use std::collections::HashMap;
-use crate::memory::Memory;
 use lazy_static::lazy_static;
 
 pub struct Opcode {
-    opcode: u8,
     pub(crate) size: usize,
-    name: &'static str
+    pub(crate) name: &'static str
 }
 
 impl Opcode {
-    fn new(opcode: u8, size: usize, name: &'static str) -> Opcode {
-        Opcode { opcode, size, name }
+    fn new(size: usize, name: &'static str) -> Opcode {
+        Opcode { size, name }
     }
-
-    pub(crate) fn display1(&self) -> String {
-        let s = self.name;
-        format!("{:02x}         {:14}", self.opcode, s)
-    }
-
-    pub(crate) fn display2(&self, byte1: u8) -> String {
-        let s = format!("{} {:02x}", self.name, byte1);
-        format!("{:02x} {:02x}      {:14}", self.opcode, byte1, s)
-    }
-
-    pub(crate) fn display3(&self, byte1: u8, byte2: u8) -> String {
-        let s = format!("{} {:04x}", self.name, Memory::to_word(byte1, byte2));
-        format!("{:02x} {:02x} {:02x}   {:14}", self.opcode, byte1, byte2, s)
-    }
-}   
+}
 
 pub const NOP: u8 = 0x00;
 pub const LXI_B: u8 = 0x01;
@@ -147,6 +130,7 @@ pub const MOV_M_D: u8 = 0x72;
 pub const MOV_M_E: u8 = 0x73;
 pub const MOV_M_H: u8 = 0x74;
 pub const MOV_M_L: u8 = 0x75;
+pub const HLT: u8 = 0x76;
 pub const MOV_M_A: u8 = 0x77;
 
 pub const MOV_A_B: u8 = 0x78;
@@ -156,6 +140,7 @@ pub const MOV_A_E: u8 = 0x7b;
 pub const MOV_A_H: u8 = 0x7c;
 pub const MOV_A_L: u8 = 0x7d;
 pub const MOV_A_M: u8 = 0x7e;
+pub const MOV_A_A: u8 = 0x7f;
 
 pub const ADD_B: u8 = 0x80;
 pub const ADD_C: u8 = 0x81;
@@ -236,6 +221,7 @@ pub const JMP: u8 = 0xc3;
 pub const CNZ: u8 = 0xc4;
 pub const PUSH_B: u8 = 0xc5;
 pub const ADI: u8 = 0xc6;
+pub const RST_0: u8 = 0xc7;
 pub const RZ: u8 = 0xc8;
 pub const RET: u8 = 0xc9;
 pub const JZ: u8 = 0xca;
@@ -256,6 +242,7 @@ pub const JC: u8 = 0xda;
 pub const IN: u8 = 0xdb;
 pub const CC: u8 = 0xdc;
 pub const SBI: u8 = 0xde;
+pub const RST_3: u8 = 0xdf;
 pub const RPO: u8 = 0xe0;
 pub const POP_H: u8 = 0xe1;
 pub const JPO: u8 = 0xe2;
@@ -263,12 +250,14 @@ pub const XTHL: u8 = 0xe3;
 pub const CPO: u8 = 0xe4;
 pub const PUSH_H: u8 = 0xe5;
 pub const ANI: u8 = 0xe6;
+pub const RST_4: u8 = 0xe7;
 pub const RPE: u8 = 0xe8;
 pub const PCHL: u8 = 0xe9;
 pub const JPE: u8 = 0xea;
 pub const XCHG: u8 = 0xeb;
 pub const CPE: u8 = 0xec;
 pub const XRI: u8 = 0xee;
+pub const RST_5: u8 = 0xef;
 pub const RP: u8 = 0xf0;
 pub const POP_PSW: u8 = 0xf1;
 pub const JP: u8 = 0xf2;
@@ -276,6 +265,7 @@ pub const DI: u8 = 0xf3;
 pub const CP: u8 = 0xf4;
 pub const PUSH_PSW: u8 = 0xf5;
 pub const ORI: u8 = 0xf6;
+pub const RST_6: u8 = 0xf7;
 pub const RM: u8 = 0xf8;
 pub const SPHL: u8 = 0xf9;
 pub const JM: u8 = 0xfa;
@@ -284,66 +274,81 @@ pub const CM: u8 = 0xfc;
 pub const CPI: u8 = 0xfe;
 pub const RST_7: u8 = 0xff;
 
+// Undocumented encodings, which behave like the documented instruction of the same name
+pub const NOP_08: u8 = 0x08;
+pub const NOP_10: u8 = 0x10;
+pub const NOP_18: u8 = 0x18;
+pub const NOP_20: u8 = 0x20;
+pub const NOP_28: u8 = 0x28;
+pub const NOP_30: u8 = 0x30;
+pub const NOP_38: u8 = 0x38;
+pub const JMP_CB: u8 = 0xcb;
+pub const RET_D9: u8 = 0xd9;
+pub const CALL_DD: u8 = 0xdd;
+pub const CALL_ED: u8 = 0xed;
+pub const CALL_FD: u8 = 0xfd;
+
 fn init_opcodes() -> HashMap<u8, Opcode> {
-    // Opcode, size, disassembly name, cycles (appendix B of the ref manual)
+    // Opcode, size, Intel syntax (d8/d16: immediate, a16: address, p8: port)
     let ops: Vec<(u8, usize, &str)> = vec![
         (NOP, 1, "NOP"),
-        (LXI_B, 3, "LD BC,"),
-        (STAX_B, 1, "STA (BC)"),
-        (INX_B, 1, "INC (BC)"),
+        (LXI_B, 3, "LXI B,d16"),
+        (STAX_B, 1, "STAX B"),
+        (INX_B, 1, "INX B"),
         (INR_B, 1, "INR B"),
-        (DCR_B, 1, "DEC B"),
-        (MVI_B, 2, "LD B,"),
+        (DCR_B, 1, "DCR B"),
+        (MVI_B, 2, "MVI B,d8"),
         (RLC, 1, "RLC"),
-        (DAD_B, 1, "ADD HL,BC"),
-        (LDAX_B, 1, "LD A,(BC)"),
-        (DCX_B, 1, "DEC (BC)"),
+        (DAD_B, 1, "DAD B"),
+        (LDAX_B, 1, "LDAX B"),
+        (DCX_B, 1, "DCX B"),
         (INR_C, 1, "INR C"),
-        (DCR_C, 1, "DEC C"),
-        (MVI_C, 2, "LD C,"),
+        (DCR_C, 1, "DCR C"),
+        (MVI_C, 2, "MVI C,d8"),
         (RRC, 1, "RRC"),
-        (LXI_D, 3, "LD DE,"),
-        (STAX_D, 1, "STA (DE)"),
-        (INX_D, 1, "INC (DE)"),
+        (LXI_D, 3, "LXI D,d16"),
+        (STAX_D, 1, "STAX D"),
+        (INX_D, 1, "INX D"),
         (INR_D, 1, "INR D"),
-        (DCR_D, 1, "DEC D"),
-        (MVI_D, 2, "LD D,"),
+        (DCR_D, 1, "DCR D"),
+        (MVI_D, 2, "MVI D,d8"),
         (RAL, 1, "RAL"),
-        (DAD_D, 1, "ADD HL,DE"),
-        (LDAX_D, 1, "LD A,(DE)"),
-        (DCX_D, 1, "DC (DE)"),
+        (DAD_D, 1, "DAD D"),
+        (LDAX_D, 1, "LDAX D"),
+        (DCX_D, 1, "DCX D"),
         (INR_E, 1, "INR E"),
-        (DCR_E, 1, "DEC E"),
-        (MVI_E, 2, "LD E,"),
+        (DCR_E, 1, "DCR E"),
+        (MVI_E, 2, "MVI E,d8"),
         (RAR, 1, "RAR"),
-        (LXI_H, 3, "LD HL,"),
-        (SHLD, 3, "SHLD"),
-        (INX_H, 1, "INC (HL)"),
+        (LXI_H, 3, "LXI H,d16"),
+        (SHLD, 3, "SHLD a16"),
+        (INX_H, 1, "INX H"),
         (INR_H, 1, "INR H"),
-        (DCR_H, 1, "DEC HL"),
-        (MVI_H, 2, "MOV H,"),
+        (DCR_H, 1, "DCR H"),
+        (MVI_H, 2, "MVI H,d8"),
         (DAA, 1, "DAA"),
-        (DAD_H, 1, "ADD HL,HL"),
-        (LHLD, 3, "LHLD"),
-        (DCX_H, 1, "DEC (HL)"),
+        (DAD_H, 1, "DAD H"),
+        (LHLD, 3, "LHLD a16"),
+        (DCX_H, 1, "DCX H"),
         (INR_L, 1, "INR L"),
-        (DCR_L, 1, "DEC L"),
-        (MVI_L, 2, "LD L,"),
+        (DCR_L, 1, "DCR L"),
+        (MVI_L, 2, "MVI L,d8"),
         (CMA, 1, "CMA"),
-        (LXI_SP, 3, "LD SP,"),
-        (STA, 3, "STA"),
-        (INX_SP, 1, "INC (SP)"),
-        (INR_M, 1, "INC (HL)"),
-        (DCR_M, 1, "DEC (HL)"),
-        (MVI_M, 2, "MV (HL),"),
+        (LXI_SP, 3, "LXI SP,d16"),
+        (STA, 3, "STA a16"),
+        (INX_SP, 1, "INX SP"),
+        (INR_M, 1, "INR M"),
+        (DCR_M, 1, "DCR M"),
+        (MVI_M, 2, "MVI M,d8"),
         (STC, 1, "STC"),
-        (DAD_SP, 1, "ADD HL,SP"),
-        (0x3a, 3, "LDA"),
-        (DCX_SP, 1, "DEC (SP)"),
+        (DAD_SP, 1, "DAD SP"),
+        (LDA, 3, "LDA a16"),
+        (DCX_SP, 1, "DCX SP"),
         (INR_A, 1, "INR A"),
-        (DCR_A, 1, "DEC A"),
-        (MVI_A, 2, "LD A,"),
+        (DCR_A, 1, "DCR A"),
+        (MVI_A, 2, "MVI A,d8"),
         (CMC, 1, "CMC"),
+        (MOV_B_B, 1, "MOV B,B"),
         (MOV_B_C, 1, "MOV B,C"),
         (MOV_B_D, 1, "MOV B,D"),
         (MOV_B_E, 1, "MOV B,E"),
@@ -391,20 +396,22 @@ fn init_opcodes() -> HashMap<u8, Opcode> {
         (MOV_L_L, 1, "MOV L,L"),
         (MOV_L_M, 1, "MOV L,M"),
         (MOV_L_A, 1, "MOV L,A"),
-        (MOV_M_B, 1, "LD (HL),B"),
-        (MOV_M_C, 1, "LD (HL),C"),
-        (MOV_M_D, 1, "LD (HL),D"),
-        (MOV_M_E, 1, "LD (HL),E"),
-        (MOV_M_H, 1, "LD (HL),H"),
-        (MOV_M_L, 1, "LD (HL),L"),
-        (MOV_M_A, 1, "LD (HL),A"),
+        (MOV_M_B, 1, "MOV M,B"),
+        (MOV_M_C, 1, "MOV M,C"),
+        (MOV_M_D, 1, "MOV M,D"),
+        (MOV_M_E, 1, "MOV M,E"),
+        (MOV_M_H, 1, "MOV M,H"),
+        (MOV_M_L, 1, "MOV M,L"),
+        (HLT, 1, "HLT"),
+        (MOV_M_A, 1, "MOV M,A"),
         (MOV_A_B, 1, "MOV A,B"),
         (MOV_A_C, 1, "MOV A,C"),
         (MOV_A_D, 1, "MOV A,D"),
         (MOV_A_E, 1, "MOV A,E"),
         (MOV_A_H, 1, "MOV A,H"),
         (MOV_A_L, 1, "MOV A,L"),
-        (MOV_A_M, 1, "MOV A,(HL)"),
+        (MOV_A_M, 1, "MOV A,M"),
+        (MOV_A_A, 1, "MOV A,A"),
         (ADD_B, 1, "ADD B"),
         (ADD_C, 1, "ADD C"),
         (ADD_D, 1, "ADD D"),
@@ -437,14 +444,14 @@ fn init_opcodes() -> HashMap<u8, Opcode> {
         (SBB_L, 1, "SBB L"),
         (SBB_M, 1, "SBB M"),
         (SBB_A, 1, "SBB A"),
-        (ANA_B, 1, "AND B"),
-        (ANA_C, 1, "AND C"),
-        (ANA_D, 1, "AND D"),
-        (ANA_E, 1, "AND E"),
-        (ANA_H, 1, "AND H"),
-        (ANA_L, 1, "AND L"),
-        (ANA_M, 1, "AND M"),
-        (ANA_A, 1, "AND A"),
+        (ANA_B, 1, "ANA B"),
+        (ANA_C, 1, "ANA C"),
+        (ANA_D, 1, "ANA D"),
+        (ANA_E, 1, "ANA E"),
+        (ANA_H, 1, "ANA H"),
+        (ANA_L, 1, "ANA L"),
+        (ANA_M, 1, "ANA M"),
+        (ANA_A, 1, "ANA A"),
         (ORA_B, 1, "ORA B"),
         (ORA_C, 1, "ORA C"),
         (ORA_D, 1, "ORA D"),
@@ -470,66 +477,83 @@ fn init_opcodes() -> HashMap<u8, Opcode> {
         (XRA_M, 1, "XRA M"),
         (XRA_A, 1, "XRA A"),
         (RNZ, 1, "RNZ"),
-        (POP_B, 1, "POP BC"),
-        (JNZ, 3, "JNZ"),
-        (JMP, 3, "JMP"),
-        (CNZ, 3, "CNZ"),
-        (PUSH_B, 1, "PUSH BC"),
-        (ADI, 2, "ADI"),
+        (POP_B, 1, "POP B"),
+        (JNZ, 3, "JNZ a16"),
+        (JMP, 3, "JMP a16"),
+        (CNZ, 3, "CNZ a16"),
+        (PUSH_B, 1, "PUSH B"),
+        (ADI, 2, "ADI d8"),
         (RZ, 1, "RZ"),
         (RET, 1, "RET"),
-        (JZ, 3, "JZ"),
-        (CZ, 3, "CZ"),
-        (CALL, 3, "CALL"),
-        (ACI, 2, "ACI"),
+        (JZ, 3, "JZ a16"),
+        (CZ, 3, "CZ a16"),
+        (CALL, 3, "CALL a16"),
+        (ACI, 2, "ACI d8"),
         (RNC, 1, "RNC"),
-        (POP_D, 1, "POP DE"),
-        (JNC, 3, "JNC"),
-        (OUT, 2, "OUT"),
-        (CNC, 3, "CNC"),
-        (PUSH_D, 1, "PUSH DE"),
-        (SUI, 2, "SUI"),
+        (POP_D, 1, "POP D"),
+        (JNC, 3, "JNC a16"),
+        (OUT, 2, "OUT p8"),
+        (CNC, 3, "CNC a16"),
+        (PUSH_D, 1, "PUSH D"),
+        (SUI, 2, "SUI d8"),
         (RC, 1, "RC"),
-        (JC, 3, "JC"),
-        (IN, 2, "IN"),
-        (CC, 3, "CC"),
-        (SBI, 2, "SBI"),
+        (JC, 3, "JC a16"),
+        (IN, 2, "IN p8"),
+        (CC, 3, "CC a16"),
+        (SBI, 2, "SBI d8"),
         (RPO, 1, "RPO"),
-        (POP_H, 1, "POP HL"),
-        (JPO, 3, "JPO"),
+        (POP_H, 1, "POP H"),
+        (JPO, 3, "JPO a16"),
         (XTHL, 1, "XTHL"),
-        (CPO, 3, "CPO"),
-        (PUSH_H, 1, "PUSH HL"),
-        (ANI, 2, "ANI"),
+        (CPO, 3, "CPO a16"),
+        (PUSH_H, 1, "PUSH H"),
+        (ANI, 2, "ANI d8"),
         (RPE, 1, "RPE"),
         (PCHL, 1, "PCHL"),
-        (JPE, 3, "JPE"),
-        (XCHG, 1, "EX DE,HL"),
-        (CPE, 3, "CPE"),
-        (XRI, 2, "XRI"),
+        (JPE, 3, "JPE a16"),
+        (XCHG, 1, "XCHG"),
+        (CPE, 3, "CPE a16"),
+        (XRI, 2, "XRI d8"),
         (RP, 1, "RP"),
         (POP_PSW, 1, "POP PSW"),
-        (JP, 3, "JP"),
-        (CP, 3, "CP"),
+        (JP, 3, "JP a16"),
+        (CP, 3, "CP a16"),
         (PUSH_PSW, 1, "PUSH PSW"),
-        (ORI, 2, "ORI"),
+        (ORI, 2, "ORI d8"),
         (RM, 1, "RM"),
         (SPHL, 1, "SPHL"),
-        (JM, 3, "JM"),
-        (CM, 3, "CM"),
+        (JM, 3, "JM a16"),
+        (CM, 3, "CM a16"),
         (EI, 1, "EI"),
         (DI, 1, "DI"),
-        (CPI, 2, "CPI"),
-        (RST_1, 2, "RST 1"),
-        (RST_2, 2, "RST 2"),
-        (RST_7, 2, "RST 7"),
+        (CPI, 2, "CPI d8"),
+        (RST_0, 1, "RST 0"),
+        (RST_1, 1, "RST 1"),
+        (RST_2, 1, "RST 2"),
+        (RST_3, 1, "RST 3"),
+        (RST_4, 1, "RST 4"),
+        (RST_5, 1, "RST 5"),
+        (RST_6, 1, "RST 6"),
+        (RST_7, 1, "RST 7"),
+        (NOP_08, 1, "NOP"),
+        (NOP_10, 1, "NOP"),
+        (NOP_18, 1, "NOP"),
+        (NOP_20, 1, "NOP"),
+        (NOP_28, 1, "NOP"),
+        (NOP_30, 1, "NOP"),
+        (NOP_38, 1, "NOP"),
+        (JMP_CB, 3, "JMP a16"),
+        (RET_D9, 1, "RET"),
+        (CALL_DD, 3, "CALL a16"),
+        (CALL_ED, 3, "CALL a16"),
+        (CALL_FD, 3, "CALL a16"),
     ];
     let mut result: HashMap<u8, Opcode> = HashMap::new();
     for op in ops {
-        if result.get(&op.0).is_some() {
+        if result.contains_key(&op.0) {
             panic!("REPEATED OPCODE {:02x}", op.0);
         }
-        result.insert(op.0, Opcode::new(op.0, op.1, op.2));
+        result.insert(op.0, Opcode::new(op.1, op.2));
     }
     result
 }
@@ -0,0 +1,208 @@
+//! An opt-in profiler, enabled with `Emulator::enable_profiler()`: where the cycles go,
+//! per address and per subroutine.
+//!
+//! The subroutines are the targets of the CALLs, RSTs and interrupts. A call is over when
+//! SP goes back above the return address it pushed, which also covers the code that drops
+//! its return address and resets SP. The exclusive cycles of a subroutine are the ones
+//! of its own instructions, the inclusive ones also count the subroutines it calls (once
+//! for recursive calls). The cycles outside of any call go to `root`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RoutineProfile {
+    pub address: u16,
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Clone)]
+struct Frame {
+    target: u16,
+    /// SP once the return address is pushed
+    sp: u16,
+    /// The total cycles when it was called
+    start: u64,
+    exclusive: u64,
+}
+
+#[derive(Clone)]
+pub struct Profiler {
+    /// Executions and cycles of each address
+    addresses: Vec<(u64, u64)>,
+    routines: HashMap<u16, RoutineProfile>,
+    stack: Vec<Frame>,
+    /// Exclusive cycles by stack of calls, for the folded stacks
+    stacks: HashMap<Vec<u16>, u64>,
+    /// Cycles outside of any call
+    root: u64,
+    cycles: u64,
+    instructions: u64,
+    /// Cycles spent halted, waiting for an interrupt
+    halted: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            addresses: vec![(0, 0); 0x10000],
+            routines: HashMap::new(),
+            stack: Vec::new(),
+            stacks: HashMap::new(),
+            root: 0,
+            cycles: 0,
+            instructions: 0,
+            halted: 0,
+        }
+    }
+
+    /// The instruction at `pc` ran in `cycles`, moving SP from `sp` to `new_sp` and PC
+    /// to `new_pc`.
+    pub(crate) fn record(&mut self, pc: u16, cycles: u8, is_call: bool, sp: u16, new_sp: u16, new_pc: u16) {
+        let cycles = cycles as u64;
+        self.cycles += cycles;
+        self.instructions += 1;
+        let address = &mut self.addresses[pc as usize];
+        address.0 += 1;
+        address.1 += cycles;
+        match self.stack.last_mut() {
+            Some(frame) => frame.exclusive += cycles,
+            None => self.root += cycles,
+        }
+        self.pop_returned(new_sp);
+        if is_call && new_sp == sp.wrapping_sub(2) {
+            self.push(new_pc, new_sp);
+        }
+    }
+
+    pub(crate) fn record_halted(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.halted += cycles as u64;
+    }
+
+    /// An interrupt pushed PC and jumped to `vector`.
+    pub(crate) fn record_interrupt(&mut self, vector: u16, new_sp: u16) {
+        self.pop_returned(new_sp);
+        self.push(vector, new_sp);
+    }
+
+    fn push(&mut self, target: u16, sp: u16) {
+        self.stack.push(Frame { target, sp, start: self.cycles, exclusive: 0 });
+        let routine = self.routines.entry(target).or_insert(RoutineProfile { address: target, ..Default::default() });
+        routine.calls += 1;
+    }
+
+    /// Pop the calls whose return address is below `sp`.
+    fn pop_returned(&mut self, sp: u16) {
+        while self.stack.last().is_some_and(|frame| frame.sp < sp) {
+            self.pop();
+        }
+    }
+
+    fn pop(&mut self) {
+        let path: Vec<u16> = self.stack.iter().map(|frame| frame.target).collect();
+        let frame = self.stack.pop().unwrap();
+        *self.stacks.entry(path).or_default() += frame.exclusive;
+        let recursive = self.stack.iter().any(|f| f.target == frame.target);
+        let routine = self.routines.get_mut(&frame.target).unwrap();
+        routine.exclusive += frame.exclusive;
+        if ! recursive {
+            routine.inclusive += self.cycles - frame.start;
+        }
+    }
+
+    /// The profile with the calls in progress counted as if they returned now.
+    fn finished(&self) -> Profiler {
+        let mut result = self.clone();
+        while ! result.stack.is_empty() {
+            result.pop();
+        }
+        result
+    }
+
+    /// Cycles run since the profiler was enabled
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// The executed addresses with their number of executions and cycles.
+    pub fn addresses(&self) -> impl Iterator<Item = (u16, u64, u64)> + '_ {
+        self.addresses.iter().enumerate()
+            .filter(|(_, (count, _))| *count > 0)
+            .map(|(address, (count, cycles))| (address as u16, *count, *cycles))
+    }
+
+    /// The subroutines, by decreasing inclusive cycles.
+    pub fn routines(&self) -> Vec<RoutineProfile> {
+        self.finished().sorted_routines()
+    }
+
+    fn sorted_routines(&self) -> Vec<RoutineProfile> {
+        let mut result: Vec<RoutineProfile> = self.routines.values().copied().collect();
+        result.sort_by_key(|routine| (u64::MAX - routine.inclusive, routine.address));
+        result
+    }
+
+    fn name(names: &dyn Fn(u16) -> Option<String>, address: u16) -> String {
+        names(address).unwrap_or_else(|| format!("sub_{:04x}", address))
+    }
+
+    /// A text report: the subroutines by inclusive cycles, then the `limit` addresses
+    /// that took the most cycles. `names` gives the labels of the addresses that have one.
+    pub fn report(&self, names: &dyn Fn(u16) -> Option<String>, limit: usize) -> String {
+        let finished = self.finished();
+        let total = self.cycles.max(1) as f64;
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total;
+        let mut result = format!("{} cycles, {} instructions, {} cycles halted\n\n",
+            self.cycles, self.instructions, self.halted);
+        let _ = writeln!(result, "{:>10} {:>12} {:>6} {:>12} {:>6}  Subroutine",
+            "Calls", "Inclusive", "%", "Exclusive", "%");
+        let _ = writeln!(result, "{:>10} {:>12} {:>6} {:>12} {:>6.2}  root", "", "", "",
+            finished.root, percent(finished.root));
+        for routine in finished.sorted_routines() {
+            let _ = writeln!(result, "{:>10} {:>12} {:>6.2} {:>12} {:>6.2}  {:04x} {}",
+                routine.calls, routine.inclusive, percent(routine.inclusive),
+                routine.exclusive, percent(routine.exclusive),
+                routine.address, Profiler::name(names, routine.address));
+        }
+
+        let mut addresses: Vec<(u16, u64, u64)> = self.addresses().collect();
+        addresses.sort_by_key(|(address, _, cycles)| (u64::MAX - cycles, *address));
+        let _ = writeln!(result, "\n{:>10} {:>12} {:>6}  Address", "Count", "Cycles", "%");
+        for (address, count, cycles) in addresses.into_iter().take(limit) {
+            let _ = writeln!(result, "{:>10} {:>12} {:>6.2}  {:04x} {}", count, cycles, percent(cycles),
+                address, names(address).unwrap_or_default());
+        }
+        result
+    }
+
+    /// The exclusive cycles of each stack of calls, one "root;caller;callee cycles" per
+    /// line, the input format of flamegraph.pl and inferno.
+    pub fn folded(&self, names: &dyn Fn(u16) -> Option<String>) -> String {
+        let finished = self.finished();
+        let mut lines: Vec<String> = finished.stacks.iter()
+            .filter(|(_, cycles)| **cycles > 0)
+            .map(|(path, cycles)| {
+                let frames: Vec<String> = path.iter().map(|address| Profiler::name(names, *address)).collect();
+                format!("root;{} {}", frames.join(";"), cycles)
+            })
+            .collect();
+        if finished.root > 0 {
+            lines.push(format!("root {}", finished.root));
+        }
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
@@ -0,0 +1,110 @@
+//! Gameplay recordings: every emulated frame to a Y4M video and the mixed sounds to a WAV
+//! file. Both are paced by the emulated frames, not by the clock: each frame adds one
+//! video frame and exactly 1/60 second of audio, so they stay in sync however fast the
+//! emulator runs. `ffmpeg -i game.y4m -i game.wav game.mp4` turns them into a video.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use crate::emulator::{HEIGHT, WIDTH};
+use crate::emulator_state::SharedState;
+use crate::error::EmulatorError;
+use crate::screen;
+use crate::sound::Mixer;
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const FRAMES_PER_SECOND: u32 = 60;
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / FRAMES_PER_SECOND) as usize;
+const WAV_HEADER_SIZE: u32 = 44;
+
+pub struct AvRecorder {
+    y4m_file_name: String,
+    wav_file_name: String,
+    y4m: BufWriter<File>,
+    wav: BufWriter<File>,
+    mixer: Mixer,
+    frames: u64,
+}
+
+fn io_error(file_name: &str) -> impl Fn(std::io::Error) -> EmulatorError + '_ {
+    move |e| EmulatorError::Io { file_name: file_name.into(), message: e.to_string() }
+}
+
+/// BT.601, limited range
+fn to_yuv(color: u32) -> (u8, u8, u8) {
+    let (r, g, b) = ((color >> 16 & 0xff) as f64, (color >> 8 & 0xff) as f64, (color & 0xff) as f64);
+    let y = 16.0 + (65.481 * r + 128.553 * g + 24.966 * b) / 255.0;
+    let u = 128.0 + (-37.797 * r - 74.203 * g + 112.0 * b) / 255.0;
+    let v = 128.0 + (112.0 * r - 93.786 * g - 18.214 * b) / 255.0;
+    (y.round() as u8, u.round() as u8, v.round() as u8)
+}
+
+impl AvRecorder {
+    /// `sounds_directory` contains the samples of the sounds, see `sound::SOUNDS`.
+    pub fn create(y4m_file_name: &str, wav_file_name: &str, sounds_directory: &str)
+            -> Result<AvRecorder, EmulatorError> {
+        let mixer = Mixer::load(sounds_directory, SAMPLE_RATE)?;
+        let mut y4m = BufWriter::new(File::create(y4m_file_name).map_err(io_error(y4m_file_name))?);
+        writeln!(y4m, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", WIDTH, HEIGHT, FRAMES_PER_SECOND)
+            .map_err(io_error(y4m_file_name))?;
+        let mut wav = BufWriter::new(File::create(wav_file_name).map_err(io_error(wav_file_name))?);
+        // The sizes are filled in by finish()
+        wav.write_all(&wav_header(0)).map_err(io_error(wav_file_name))?;
+        Ok(AvRecorder {
+            y4m_file_name: y4m_file_name.into(),
+            wav_file_name: wav_file_name.into(),
+            y4m, wav, mixer, frames: 0,
+        })
+    }
+
+    /// Record the frame that was just emulated: the screen and the sounds it started.
+    pub fn add_frame(&mut self, shared_state: &SharedState) -> Result<(), EmulatorError> {
+        let pixels = screen::render(&shared_state.graphic_memory());
+        let yuv: Vec<(u8, u8, u8)> = pixels.iter().map(|c| to_yuv(*c)).collect();
+        let mut frame = Vec::with_capacity(6 + yuv.len() * 3);
+        frame.extend_from_slice(b"FRAME\n");
+        frame.extend(yuv.iter().map(|p| p.0));
+        frame.extend(yuv.iter().map(|p| p.1));
+        frame.extend(yuv.iter().map(|p| p.2));
+        self.y4m.write_all(&frame).map_err(io_error(&self.y4m_file_name))?;
+
+        self.mixer.update(shared_state.get_out(3)?, shared_state.get_out(5)?);
+        let samples: Vec<u8> = self.mixer.mix(SAMPLES_PER_FRAME).iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        self.wav.write_all(&samples).map_err(io_error(&self.wav_file_name))?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Number of frames recorded so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn finish(mut self) -> Result<(), EmulatorError> {
+        self.y4m.flush().map_err(io_error(&self.y4m_file_name))?;
+        let data_size = (self.frames as usize * SAMPLES_PER_FRAME * 2) as u32;
+        let error = io_error(&self.wav_file_name);
+        self.wav.seek(SeekFrom::Start(0)).map_err(&error)?;
+        self.wav.write_all(&wav_header(data_size)).map_err(&error)?;
+        self.wav.flush().map_err(&error)
+    }
+}
+
+/// The header of a 16 bit mono WAV file.
+fn wav_header(data_size: u32) -> Vec<u8> {
+    let mut result = Vec::with_capacity(WAV_HEADER_SIZE as usize);
+    result.extend_from_slice(b"RIFF");
+    result.extend_from_slice(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes());
+    result.extend_from_slice(b"WAVEfmt ");
+    result.extend_from_slice(&16u32.to_le_bytes());
+    result.extend_from_slice(&1u16.to_le_bytes());     // PCM
+    result.extend_from_slice(&1u16.to_le_bytes());     // mono
+    result.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    result.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    result.extend_from_slice(&2u16.to_le_bytes());     // bytes per sample
+    result.extend_from_slice(&16u16.to_le_bytes());    // bits per sample
+    result.extend_from_slice(b"data");
+    result.extend_from_slice(&data_size.to_le_bytes());
+    result
+}
@@ -0,0 +1,141 @@
+//! Rewind: a ring buffer of the states of the machine at the end of each frame.
+//!
+//! Only the most recent state is kept in full. Each older frame is stored as the
+//! difference needed to go back one frame from the next one: its registers and ports, and
+//! the runs of memory bytes that changed. Space Invaders only touches a few KB of RAM per
+//! frame, so a frame typically costs a few KB instead of 64K.
+
+use std::collections::VecDeque;
+use crate::snapshot::Snapshot;
+
+/// Two runs of changed bytes closer than this are stored as one run.
+const MAX_GAP: usize = 4;
+/// What a run costs besides its bytes: its address and the `Vec`.
+const RUN_OVERHEAD: usize = 2 + std::mem::size_of::<Vec<u8>>();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RewindConfig {
+    /// How many frames can be rewound
+    pub depth: usize,
+    /// Maximum number of bytes used by the older frames. The oldest ones are dropped
+    /// once it's reached, even if `depth` isn't.
+    pub memory_budget: usize,
+}
+
+impl Default for RewindConfig {
+    /// Ten seconds, in at most 16 MB.
+    fn default() -> Self {
+        RewindConfig { depth: 600, memory_budget: 16 * 1024 * 1024 }
+    }
+}
+
+/// The previous frame, relative to the one that follows it.
+struct Delta {
+    /// Everything but the memory, which is left empty
+    state: Snapshot,
+    runs: Vec<(u16, Vec<u8>)>,
+}
+
+impl Delta {
+    /// What must be applied to `to` to get `from`.
+    fn new(from: &Snapshot, to: &Snapshot) -> Delta {
+        let mut runs: Vec<(u16, Vec<u8>)> = Vec::new();
+        let mut address = 0;
+        while address < from.memory.len() {
+            if from.memory[address] == to.memory[address] {
+                address += 1;
+                continue;
+            }
+            let start = address;
+            let mut end = address + 1;
+            while end < from.memory.len() && from.memory[end..].iter().zip(&to.memory[end..])
+                .take(MAX_GAP).any(|(a, b)| a != b) {
+                end += 1;
+            }
+            runs.push((start as u16, from.memory[start..end].to_vec()));
+            address = end;
+        }
+        Delta {
+            state: Snapshot { memory: Vec::new(), .. from.clone() },
+            runs,
+        }
+    }
+
+    fn apply(self, to: &Snapshot) -> Snapshot {
+        let mut memory = to.memory.clone();
+        for (address, bytes) in self.runs {
+            let address = address as usize;
+            memory[address..address + bytes.len()].copy_from_slice(&bytes);
+        }
+        Snapshot { memory, .. self.state }
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Delta>() + self.state.rom_set.len() +
+            self.runs.iter().map(|(_, bytes)| bytes.len() + RUN_OVERHEAD).sum::<usize>()
+    }
+}
+
+pub struct RewindBuffer {
+    config: RewindConfig,
+    latest: Option<Snapshot>,
+    /// Oldest first
+    deltas: VecDeque<Delta>,
+    size: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(config: RewindConfig) -> RewindBuffer {
+        RewindBuffer { config, latest: None, deltas: VecDeque::new(), size: 0 }
+    }
+
+    pub fn config(&self) -> RewindConfig {
+        self.config
+    }
+
+    /// How many frames can be rewound.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Bytes used by the older frames.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if let Some(latest) = self.latest.take() {
+            if self.config.depth > 0 {
+                let delta = Delta::new(&latest, &snapshot);
+                self.size += delta.size();
+                self.deltas.push_back(delta);
+            }
+        }
+        self.latest = Some(snapshot);
+        while self.deltas.len() > self.config.depth || self.size > self.config.memory_budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.size -= delta.size(),
+                None => break,
+            }
+        }
+    }
+
+    /// Go back one frame: forget the latest state and return the one before it.
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        let delta = self.deltas.pop_back()?;
+        self.size -= delta.size();
+        let previous = delta.apply(self.latest.as_ref().unwrap());
+        self.latest = Some(previous.clone());
+        Some(previous)
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.size = 0;
+    }
+}
@@ -0,0 +1,153 @@
+//! The screen of the cabinet. The video memory (0x2400-0x3fff) holds 224 columns of 256
+//! pixels, one bit per pixel, and the monitor is rotated 90 degrees counterclockwise, so
+//! the screen seen by the player is 224 pixels wide and 256 pixels high. The cabinet had
+//! strips of coloured cellophane on the screen, which `render()` reproduces.
+//!
+//! `render()` is what the window displays, and the captures (PNG screenshots and animated
+//! GIFs) are made from it so they look exactly like the screen.
+
+use std::fs::File;
+use std::io::BufWriter;
+use crate::emulator::{HEIGHT, WIDTH};
+use crate::error::EmulatorError;
+use crate::memory::GRAPHIC_MEMORY_SIZE;
+
+pub const RED: u32 = 0x00ff0000;
+pub const GREEN: u32 = 0x0000ff00;
+pub const WHITE: u32 = 0xffffff;
+pub const BLACK: u32 = 0;
+
+/// The colours of the screen, in the order of the GIF palette.
+const PALETTE: [u32; 4] = [BLACK, WHITE, RED, GREEN];
+
+/// The screen as the player sees it, row by row from the top left corner, one 0RGB
+/// pixel per `u32`.
+pub fn render(graphic_memory: &[u8; GRAPHIC_MEMORY_SIZE]) -> Vec<u32> {
+    let width = WIDTH as usize;
+    let height = HEIGHT as usize;
+    let mut result = vec![BLACK; width * height];
+    let mut i: usize = 0;
+    for x in 0..width {
+        for iy in (0..height).step_by(8) {
+            let mut byte = graphic_memory[i];
+            i += 1;
+            for b in 0..8 {
+                let y = height - (iy + b) - 1;
+                let color = if byte & 1 == 0 { BLACK }
+                    else if iy > 200 && iy < 220 { RED }
+                    else if iy < 80 { GREEN }
+                    else { WHITE };
+                byte >>= 1;
+                result[y * width + x] = color;
+            }
+        }
+    }
+    result
+}
+
+/// Which pixels are lit, in the same order as `render()`.
+pub fn pixels(graphic_memory: &[u8; GRAPHIC_MEMORY_SIZE]) -> Vec<bool> {
+    render(graphic_memory).into_iter().map(|color| color != BLACK).collect()
+}
+
+/// The screen as a binary PBM image.
+pub fn to_pbm(graphic_memory: &[u8; GRAPHIC_MEMORY_SIZE]) -> Vec<u8> {
+    let width = WIDTH as usize;
+    let mut result = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    for row in pixels(graphic_memory).chunks(width) {
+        for eight in row.chunks(8) {
+            let byte = eight.iter().enumerate()
+                .fold(0u8, |acc, (i, on)| if *on { acc | (0x80 >> i) } else { acc });
+            result.push(byte);
+        }
+    }
+    result
+}
+
+/// The screen as a PNG image.
+pub fn to_png(graphic_memory: &[u8; GRAPHIC_MEMORY_SIZE]) -> Vec<u8> {
+    let mut result = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut result, WIDTH as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let rgb: Vec<u8> = render(graphic_memory).iter()
+            .flat_map(|color| [(color >> 16) as u8, (color >> 8) as u8, *color as u8])
+            .collect();
+        // Writing to a Vec can't fail
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&rgb).unwrap();
+    }
+    result
+}
+
+pub fn save_png(file_name: &str, graphic_memory: &[u8; GRAPHIC_MEMORY_SIZE]) -> Result<(), EmulatorError> {
+    std::fs::write(file_name, to_png(graphic_memory)).map_err(|e| EmulatorError::Io {
+        file_name: file_name.into(), message: e.to_string()
+    })
+}
+
+/// Records frames to an animated GIF, at 60 frames per second. GIF delays are in
+/// hundredths of a second, so they alternate between 1 and 2 to keep the exact pace,
+/// and identical consecutive frames are merged into a longer one.
+pub struct GifRecorder {
+    file_name: String,
+    encoder: gif::Encoder<BufWriter<File>>,
+    /// The last frame, not written yet since the next ones might be identical
+    pending: Option<Vec<u8>>,
+    /// Number of frames added, and written
+    frames: u64,
+    written_frames: u64,
+}
+
+impl GifRecorder {
+    pub fn create(file_name: &str) -> Result<GifRecorder, EmulatorError> {
+        let error = |message: String| EmulatorError::Io { file_name: file_name.into(), message };
+        let file = File::create(file_name).map_err(|e| error(e.to_string()))?;
+        let palette: Vec<u8> = PALETTE.iter()
+            .flat_map(|color| [(color >> 16) as u8, (color >> 8) as u8, *color as u8])
+            .collect();
+        let mut encoder = gif::Encoder::new(BufWriter::new(file), WIDTH, HEIGHT, &palette)
+            .map_err(|e| error(e.to_string()))?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| error(e.to_string()))?;
+        Ok(GifRecorder {
+            file_name: file_name.into(), encoder, pending: None, frames: 0, written_frames: 0
+        })
+    }
+
+    pub fn add_frame(&mut self, graphic_memory: &[u8; GRAPHIC_MEMORY_SIZE]) -> Result<(), EmulatorError> {
+        let indices: Vec<u8> = render(graphic_memory).iter()
+            .map(|color| PALETTE.iter().position(|c| c == color).unwrap() as u8)
+            .collect();
+        if self.pending.as_ref() != Some(&indices) {
+            self.flush()?;
+            self.pending = Some(indices);
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Number of frames added so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Write the last frame and close the file.
+    pub fn finish(mut self) -> Result<(), EmulatorError> {
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), EmulatorError> {
+        if let Some(indices) = self.pending.take() {
+            let hundredths = |frames: u64| (frames * 100 + 30) / 60;
+            let delay = hundredths(self.frames) - hundredths(self.written_frames);
+            let mut frame = gif::Frame::from_indexed_pixels(WIDTH, HEIGHT, indices, None);
+            frame.delay = delay as u16;
+            self.encoder.write_frame(&frame).map_err(|e| EmulatorError::Io {
+                file_name: self.file_name.clone(), message: e.to_string()
+            })?;
+            self.written_frames = self.frames;
+        }
+        Ok(())
+    }
+}
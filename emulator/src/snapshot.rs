@@ -0,0 +1,171 @@
+//! Complete machine snapshots: CPU registers, flags, the 64K of memory, the shift register
+//! and the I/O ports. `Emulator::snapshot()` and `Emulator::restore()` capture and restore
+//! them, `to_bytes()`/`from_bytes()` convert them to and from the file format:
+//!
+//! | Size  | Content                                                            |
+//! |-------|--------------------------------------------------------------------|
+//! | 8     | Magic: `8080SNAP`                                                  |
+//! | 2     | Format version (little endian)                                     |
+//! | 4 + n | Name of the ROM set: length, then the bytes                        |
+//! | 8     | A, B, C, D, E, H, L, flags (low byte of PSW)                       |
+//! | 2 + 2 | PC, SP                                                             |
+//! | 1 + 1 | Interrupt enable, halted                                           |
+//! | 2 + 1 | Shift register, shift register offset                              |
+//! | 4     | Ports: in 1, in 2, out 3, out 5                                    |
+//! | 65536 | Memory                                                             |
+//! | 4     | CRC-32 of all the previous bytes                                   |
+//!
+//! `load()` also takes the crash dumps of `crash`, which hold a snapshot.
+
+use std::fs;
+use crate::crash;
+use crate::error::EmulatorError;
+use crate::memory::MEMORY_SIZE;
+use crate::state::Registers;
+
+const MAGIC: &[u8; 8] = b"8080SNAP";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub rom_set: String,
+    pub registers: Registers,
+    pub shift_register: u16,
+    pub shift_register_offset: u8,
+    pub in_1: u8,
+    pub in_2: u8,
+    pub out_3: u8,
+    pub out_5: u8,
+    pub memory: Vec<u8>,
+}
+
+fn invalid(message: &str) -> EmulatorError {
+    EmulatorError::InvalidSnapshot(message.into())
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let r = &self.registers;
+        let mut result = Vec::with_capacity(MEMORY_SIZE + 64);
+        result.extend_from_slice(MAGIC);
+        result.extend_from_slice(&VERSION.to_le_bytes());
+        result.extend_from_slice(&(self.rom_set.len() as u32).to_le_bytes());
+        result.extend_from_slice(self.rom_set.as_bytes());
+        result.extend_from_slice(&[r.a, r.b, r.c, r.d, r.e, r.h, r.l, r.flags]);
+        result.extend_from_slice(&(r.pc as u16).to_le_bytes());
+        result.extend_from_slice(&(r.sp as u16).to_le_bytes());
+        result.push(r.enable_interrupts as u8);
+        result.push(r.halted as u8);
+        result.extend_from_slice(&self.shift_register.to_le_bytes());
+        result.push(self.shift_register_offset);
+        result.extend_from_slice(&[self.in_1, self.in_2, self.out_3, self.out_5]);
+        result.extend_from_slice(&self.memory);
+        let crc = crc32(&result);
+        result.extend_from_slice(&crc.to_le_bytes());
+        result
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, EmulatorError> {
+        if bytes.len() < MAGIC.len() + 2 || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a snapshot"));
+        }
+        let (content, crc) = bytes.split_at(bytes.len() - 4);
+        if crc32(content).to_le_bytes() != crc {
+            return Err(invalid("wrong checksum"));
+        }
+        let mut reader = Reader::new(content, MAGIC.len(), invalid);
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let length = reader.u32()? as usize;
+        let rom_set = String::from_utf8(reader.take(length)?.to_vec())
+            .map_err(|_| invalid("invalid ROM set name"))?;
+        let r = reader.take(8)?;
+        let mut registers = Registers {
+            a: r[0], b: r[1], c: r[2], d: r[3], e: r[4], h: r[5], l: r[6], flags: r[7],
+            .. Default::default()
+        };
+        registers.pc = reader.u16()? as usize;
+        registers.sp = reader.u16()? as usize;
+        registers.enable_interrupts = reader.u8()? != 0;
+        registers.halted = reader.u8()? != 0;
+        let shift_register = reader.u16()?;
+        let shift_register_offset = reader.u8()?;
+        let ports = reader.take(4)?;
+        let memory = reader.take(MEMORY_SIZE)?.to_vec();
+        if reader.position != content.len() {
+            return Err(invalid("unexpected data after the memory"));
+        }
+        Ok(Snapshot {
+            rom_set, registers, shift_register, shift_register_offset,
+            in_1: ports[0], in_2: ports[1], out_3: ports[2], out_5: ports[3],
+            memory,
+        })
+    }
+
+    pub fn save(&self, file_name: &str) -> Result<(), EmulatorError> {
+        fs::write(file_name, self.to_bytes()).map_err(|e| EmulatorError::Io {
+            file_name: file_name.into(), message: e.to_string()
+        })
+    }
+
+    pub fn load(file_name: &str) -> Result<Snapshot, EmulatorError> {
+        let bytes = fs::read(file_name).map_err(|e| EmulatorError::Io {
+            file_name: file_name.into(), message: e.to_string()
+        })?;
+        if bytes.starts_with(crash::MAGIC.as_bytes()) {
+            return crash::parse_snapshot(&String::from_utf8_lossy(&bytes));
+        }
+        Snapshot::from_bytes(&bytes)
+    }
+}
+
+/// Reads the little endian fields of the snapshot and movie files.
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pub position: usize,
+    /// Builds the error returned when the data is truncated
+    error: fn(&str) -> EmulatorError,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8], position: usize, error: fn(&str) -> EmulatorError) -> Reader<'a> {
+        Reader { bytes, position, error }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], EmulatorError> {
+        if self.position + n > self.bytes.len() {
+            return Err((self.error)("truncated"));
+        }
+        let result = &self.bytes[self.position..self.position + n];
+        self.position += n;
+        Ok(result)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, EmulatorError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, EmulatorError> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, EmulatorError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+/// CRC-32 (IEEE 802.3), as used by zip and png.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
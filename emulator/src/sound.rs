@@ -0,0 +1,191 @@
+//! The sounds of the cabinet. The game turns them on and off with the bits of the output
+//! ports 3 and 5 (see `SharedState::get_out()`). `SoundEvents` turns the values of these
+//! ports into on/off events, which the window plays on the sound card and `Mixer` mixes
+//! into samples for recordings.
+
+use std::collections::HashMap;
+use crate::error::EmulatorError;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SoundType {
+    Fire,
+    InvaderDies,
+    PlayerDies,
+    Ufo,
+    Invader1,
+    Invader2,
+    Invader3,
+    Invader4,
+    UfoHit,
+}
+
+/// Where the samples are, relative to the root of the project
+pub const SOUNDS_DIRECTORY: &str = "sounds";
+
+pub struct SoundInfo {
+    pub sound_type: SoundType,
+    /// The sample, in the `sounds` directory
+    pub file_name: &'static str,
+    pub port: u8,
+    pub bit: u8,
+    /// Whether the sound keeps playing for as long as its bit is on
+    pub repeats: bool,
+}
+
+const fn info(sound_type: SoundType, file_name: &'static str, port: u8, bit: u8, repeats: bool) -> SoundInfo {
+    SoundInfo { sound_type, file_name, port, bit, repeats }
+}
+
+pub const SOUNDS: [SoundInfo; 9] = [
+    info(SoundType::Ufo, "ufo_lowpitch.wav", 3, 0, true),
+    info(SoundType::Fire, "shoot.wav", 3, 1, false),
+    info(SoundType::PlayerDies, "explosion.wav", 3, 2, false),
+    info(SoundType::InvaderDies, "invaderkilled.wav", 3, 3, false),
+    info(SoundType::Invader1, "fastinvader1.wav", 5, 0, false),
+    info(SoundType::Invader2, "fastinvader2.wav", 5, 1, false),
+    info(SoundType::Invader3, "fastinvader3.wav", 5, 2, false),
+    info(SoundType::Invader4, "fastinvader4.wav", 5, 3, false),
+    info(SoundType::UfoHit, "explosion.wav", 5, 4, false),
+];
+
+/// Tracks the sound ports and reports which sounds changed.
+#[derive(Default)]
+pub struct SoundEvents {
+    out_3: u8,
+    out_5: u8,
+}
+
+impl SoundEvents {
+    pub fn new() -> SoundEvents {
+        SoundEvents::default()
+    }
+
+    /// The sounds that were turned on (true) or off (false) since the last call.
+    pub fn update(&mut self, out_3: u8, out_5: u8) -> Vec<(SoundType, bool)> {
+        let mut result = Vec::new();
+        for sound in SOUNDS.iter() {
+            let (previous, value) = if sound.port == 3 { (self.out_3, out_3) } else { (self.out_5, out_5) };
+            let on = value & (1 << sound.bit) != 0;
+            if on != (previous & (1 << sound.bit) != 0) {
+                result.push((sound.sound_type, on));
+            }
+        }
+        self.out_3 = out_3;
+        self.out_5 = out_5;
+        result
+    }
+}
+
+/// A sound being played, and the position of its next sample.
+struct Voice {
+    sound_type: SoundType,
+    position: usize,
+    repeats: bool,
+}
+
+/// Mixes the sounds turned on by the game into 16 bit mono samples.
+pub struct Mixer {
+    sample_rate: u32,
+    samples: HashMap<SoundType, Vec<i16>>,
+    events: SoundEvents,
+    voices: Vec<Voice>,
+}
+
+impl Mixer {
+    /// Load the samples from `directory` and convert them to `sample_rate`.
+    pub fn load(directory: &str, sample_rate: u32) -> Result<Mixer, EmulatorError> {
+        let mut samples = HashMap::new();
+        for sound in SOUNDS.iter() {
+            let file_name = format!("{}/{}", directory, sound.file_name);
+            let bytes = std::fs::read(&file_name).map_err(|e| EmulatorError::Io {
+                file_name: file_name.clone(), message: e.to_string()
+            })?;
+            let wav = read_wav(&bytes).map_err(|message| EmulatorError::Io {
+                file_name: file_name.clone(), message: message.into()
+            })?;
+            samples.insert(sound.sound_type, wav.resample(sample_rate));
+        }
+        Ok(Mixer { sample_rate, samples, events: SoundEvents::new(), voices: Vec::new() })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Start and stop the sounds according to the new values of the ports 3 and 5.
+    pub fn update(&mut self, out_3: u8, out_5: u8) {
+        for (sound_type, on) in self.events.update(out_3, out_5) {
+            let repeats = SOUNDS.iter().any(|s| s.sound_type == sound_type && s.repeats);
+            if on {
+                self.voices.push(Voice { sound_type, position: 0, repeats });
+            } else if repeats {
+                self.voices.retain(|v| v.sound_type != sound_type);
+            }
+        }
+    }
+
+    /// The next `count` samples.
+    pub fn mix(&mut self, count: usize) -> Vec<i16> {
+        let mut result = vec![0i32; count];
+        for voice in self.voices.iter_mut() {
+            let samples = &self.samples[&voice.sound_type];
+            for value in result.iter_mut() {
+                if voice.position >= samples.len() {
+                    if ! voice.repeats || samples.is_empty() {
+                        break;
+                    }
+                    voice.position = 0;
+                }
+                *value += samples[voice.position] as i32;
+                voice.position += 1;
+            }
+        }
+        let samples = &self.samples;
+        self.voices.retain(|v| v.repeats || v.position < samples[&v.sound_type].len());
+        result.into_iter().map(|v| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16).collect()
+    }
+}
+
+struct Wav {
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+impl Wav {
+    /// Nearest neighbour is good enough for these 8 bit samples.
+    fn resample(&self, sample_rate: u32) -> Vec<i16> {
+        let count = self.samples.len() as u64 * sample_rate as u64 / self.sample_rate as u64;
+        (0..count)
+            .map(|i| self.samples[(i * self.sample_rate as u64 / sample_rate as u64) as usize])
+            .collect()
+    }
+}
+
+/// Read a mono PCM WAV file, 8 or 16 bits.
+fn read_wav(bytes: &[u8]) -> Result<Wav, &'static str> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("not a WAV file");
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    let mut format = None;
+    let mut i = 12;
+    while i + 8 <= bytes.len() {
+        let size = u32_at(i + 4) as usize;
+        let data = &bytes[i + 8..(i + 8 + size).min(bytes.len())];
+        match &bytes[i..i + 4] {
+            b"fmt " if data.len() >= 16 => format = Some((u16_at(i + 8), u16_at(i + 10), u32_at(i + 12), u16_at(i + 22))),
+            b"data" => {
+                let samples = match format {
+                    Some((1, 1, _, 8)) => data.iter().map(|b| ((*b as i16) - 128) << 8).collect(),
+                    Some((1, 1, _, 16)) => data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect(),
+                    _ => return Err("only mono 8 and 16 bit PCM is supported"),
+                };
+                return Ok(Wav { sample_rate: format.unwrap().2, samples });
+            },
+            _ => {},
+        }
+        i += 8 + size + (size & 1);
+    }
+    Err("no data")
+}
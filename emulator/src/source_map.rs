@@ -0,0 +1,105 @@
+//! Maps addresses to source lines with an assembler listing, in the format of
+//! `cpudiag.lst` and of the listings of `assembler::assemble()`:
+//!
+//! ```text
+//! 0100    C3 AB 01            JMP CPU ;JUMP TO 8080 CPU DIAGNOSTIC
+//! 0103    4D 49 43 52         DB  'MICROCOSM ASSOCIATES 8080/8085 CPU DIAGNOSTIC'
+//!         . . .
+//! 012F    43
+//! ```
+//!
+//! where the address is followed by a tab, and the 16 columns of bytes by another one.
+//!
+//! Each line of the listing is a line of the source, except for the ". . ." lines and the
+//! last bytes they're followed by, and for the "Labels:" table at the end.
+
+use std::collections::BTreeMap;
+use std::fs;
+use crate::error::EmulatorError;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceMap {
+    /// The line of each statement that assembled to bytes, by address
+    lines: BTreeMap<u16, usize>,
+    /// The address of each of these lines
+    addresses: BTreeMap<usize, u16>,
+    /// The labels of the "Labels:" table
+    labels: BTreeMap<u16, String>,
+}
+
+impl SourceMap {
+    /// `listing_lines`: number the lines of the listing itself, for when the listing is
+    /// the file shown to the user, instead of those of the source.
+    pub fn parse(listing: &str, listing_lines: bool) -> SourceMap {
+        let mut result = SourceMap::default();
+        let mut source_line = 0;
+        let mut lines = listing.lines().enumerate();
+        while let Some((index, line)) = lines.next() {
+            if line.starts_with("\t. . .") {
+                lines.next();
+                continue;
+            }
+            if line == "Labels:" {
+                for (_, line) in lines.by_ref() {
+                    result.parse_labels(line);
+                }
+                break;
+            }
+            source_line += 1;
+            let number = if listing_lines { index + 1 } else { source_line };
+            let address = line.get(..4).and_then(|a| u16::from_str_radix(a, 16).ok());
+            let has_bytes = line.get(5..7).is_some_and(|b| b.trim() != "");
+            if let (Some(address), true, Some('\t')) = (address, has_bytes, line.chars().nth(4)) {
+                result.lines.entry(address).or_insert(number);
+                result.addresses.entry(number).or_insert(address);
+            }
+        }
+        result
+    }
+
+    /// A line of the table of labels: names followed by their address, e.g.
+    /// "acii                    0245aimm                    022A"
+    fn parse_labels(&mut self, mut line: &str) {
+        loop {
+            line = line.trim_start();
+            let name_end = match line.find(char::is_whitespace) {
+                Some(end) => end,
+                None => return,
+            };
+            let rest = line[name_end..].trim_start();
+            match rest.get(..4).and_then(|a| u16::from_str_radix(a, 16).ok()) {
+                Some(address) => {
+                    self.labels.entry(address).or_insert_with(|| line[..name_end].to_string());
+                },
+                None => return,
+            }
+            line = &rest[4..];
+        }
+    }
+
+    pub fn load(file_name: &str, listing_lines: bool) -> Result<SourceMap, EmulatorError> {
+        let listing = fs::read_to_string(file_name)
+            .map_err(|e| EmulatorError::Io { file_name: file_name.into(), message: e.to_string() })?;
+        Ok(SourceMap::parse(&listing, listing_lines))
+    }
+
+    /// The line of the statement at `address`, which must be its first byte.
+    pub fn line(&self, address: u16) -> Option<usize> {
+        self.lines.get(&address).copied()
+    }
+
+    /// The first line from `line` that assembled to bytes, and its address: where a
+    /// breakpoint set on `line` goes.
+    pub fn address(&self, line: usize) -> Option<(usize, u16)> {
+        self.addresses.range(line..).next().map(|(line, address)| (*line, *address))
+    }
+
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    /// The closest label at or before `address`, and its address.
+    pub fn label_before(&self, address: u16) -> Option<(&str, u16)> {
+        self.labels.range(..=address).next_back().map(|(address, name)| (name.as_str(), *address))
+    }
+}
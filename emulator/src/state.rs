@@ -19,7 +19,7 @@ pub(crate) struct Psw {
  */
 impl Psw {
     pub(crate) fn to_u8(f: bool) -> u16 { if f { 1 } else { 0 } }
-    pub(crate) fn to_bool(v: u8) -> bool { if v == 0 { false } else { true }}
+    pub(crate) fn to_bool(v: u8) -> bool { v != 0 }
 
     pub(crate) fn set_flags(&mut self, value: u8) {
         self.sign = Psw::to_bool(value & (1 << 7));
@@ -48,6 +48,35 @@ impl Psw {
     }
 }
 
+/// A copy of the registers, flags and interrupt state of the CPU, for hosts that want
+/// to display or compare them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    /// The low byte of PSW: S Z 0 AC 0 P 1 C
+    pub flags: u8,
+    pub pc: usize,
+    pub sp: usize,
+    pub enable_interrupts: bool,
+    pub halted: bool,
+}
+
+impl std::fmt::Display for Registers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |bit: u8| (self.flags >> bit) & 1;
+        write!(f, "a:{:02x} b:{:02x} c:{:02x} d:{:02x} e:{:02x} lh:{:04x} pc:{:04x} sp:{:04x} [C={} P={} S={} Z={}]",
+               self.a, self.b, self.c, self.d, self.e, ((self.h as u16) << 8) | self.l as u16,
+               self.pc, self.sp,
+               flag(0), flag(2), flag(7), flag(6))
+    }
+}
+
 #[derive(Default)]
 pub(crate) struct State {
     pub b: u8,
@@ -60,6 +89,8 @@ pub(crate) struct State {
     pub pc: usize,
     pub sp: usize,
     pub enable_interrupts: bool,
+    /// Set by HLT, cleared when an interrupt is accepted
+    pub halted: bool,
 }
 
 impl State {
@@ -70,6 +101,38 @@ impl State {
         }
     }
 
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.psw.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            h: self.h,
+            l: self.l,
+            flags: (self.psw.value() & 0xff) as u8,
+            pc: self.pc,
+            sp: self.sp,
+            enable_interrupts: self.enable_interrupts,
+            halted: self.halted,
+        }
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.psw.a = registers.a;
+        self.psw.set_flags(registers.flags);
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.pc = registers.pc;
+        self.sp = registers.sp;
+        self.enable_interrupts = registers.enable_interrupts;
+        self.halted = registers.halted;
+    }
+
     pub fn m(&self) -> usize {
         Memory::to_word(self.l, self.h)
     }
@@ -81,20 +144,19 @@ impl State {
                 self.psw.disassemble())
     }
 
-    pub fn set_logic_flags(&mut self, value: i16) {
+    /// Zero, sign and parity, which every ALU operation sets from its result.
+    fn set_zsp_flags(&mut self, value: u8) {
         self.psw.zero = value == 0;
         self.psw.sign = 0x80 == (value & 0x80);
-        self.psw.parity = (value & 0xff).count_ones() % 2 == 0;
-        self.psw.carry = false;
-        // state.psw.auxiliary_carry = (state.psw.a < byte1);
+        self.psw.parity = value.count_ones().is_multiple_of(2);
     }
 
-    pub fn set_arithmetic_flags(&mut self, value: i16) {
-        self.psw.zero = (value & 0xff) == 0;
-        self.psw.sign = 0x80 == (value & 0x80);
-        self.psw.parity = (value & 0xff).count_ones() % 2 == 0;
-        self.psw.carry = value < 0 || value > 0xff;
-        self.psw.auxiliary_carry = self.psw.carry;
+    /// ANA, ORA, XRA and their immediate versions always clear the carry. AC is only
+    /// set by the ANDs (see `and()`).
+    pub fn set_logic_flags(&mut self, value: u8, auxiliary_carry: bool) {
+        self.set_zsp_flags(value);
+        self.psw.carry = false;
+        self.psw.auxiliary_carry = auxiliary_carry;
     }
 
     pub fn jump_if_flag(&mut self, word: usize, flag: bool) -> bool {
@@ -107,72 +169,128 @@ impl State {
     }
 
     pub fn call(&mut self, memory: &mut Memory, target_pc: usize) {
-        let ret = self.pc + 3;
-        memory.write(self.sp - 1, ((ret >> 8) as u8) & 0xff);
-        memory.write(self.sp - 2, (ret & 0xff) as u8);
-        self.sp -= 2;
+        self.push_and_jump(memory, self.pc + 3, target_pc);
+    }
+
+    /// RST n is a one byte CALL to n * 8.
+    pub fn rst(&mut self, memory: &mut Memory, n: u8) {
+        self.push_and_jump(memory, self.pc + 1, (n as usize) << 3);
+    }
+
+    fn push_and_jump(&mut self, memory: &mut Memory, ret: usize, target_pc: usize) {
+        self.push(memory, (ret >> 8) as u8, ret as u8);
         self.pc = target_pc;
     }
 
+    /// The stack wraps around the 64K address space like on the real CPU.
+    pub fn push(&mut self, memory: &mut Memory, high: u8, low: u8) {
+        self.sp = self.sp.wrapping_sub(1) & 0xffff;
+        memory.write(self.sp, high);
+        self.sp = self.sp.wrapping_sub(1) & 0xffff;
+        memory.write(self.sp, low);
+    }
+
+    /// Returns the low and high bytes.
+    pub fn pop(&mut self, memory: &Memory) -> (u8, u8) {
+        let low = memory.read(self.sp);
+        let high = memory.read(self.sp + 1);
+        self.sp = (self.sp + 2) & 0xffff;
+        (low, high)
+    }
+
     pub fn ret(&mut self, memory: &Memory, flag: bool) -> bool {
         if flag {
-            self.pc = Memory::to_word(memory.read(self.sp), memory.read(self.sp + 1));
-            self.sp += 2;
+            let (low, high) = self.pop(memory);
+            self.pc = Memory::to_word(low, high);
         }
         flag
     }
 
+    /// DCR: the carry is not affected. The 8080 adds 0xff, so AC is the carry out of
+    /// bit 3 of that addition, i.e. set unless the low nibble was 0.
     pub fn dec(&mut self, n: u8) -> u8 {
-        let value = if n == 0 {
-            self.psw.carry = true;
-            self.psw.auxiliary_carry = true;
-            0xff
-        } else {
-            n - 1
-        };
-        self.set_arithmetic_flags(value as i16);
+        let value = n.wrapping_sub(1);
+        self.psw.auxiliary_carry = (n & 0xf) != 0;
+        self.set_zsp_flags(value);
+        value
+    }
+
+    /// INR: the carry is not affected.
+    pub fn inr(&mut self, n: u8) -> u8 {
+        let value = n.wrapping_add(1);
+        self.psw.auxiliary_carry = (n & 0xf) == 0xf;
+        self.set_zsp_flags(value);
         value
     }
 
     pub fn xra(&mut self, value: u8) -> u8 {
         let value = self.psw.a ^ value;
-        self.set_arithmetic_flags(value as i16);
+        self.set_logic_flags(value, false);
         value
     }
 
+    /// The 8080 sets AC to the OR of bit 3 of both operands.
     pub fn and(&mut self, value: u8) -> u8 {
+        let auxiliary_carry = ((self.psw.a | value) & 0x08) != 0;
         let value = self.psw.a & value;
-        self.set_arithmetic_flags(value as i16);
+        self.set_logic_flags(value, auxiliary_carry);
         value
     }
 
     pub fn or(&mut self, value: u8) -> u8 {
         let value = self.psw.a | value;
-        self.set_arithmetic_flags(value as i16);
+        self.set_logic_flags(value, false);
         value
     }
 
+    /// ADD, ADC, ADI, ACI: AC is the carry out of bit 3, CY the carry out of bit 7.
     pub fn add(&mut self, value: u8, carry: u16) {
-        let value = self.psw.a as u16 + value as u16 + carry;
-        self.set_arithmetic_flags(value as i16);
-        self.psw.a = value as u8 & 0xff;
+        let a = self.psw.a;
+        let result = a as u16 + value as u16 + carry;
+        self.psw.auxiliary_carry = (a & 0xf) as u16 + (value & 0xf) as u16 + carry > 0xf;
+        self.psw.carry = result > 0xff;
+        self.set_zsp_flags(result as u8);
+        self.psw.a = result as u8;
     }
 
-    pub fn sub(&mut self, value: u8, carry: u16) {
-        let value = self.psw.a as i16 - value as i16 - carry as i16;
-        self.set_arithmetic_flags(value as i16);
-        self.psw.a = value as u8 & 0xff;
+    pub fn sub(&mut self, value: u8, borrow: u16) {
+        self.psw.a = self.subtract(value, borrow);
     }
 
     pub fn cmp(&mut self, n: u8) {
-        let value: i16 = self.psw.a as i16 - n as i16;
-        self.set_arithmetic_flags(value);
+        self.subtract(n, 0);
     }
 
-    pub fn inr(&mut self, n: u8) -> u8 {
-        let value = if n == 0xff { 0 } else { n + 1 };
-        self.set_arithmetic_flags(value as i16);
-        value
+    /// SUB, SBB, SUI, SBI, CMP, CPI. The 8080 subtracts by adding the one's complement
+    /// of the operand plus the inverted borrow: CY is the inverse of the carry out of bit 7
+    /// (i.e. a borrow) but AC is the carry out of bit 3, not inverted.
+    fn subtract(&mut self, value: u8, borrow: u16) -> u8 {
+        let a = self.psw.a;
+        let result = a as i16 - value as i16 - borrow as i16;
+        self.psw.auxiliary_carry = (a & 0xf) as i16 - (value & 0xf) as i16 - borrow as i16 >= 0;
+        self.psw.carry = result < 0;
+        self.set_zsp_flags(result as u8);
+        result as u8
+    }
+
+    /// DAA: adjust A to two BCD digits after an addition.
+    pub fn daa(&mut self) {
+        let a = self.psw.a;
+        let lsb = a & 0x0f;
+        let msb = a >> 4;
+        let mut correction = 0;
+        let mut carry = self.psw.carry;
+        if lsb > 9 || self.psw.auxiliary_carry {
+            correction |= 0x06;
+        }
+        if msb > 9 || (msb >= 9 && lsb > 9) || carry {
+            correction |= 0x60;
+            carry = true;
+        }
+        self.psw.auxiliary_carry = lsb + (correction & 0x0f) > 0xf;
+        self.psw.carry = carry;
+        self.psw.a = a.wrapping_add(correction);
+        self.set_zsp_flags(self.psw.a);
     }
 
     pub fn add_hl(&mut self, b0: u8, b1: u8) {
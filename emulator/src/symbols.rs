@@ -0,0 +1,155 @@
+//! Symbol tables: the names of the routines and variables of a program, used by
+//! everything that shows addresses (`Memory::disassemble`, the traces, the profiler and
+//! the debugger), e.g. `CALL DrawShieldPl1` instead of `CALL 01EFH`.
+//!
+//! A symbol file has one symbol per line: its address in hexadecimal, its name, its kind
+//! (`code`, the default, or `data`) and an optional comment after a `;`:
+//!
+//! ```text
+//! # Space Invaders
+//! 01EF DrawShieldPl1          ; Draw the shields of player 1
+//! 20C0 isrDelay      data     ; Counted down by the interrupts
+//! ```
+//!
+//! Empty lines and lines starting with `#` or `;` are skipped.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use crate::error::EmulatorError;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SymbolKind {
+    /// A routine or a jump target
+    Code,
+    /// A variable or a table
+    Data,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub address: u16,
+    pub name: String,
+    pub kind: SymbolKind,
+    pub comment: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    symbols: BTreeMap<u16, Symbol>,
+    /// The address of each name, in upper case
+    addresses: HashMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    /// Add a symbol, replacing the one at the same address.
+    pub fn insert(&mut self, symbol: Symbol) {
+        if let Some(old) = self.symbols.remove(&symbol.address) {
+            self.addresses.remove(&old.name.to_ascii_uppercase());
+        }
+        self.addresses.insert(symbol.name.to_ascii_uppercase(), symbol.address);
+        self.symbols.insert(symbol.address, symbol);
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut result = Symbols::new();
+        for (i, line) in text.lines().enumerate() {
+            let (fields, comment) = match line.split_once(';') {
+                Some((fields, comment)) => (fields, Some(comment.trim().to_string()).filter(|c| ! c.is_empty())),
+                None => (line, None),
+            };
+            let fields: Vec<&str> = fields.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+            let error = || format!("Invalid symbol at line {}: {}", i + 1, line.trim());
+            let digits = fields[0].trim_start_matches("0x").trim_start_matches('$');
+            let address = u16::from_str_radix(digits.strip_suffix(['h', 'H']).unwrap_or(digits), 16)
+                .map_err(|_| error())?;
+            let kind = match fields.get(2).map(|kind| kind.to_ascii_lowercase()).as_deref() {
+                None | Some("code") => SymbolKind::Code,
+                Some("data") => SymbolKind::Data,
+                Some(_) => return Err(error()),
+            };
+            match fields[1..] {
+                [name] | [name, _] => result.insert(Symbol { address, name: name.to_string(), kind, comment }),
+                _ => return Err(error()),
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn load(file_name: &str) -> Result<Symbols, EmulatorError> {
+        let io_error = |message: String| EmulatorError::Io { file_name: file_name.into(), message };
+        let text = fs::read_to_string(file_name).map_err(|e| io_error(e.to_string()))?;
+        Symbols::parse(&text).map_err(io_error)
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.values()
+    }
+
+    pub fn get(&self, address: u16) -> Option<&Symbol> {
+        self.symbols.get(&address)
+    }
+
+    /// The address of a name, whatever its case.
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.addresses.get(&name.to_ascii_uppercase()).copied()
+    }
+
+    /// The name of an address, in the form `Instruction::format()` and the profiler take.
+    pub fn label(&self, address: u16) -> Option<String> {
+        self.get(address).map(|symbol| symbol.name.clone())
+    }
+
+    /// The closest code symbol at or before `address`.
+    pub fn routine(&self, address: u16) -> Option<&Symbol> {
+        self.symbols.range(..=address).rev().find(|(_, symbol)| symbol.kind == SymbolKind::Code)
+            .map(|(_, symbol)| symbol)
+    }
+
+    /// Where `address` is: `DrawShieldPl1` or `DrawShieldPl1+3` in the routine before
+    /// it, `None` when there's no routine in the 256 bytes before, which would rather be
+    /// a routine without a symbol.
+    pub fn location(&self, address: u16) -> Option<String> {
+        self.routine(address).filter(|symbol| address - symbol.address < 0x100).map(|symbol| {
+            match address - symbol.address {
+                0 => symbol.name.clone(),
+                offset => format!("{}+{:X}", symbol.name, offset),
+            }
+        })
+    }
+
+    /// Parse an address: a symbol, a symbol with a hexadecimal offset (`DrawShieldPl1+3`)
+    /// or a hexadecimal number.
+    pub fn parse_address(&self, s: &str) -> Result<u16, String> {
+        let s = s.trim();
+        let (name, offset) = match s.split_once('+') {
+            Some((name, offset)) => (name.trim(), Some(offset.trim())),
+            None => (s, None),
+        };
+        let offset = offset.map(|offset| u16::from_str_radix(offset, 16)
+            .map_err(|_| format!("Invalid offset: {}", offset))).transpose()?.unwrap_or(0);
+        match self.address(name) {
+            Some(address) => Ok(address.wrapping_add(offset)),
+            None if offset == 0 => {
+                let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+                u16::from_str_radix(digits.strip_suffix(['h', 'H']).unwrap_or(digits), 16)
+                    .map_err(|_| format!("Unknown symbol or invalid address: {}", s))
+            },
+            None => Err(format!("Unknown symbol: {}", name)),
+        }
+    }
+}
@@ -1,30 +1,221 @@
-#[cfg(test)]
-mod test {
-    use crate::memory::Memory;
-    use crate::emulator::{Emulator, StepResult, StepStatus};
-
-    #[test]
-    fn run_cpu_diag() {
-        let mut memory = Memory::new();
-        let start = 0x100;
-        memory.read_file("cpudiag.bin", start);
-        let mut computer = Emulator::new(Box::new(memory), start as usize);
-        let mut result = StepResult { status: StepStatus::Continue, cycles: 0 };
-        unsafe {
-            while result.status == StepStatus::Continue {
-                result = computer.step(true);
-            }
-        }
-        match result.status {
-            StepStatus::Success(s) => {
-                println!("Success: {}", s)
-            },
-            StepStatus::Failure(s) => {
-                panic!(s);
-            },
-            _ => {
-                println!("Something went wrong");
-            }
-        }
+use crate::memory::{Memory, GRAPHIC_MEMORY_SIZE};
+use crate::disassembler::{self, Analysis, Flow, Operand, Register, RegisterPair};
+use crate::emulator::{StepStatus, HEIGHT, WIDTH};
+use crate::error::EmulatorError;
+use crate::opcodes::*;
+use crate::opcodes::OPCODES;
+use crate::screen::{self, GifRecorder};
+use crate::sound::{Mixer, SoundEvents, SoundType, SOUNDS_DIRECTORY};
+use crate::test_util::emulator_with_bytes;
+
+#[test]
+fn independent_instances() {
+    // MVI A,n ; STA 2400h ; OUT 3
+    let program = |value: u8| emulator_with_bytes(&[MVI_A, value, STA, 0x00, 0x24, OUT, 3], 0);
+    let mut e1 = program(0x11);
+    let mut e2 = program(0x22);
+    for _ in 0..3 {
+        e1.step().unwrap();
+        e2.step().unwrap();
+    }
+
+    let s1 = e1.shared_state();
+    let s2 = e2.shared_state();
+    assert_eq!(s1.lock().unwrap().graphic_memory()[0], 0x11);
+    assert_eq!(s2.lock().unwrap().graphic_memory()[0], 0x22);
+    assert_eq!(s1.lock().unwrap().get_out(3), Ok(0x11));
+    assert_eq!(s2.lock().unwrap().get_out(3), Ok(0x22));
+
+    s1.lock().unwrap().pause();
+    assert!(e1.step().unwrap().status == StepStatus::Paused);
+    assert!(e2.step().unwrap().status == StepStatus::Continue);
+}
+
+#[test]
+fn errors() {
+    // MVI B,42h ; IN 7
+    let mut emulator = emulator_with_bytes(&[MVI_B, 0x42, IN, 7], 0);
+    emulator.step().unwrap();
+    match emulator.step() {
+        Err(EmulatorError::UnsupportedInPort { port, pc, opcode, registers }) => {
+            assert_eq!((port, pc, opcode), (7, 2, IN));
+            assert_eq!(registers.b, 0x42);
+            assert_eq!(registers.pc, 2);
+        },
+        _ => panic!("Expected an UnsupportedInPort error"),
+    }
+
+    assert_eq!(emulator.shared_state().lock().unwrap().get_out(4),
+               Err(EmulatorError::UnknownOutChannel(4)));
+
+    match Memory::new().read_file("does-not-exist.rom", 0) {
+        Err(EmulatorError::Io { file_name, .. }) => assert_eq!(file_name, "does-not-exist.rom"),
+        _ => panic!("Expected an Io error"),
+    }
+}
+
+#[test]
+fn all_opcodes_are_defined() {
+    for op in 0..=0xff_u8 {
+        assert!(OPCODES.contains_key(&op), "Opcode {:02x} is missing", op);
+    }
+}
+
+#[test]
+fn hlt_waits_for_interrupt() {
+    let program = [
+        LXI_SP, 0x00, 0x24,  // 0000
+        EI,                  // 0003
+        HLT,                 // 0004
+        JMP, 0x03, 0x00,     // 0005
+        INR_B, RET,          // 0008: first interrupt
+    ];
+    let mut emulator = emulator_with_bytes(&program, 0);
+    emulator.write_memory(0x10, INR_C);
+    emulator.write_memory(0x11, RET);
+    let cycles = emulator.run_one_frame().unwrap();
+    assert!(cycles >= 2_000_000 / 60);
+
+    // Both interrupts woke the CPU up: the first handler ran, the second one is about to
+    let registers = emulator.registers();
+    assert_eq!((registers.b, registers.c), (1, 0));
+    assert_eq!(registers.pc, 0x10);
+    assert!(! registers.halted);
+}
+
+#[test]
+fn rst_and_undocumented_aliases() {
+    let program = [
+        LXI_SP, 0x00, 0x24,  // 0000
+        NOP_38,              // 0003
+        RST_5,               // 0004
+    ];
+    let mut emulator = emulator_with_bytes(&program, 0);
+    // 0028: CALL (undocumented) 0040
+    for (i, byte) in [CALL_FD, 0x40, 0x00].iter().enumerate() {
+        emulator.write_memory(0x28 + i as u16, *byte);
     }
+    // 0040: RET (undocumented)
+    emulator.write_memory(0x40, RET_D9);
+    for _ in 0..3 {
+        emulator.step().unwrap();
+    }
+    assert_eq!(emulator.registers().pc, 0x28);
+    emulator.step().unwrap();
+    assert_eq!(emulator.registers().pc, 0x40);
+    emulator.step().unwrap();
+    assert_eq!(emulator.registers().pc, 0x2b);
+    assert_eq!(emulator.registers().sp, 0x23fe);
+}
+
+#[test]
+fn stack_wraps_around() {
+    // With SP at 0, PUSH writes at ffff and fffe, and POP reads them back
+    let mut emulator = emulator_with_bytes(&[LXI_B, 0x34, 0x12, PUSH_B, POP_D], 0);
+    for _ in 0..2 {
+        emulator.step().unwrap();
+    }
+    assert_eq!(emulator.registers().sp, 0xfffe);
+    assert_eq!(emulator.memory().read(0xffff), 0x12);
+    emulator.step().unwrap();
+    let registers = emulator.registers();
+    assert_eq!((registers.d, registers.e, registers.sp), (0x12, 0x34, 0));
+}
+
+#[test]
+fn screen_is_rotated() {
+    // The first byte of video memory is the bottom left corner, bit 0 being the lowest
+    let mut graphic_memory = [0; GRAPHIC_MEMORY_SIZE];
+    graphic_memory[0] = 0x01;
+    graphic_memory[GRAPHIC_MEMORY_SIZE - 1] = 0x80;
+    let pixels = screen::pixels(&graphic_memory);
+    let width = WIDTH as usize;
+    assert_eq!(pixels.iter().filter(|p| **p).count(), 2);
+    assert!(pixels[(HEIGHT as usize - 1) * width]);
+    assert!(pixels[width - 1]);
+}
+
+#[test]
+fn gif_capture_keeps_60_hz() {
+    let file_name = std::env::temp_dir().join("space-invaders-test.gif");
+    let file_name = file_name.to_str().unwrap();
+    let mut graphic_memory = [0; GRAPHIC_MEMORY_SIZE];
+    let mut recorder = GifRecorder::create(file_name).unwrap();
+    // 30 frames that change, then 30 identical ones
+    for i in 0..60 {
+        graphic_memory[0] = i.min(30);
+        recorder.add_frame(&graphic_memory).unwrap();
+    }
+    recorder.finish().unwrap();
+
+    let mut decoder = gif::DecodeOptions::new().read_info(std::fs::File::open(file_name).unwrap()).unwrap();
+    let mut delays = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        delays.push(frame.delay);
+    }
+    std::fs::remove_file(file_name).unwrap();
+    assert_eq!(delays.len(), 31);
+    assert_eq!(delays.iter().map(|d| *d as u32).sum::<u32>(), 100);
+    assert!(delays[..30].iter().all(|d| *d == 1 || *d == 2));
+}
+
+#[test]
+fn sound_events_and_mixer() {
+    let mut events = SoundEvents::new();
+    assert_eq!(events.update(0x02, 0), vec![(SoundType::Fire, true)]);
+    assert_eq!(events.update(0x02, 0), vec![]);
+    assert_eq!(events.update(0x01, 0x10),
+        vec![(SoundType::Ufo, true), (SoundType::Fire, false), (SoundType::UfoHit, true)]);
+
+    // The tests run in the emulator directory
+    let mut mixer = Mixer::load(&format!("../{}", SOUNDS_DIRECTORY), 11025).unwrap();
+    assert!(mixer.mix(100).iter().all(|s| *s == 0));
+    // The shot plays to the end even once its bit is off, the UFO repeats until its bit is off
+    mixer.update(0x03, 0);
+    mixer.update(0x01, 0);
+    assert!(mixer.mix(30000).iter().skip(27000).any(|s| *s != 0));
+    mixer.update(0, 0);
+    assert!(mixer.mix(100).iter().all(|s| *s == 0));
+}
+
+#[test]
+fn disassemble_operands() {
+    // MVI A,0FFH ; LXI B,1234H ; MOV M,B ; OUT 3 ; JNZ 0100H ; RST 1 ; then a truncated CALL and a NOP
+    let bytes = [MVI_A, 0xff, LXI_B, 0x34, 0x12, MOV_M_B, OUT, 3, JNZ, 0x00, 0x01, RST_1, CALL, 0x00];
+    let instructions = disassembler::disassemble(&bytes, 0x100);
+    let text: Vec<String> = instructions.iter().map(|i| i.to_string()).collect();
+    assert_eq!(text, vec!["MVI     A,0FFH", "LXI     B,1234H", "MOV     M,B", "OUT     03H", "JNZ     0100H",
+        "RST     1", "DB      0CDH", "NOP"]);
+    assert_eq!(instructions[0].operands, vec![Operand::Register(Register::A), Operand::Immediate8(0xff)]);
+    assert_eq!(instructions[1].operands, vec![Operand::RegisterPair(RegisterPair::B), Operand::Immediate16(0x1234)]);
+    assert_eq!(instructions[3].operands, vec![Operand::Port(3)]);
+    assert_eq!(instructions[4].address, 0x108);
+    assert_eq!(instructions[4].flow(), Flow::ConditionalJump(0x100));
+    assert_eq!(instructions[5].flow(), Flow::Call(0x08));
+    assert!(instructions[6].is_data());
+}
+
+#[test]
+fn recursive_descent() {
+    let mut rom = vec![0u8; 0x20];
+    // 0000: JMP 0018H
+    rom[0..3].copy_from_slice(&[JMP, 0x18, 0x00]);
+    // 0008: RET, followed by data
+    rom[8] = RET;
+    rom[9..12].copy_from_slice(b"HI!");
+    // 0010: PCHL
+    rom[0x10] = PCHL;
+    // 0018: CALL 0008H ; JZ 0018H ; HLT
+    rom[0x18..0x1f].copy_from_slice(&[CALL, 0x08, 0x00, JZ, 0x18, 0x00, HLT]);
+    let analysis = Analysis::new(&rom, 0, &[0x0000, 0x0008, 0x0010]);
+    assert!(analysis.is_code(0x01));
+    assert!(! analysis.is_code(0x03));
+    assert!(! analysis.is_code(0x09));
+    assert!(analysis.is_code(0x1e));
+    assert_eq!(analysis.label(0x08).as_deref(), Some("rst_1"));
+    assert_eq!(analysis.label(0x18).as_deref(), Some("lbl_0018"));
+    let listing = analysis.listing();
+    assert!(listing.contains("reset:\n        JMP     lbl_0018 "), "{}", listing);
+    assert!(listing.contains("CALL    rst_1 "), "{}", listing);
+    assert!(listing.contains("DB      48H,49H,21H,00H,00H,00H,00H ; 0009  HI!...."), "{}", listing);
 }
@@ -0,0 +1,148 @@
+use crate::assembler::assemble;
+use crate::cpm::Cpm;
+use crate::disassembler::Analysis;
+use crate::error::EmulatorError;
+
+/// cpudiag.bin wasn't assembled from cpudiag.asm as it is: its assembler collapsed the
+/// runs of spaces in two strings, and the binary was patched to put the stack at 07ADH.
+/// These changes of the source give the bytes of the binary.
+const CPUDIAG_PATCHES: [(&str, &str); 3] = [
+    ("' VERSION 1.0  (C) 1980'", "' VERSION 1.0 (C) 1980'"),
+    ("' CPU HAS FAILED!    ERROR EXIT=$'", "' CPU HAS FAILED! ERROR EXIT=$'"),
+    ("STACK\tEQU\tTEMPP+256", "STACK\tEQU\t$+256"),
+];
+
+/// cpudiag.asm with the patches.
+pub(crate) fn cpudiag_source() -> String {
+    let mut source = std::fs::read_to_string("cpudiag.asm").unwrap();
+    for (original, patched) in CPUDIAG_PATCHES {
+        assert!(source.contains(original), "{}", original);
+        source = source.replace(original, patched);
+    }
+    source
+}
+
+#[test]
+fn cpudiag_is_byte_identical() {
+    let program = assemble(&cpudiag_source()).unwrap();
+    assert_eq!(program.origin, 0x100);
+    assert_eq!(program.bytes, std::fs::read("cpudiag.bin").unwrap());
+    assert_eq!(program.symbols["STACK"], 0x07ad);
+    assert_eq!(program.symbols["TEMP2"], 0x06a8);
+
+    // Same listing as cpudiag.lst, up to its table of labels, once the lines of the
+    // patches are written back as in the source. The first line of cpudiag.lst lost its
+    // indentation, its generator dropped the <AND>, <OR> and <XOR> of the comments, and
+    // it has the stack before the patch, at 06ADH.
+    let expected = std::fs::read_to_string("cpudiag.lst").unwrap();
+    let expected: Vec<&str> = expected.lines().skip(1).take_while(|l| *l != "Labels:").collect();
+    let listing: Vec<String> = program.listing.lines().skip(1).map(|line| {
+        let mut line = CPUDIAG_PATCHES.iter()
+            .fold(line.to_string(), |line, (original, patched)| line.replace(patched, original));
+        for operator in ["<AND>", "<OR>", "<XOR>"] {
+            line = line.replace(operator, "");
+        }
+        line.replace("31 AD 07", "31 AD 06")
+    }).collect();
+    for (i, line) in expected.iter().enumerate() {
+        assert_eq!(listing.get(i).map(String::as_str), Some(*line), "line {}", i + 2);
+    }
+}
+
+#[test]
+fn forward_equ_keeps_its_address() {
+    // `$` is the address of the EQU, even when LATER delays its value to the end
+    let program = assemble("
+        ORG     100H
+        NOP
+        NOP
+HERE    EQU     $ + LATER - 100H
+LATER:  NOP
+").unwrap();
+    assert_eq!(program.symbols["HERE"], 0x102 + 2);
+}
+
+#[test]
+fn expressions_and_directives() {
+    let program = assemble("
+COUNT   EQU     LAST - START     ; forward references
+        ORG     10H
+START:  DB      'It''s', -1, HIGH 1234H, LOW 1234H
+        DW      START, $ + 2
+        DS      2
+        MVI     B,(COUNT * 2) MOD 7
+        MVI     C,1010B OR 17Q
+        LXI     SP,NOT 0
+        RST     COUNT AND 7
+LAST:   DB      'x'
+").unwrap();
+    assert_eq!(program.origin, 0x10);
+    assert_eq!(program.bytes, vec![
+        b'I', b't', b'\'', b's', 0xff, 0x12, 0x34,
+        0x10, 0x00, 0x19, 0x00,
+        0, 0,
+        0x06, 21 * 2 % 7,
+        0x0e, 0x0f,
+        0x31, 0xff, 0xff,
+        0xc7 | (21 & 7) << 3,
+        b'x',
+    ]);
+    assert_eq!(program.symbols["COUNT"], 21);
+}
+
+#[test]
+fn assembly_errors() {
+    let line = |source: &str| match assemble(source) {
+        Err(EmulatorError::Assembly { line, .. }) => line,
+        other => panic!("{:?}", other.map(|p| p.bytes)),
+    };
+    assert_eq!(line("  NOP\n  FOO A"), 2);
+    assert_eq!(line("  MOV A,SP"), 1);
+    assert_eq!(line("  MVI A,256"), 1);
+    assert_eq!(line("  JMP NOWHERE"), 1);
+    assert_eq!(line("X: NOP\nX: NOP"), 2);
+    assert_eq!(line("A   EQU 1"), 1);
+    assert_eq!(line("  NOP\n  DW 7FFFFFFFFFFFFFFFH*2"), 2);
+    assert_eq!(line("  DW -(-7FFFFFFFFFFFFFFFH-1)"), 1);
+    assert_eq!(line("  DW (-7FFFFFFFFFFFFFFFH-1)/-1"), 1);
+}
+
+#[test]
+fn disassembly_reassembles() {
+    let rom = std::fs::read("../space-invaders.rom").unwrap();
+    let listing = Analysis::new(&rom, 0, &[0x0000, 0x0008, 0x0010]).listing();
+    let program = assemble(&listing).unwrap();
+    assert_eq!(program.origin, 0);
+    assert!(program.bytes == rom, "The disassembly doesn't reassemble to the ROM");
+}
+
+#[test]
+fn overlapping_instructions() {
+    // LXI D,0100H at 0, and LXI B,0 at 2 in its operand
+    let bytes = [0x11, 0x00, 0x01, 0x00, 0x00, 0xc9];
+    let analysis = Analysis::new(&bytes, 0, &[0, 2]);
+    assert_eq!(analysis.instructions.keys().copied().collect::<Vec<u16>>(), [0, 2, 5]);
+    let listing = analysis.listing();
+    assert!(listing.contains("entry_0002      EQU     0002H\n"), "{}", listing);
+    assert_eq!(assemble(&listing).unwrap().bytes, bytes);
+
+    // An instruction at the top of the memory
+    let listing = Analysis::new(&[0x00], 0xffff, &[0xffff]).listing();
+    assert_eq!(assemble(&listing).unwrap().bytes, [0x00]);
+}
+
+#[test]
+fn mov_e_a() {
+    // The MOV E,A bug: E was written to A
+    let program = assemble("
+        ORG     100H
+        MVI     A,'8'
+        MVI     E,0
+        MOV     E,A
+        MVI     A,0
+        MVI     C,2
+        CALL    5               ; BDOS: print E
+        JMP     0
+").unwrap();
+    assert_eq!(Cpm::new(&program.bytes).run().unwrap(), "8");
+}
@@ -0,0 +1,52 @@
+use crate::assembler::assemble;
+use crate::coverage::{Coverage, OPCODE, OPERAND, READ};
+use crate::symbols::Symbols;
+use crate::test_util::{emulator_with, run_until_halted};
+
+const PROGRAM: &str = "
+        ORG     0
+        LDA     TABLE           ; 0000
+        CPI     2               ; 0003
+        JZ      TWO             ; 0005
+        HLT                     ; 0008
+TWO:    MVI     A,0             ; 0009
+        HLT                     ; 000B
+TABLE:  DB      1,2             ; 000C
+";
+
+fn run(table: u8) -> Coverage {
+    let mut emulator = emulator_with(PROGRAM);
+    emulator.write_memory(0x0c, table);
+    emulator.enable_coverage(Coverage::new());
+    run_until_halted(&mut emulator);
+    // Inspecting the memory isn't a read
+    emulator.read_memory(0x0d);
+    emulator.disable_coverage().unwrap()
+}
+
+#[test]
+fn coverage() {
+    let one = run(1);
+    let flags: Vec<u8> = (0..0x0e).map(|address| one.flags(address)).collect();
+    assert_eq!(flags, vec![
+        OPCODE, OPERAND, OPERAND, OPCODE, OPERAND, OPCODE, OPERAND, OPERAND, OPCODE,
+        0, 0, 0, READ, 0,
+    ]);
+    assert_eq!(one.ranges(0..=0x0d, |address| ! one.is_executed(address)), vec![9..=0x0d]);
+    assert_eq!(Coverage::parse(&one.to_text()), Ok(one.clone()));
+    assert!(one.to_text().starts_with("0000 x..\n0001 .o.\n"));
+    assert!(one.to_text().ends_with("0008 x..\n000c ..r\n"));
+    assert!(Coverage::parse("0000 z").is_err());
+
+    let mut merged = run(2);
+    assert!(! merged.is_executed(8));
+    merged.merge(&one);
+    assert_eq!(merged.count(0..=0x0d, OPCODE | OPERAND), 12);
+
+    let bytes = assemble(PROGRAM).unwrap().bytes;
+    let listing = one.listing(&bytes, 0, &[0], &Symbols::new());
+    assert!(listing.contains("; Never executed code: 3 bytes (21.43%) in 1 ranges\n;   0009-000B lbl_0009\n"));
+    assert!(listing.contains("MVI     A,00H                   ; 0009  3E 00  <<< never executed\n"));
+    assert!(listing.contains("HLT                             ; 0008  76\n"));
+    assert!(listing.contains("DB      01H,02H                 ; 000C  ..  <<< read\n"));
+}
@@ -0,0 +1,93 @@
+//! The CP/M diagnostic programs. `cpudiag` is in the repository, the other exercisers
+//! need to be copied into `cpu_tests/` (see the README there). Their tests are skipped
+//! without them, unless `REQUIRE_CPU_TESTS` is set, e.g. in CI, which makes them fail.
+
+use std::path::Path;
+use crate::cpm::{Cpm, DEFAULT_MAX_CYCLES};
+use crate::error::EmulatorError;
+use crate::opcodes::*;
+
+fn run_exerciser(file_name: &str, max_cycles: u64) -> Option<String> {
+    let path = format!("cpu_tests/{}", file_name);
+    if ! Path::new(&path).exists() {
+        if std::env::var_os("REQUIRE_CPU_TESTS").is_some() {
+            panic!("{} not found: copy it there (see cpu_tests/README.md)", path);
+        }
+        println!("Skipping {}: not found", path);
+        return None;
+    }
+    let mut cpm = Cpm::from_file(&path).unwrap();
+    cpm.set_max_cycles(max_cycles);
+    let output = cpm.run().unwrap().to_string();
+    println!("{}", output);
+    Some(output)
+}
+
+#[test]
+fn bdos_console_output() {
+    let program = [
+        MVI_C, 2, MVI_E, b'8', CALL, 0x05, 0x00,        // 0100
+        LXI_D, 0x12, 0x01, MVI_C, 9, CALL, 0x05, 0x00,  // 0107
+        JMP, 0x00, 0x00,                                // 010f: warm boot
+        b'0', b'8', b'0', b'$',                         // 0112
+    ];
+    let mut cpm = Cpm::new(&program);
+    assert_eq!(cpm.run().unwrap(), "8080");
+}
+
+#[test]
+fn bdos_limits() {
+    // A string without a $: the whole memory but this program, its stack and the BDOS
+    let mut program = vec![LXI_D, 0x00, 0x02, MVI_C, 9, CALL, 0x05, 0x00, JMP, 0x00, 0x00];
+    program.resize(0xf000 - 0x100 - 2, b'x');
+    let mut cpm = Cpm::new(&program);
+    match cpm.run() {
+        Err(EmulatorError::Cpm(message)) => assert!(message.contains("0200"), "{}", message),
+        other => panic!("{:?}", other),
+    }
+
+    // A program that never ends
+    let mut cpm = Cpm::new(&[JMP, 0x00, 0x01]);
+    cpm.set_max_cycles(1000);
+    assert_eq!(cpm.run(), Err(EmulatorError::Cpm("still running after 1000 cycles".into())));
+}
+
+#[test]
+fn cpudiag() {
+    let mut cpm = Cpm::from_file("cpudiag.bin").unwrap();
+    let output = cpm.run().unwrap();
+    assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+}
+
+#[test]
+fn exerciser_8080pre() {
+    if let Some(output) = run_exerciser("8080PRE.COM", DEFAULT_MAX_CYCLES) {
+        assert!(output.contains("8080 Preliminary tests complete"), "{}", output);
+    }
+}
+
+#[test]
+fn exerciser_tst8080() {
+    if let Some(output) = run_exerciser("TST8080.COM", DEFAULT_MAX_CYCLES) {
+        assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+    }
+}
+
+#[test]
+fn exerciser_cputest() {
+    if let Some(output) = run_exerciser("CPUTEST.COM", DEFAULT_MAX_CYCLES) {
+        assert!(output.contains("CPU TESTS OK"), "{}", output);
+    }
+}
+
+/// 8080EXM computes a CRC of the results of each group of instructions and compares
+/// it with the one from a real 8080, printing "ERROR" for any mismatch. It runs for
+/// billions of cycles, so it's only run with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn exerciser_8080exm() {
+    if let Some(output) = run_exerciser("8080EXM.COM", 30_000_000_000) {
+        assert!(output.contains("Tests complete"), "{}", output);
+        assert!(! output.contains("ERROR"), "{}", output);
+    }
+}
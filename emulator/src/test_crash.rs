@@ -0,0 +1,54 @@
+use crate::crash::{PortAccess, StackEntry};
+use crate::error::EmulatorError;
+use crate::snapshot::Snapshot;
+use crate::symbols::{Symbol, SymbolKind, Symbols};
+use crate::test_util::emulator_with;
+
+const PROGRAM: &str = "
+        ORG     0
+        LXI     SP,2400H
+        MVI     A,5             ; 0003
+        OUT     3               ; 0005
+        CALL    READ            ; 0007
+        HLT                     ; 000A
+READ:   IN      1               ; 000B
+        IN      7               ; 000D
+";
+
+#[test]
+fn crash_dump() {
+    let mut emulator = emulator_with(PROGRAM);
+    emulator.set_history_size(4);
+    let error = loop {
+        if let Err(e) = emulator.step() {
+            break e;
+        }
+    };
+    assert!(matches!(error, EmulatorError::UnsupportedInPort { port: 7, pc: 0x0d, .. }));
+
+    let dump = emulator.crash_dump(&error);
+    let pcs: Vec<u16> = dump.history.iter().map(|entry| entry.instruction.address).collect();
+    assert_eq!(pcs, [0x05, 0x07, 0x0b, 0x0d]);
+    let ports: Vec<(PortAccess, u8, u16)> = dump.ports.iter().map(|event| (event.access, event.port, event.pc)).collect();
+    assert_eq!(ports, [(PortAccess::Out, 3, 0x05), (PortAccess::In, 1, 0x0b)]);
+    assert_eq!(dump.ports[0].value, 5);
+    assert_eq!(dump.stack()[0], StackEntry { address: 0x23fe, value: 0x000a, caller: Some(0x07) });
+
+    let mut symbols = Symbols::new();
+    symbols.insert(Symbol {
+        address: 0x0b, name: "Read".into(), kind: SymbolKind::Code, comment: None
+    });
+    let text = dump.to_text(&symbols);
+    assert!(text.starts_with("; 8080 crash dump\nError: Unsupported IN port 7 (db at 000d)"), "{}", text);
+    assert!(text.contains("\n0000000027 0007: CD 0B 00  CALL    Read "), "{}", text);
+    assert!(text.contains("\n23FE: 000A  returns after 0007: CALL    Read\n"), "{}", text);
+    assert!(text.contains("\n0000000017 0005: OUT 03 = 05\n"), "{}", text);
+
+    // The snapshot of the dump is where the crash happened
+    let file_name = std::env::temp_dir().join("8080-crash-dump.txt");
+    let file_name = file_name.to_str().unwrap();
+    dump.save(file_name, &symbols).unwrap();
+    let snapshot = Snapshot::load(file_name).unwrap();
+    assert_eq!(snapshot, emulator.snapshot());
+    assert_eq!(snapshot.registers.pc, 0x0d);
+}
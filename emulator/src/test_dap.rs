@@ -0,0 +1,168 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::assembler::assemble;
+use crate::dap;
+use crate::source_map::SourceMap;
+use crate::test_assembler::cpudiag_source;
+
+/// The line of cpudiag.asm that starts with `text`, from 1
+fn cpudiag_line(text: &str) -> usize {
+    fs::read_to_string("cpudiag.asm").unwrap().lines().position(|l| l.starts_with(text)).unwrap() + 1
+}
+
+#[test]
+fn source_map() {
+    let listing = fs::read_to_string("cpudiag.lst").unwrap();
+    let map = SourceMap::parse(&listing, false);
+    let cpu = cpudiag_line("CPU:");
+    assert_eq!(map.line(0x100), Some(cpudiag_line("\tJMP\tCPU")));
+    assert_eq!(map.line(0x1ab), Some(cpu));
+    assert_eq!(map.line(0x1ac), None);
+    // The comments before CPU go to it
+    assert_eq!(map.address(cpu - 2), Some((cpu, 0x1ab)));
+    assert_eq!(map.label(0x1ab), Some("cpu"));
+    assert_eq!(map.label_before(0x1ae), Some(("cpu", 0x1ab)));
+
+    // The listing of the assembler maps the same
+    let program = assemble(&cpudiag_source()).unwrap();
+    let assembled = SourceMap::parse(&program.listing, false);
+    for address in 0x100..0x800 {
+        assert_eq!(assembled.line(address), map.line(address), "{:04x}", address);
+    }
+    assert_eq!(assembled.label(0x1ab), Some("CPU"));
+
+    // Lines of the listing itself
+    let map = SourceMap::parse(&listing, true);
+    let line = listing.lines().position(|l| l.starts_with("01AB")).unwrap() + 1;
+    assert_eq!(map.line(0x1ab), Some(line));
+}
+
+/// A client driving the adapter over TCP.
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    seq: u64,
+    events: Vec<Value>,
+}
+
+impl Client {
+    fn read(&mut self) -> Value {
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            match line.trim_end().strip_prefix("Content-Length: ") {
+                Some(value) => length = value.parse().unwrap(),
+                None if line.trim_end().is_empty() => break,
+                None => {},
+            }
+        }
+        let mut body = vec![0; length];
+        self.reader.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// The response to the request, the events before it are kept for `event()`.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let body = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments }).to_string();
+        write!(self.stream, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        loop {
+            let message = self.read();
+            if message["type"] == "response" {
+                assert_eq!(message["request_seq"], self.seq);
+                assert_eq!(message["success"], true, "{}", message);
+                return message["body"].clone();
+            }
+            self.events.push(message);
+        }
+    }
+
+    fn event(&mut self, name: &str) -> Value {
+        if let Some(index) = self.events.iter().position(|e| e["event"] == name) {
+            return self.events.remove(index)["body"].clone();
+        }
+        loop {
+            let message = self.read();
+            if message["event"] == name {
+                return message["body"].clone();
+            }
+        }
+    }
+
+    /// The line and name of each frame
+    fn stack(&mut self) -> Vec<(u64, String)> {
+        let trace = self.request("stackTrace", json!({ "threadId": 1 }));
+        trace["stackFrames"].as_array().unwrap().iter()
+            .map(|f| (f["line"].as_u64().unwrap(), f["name"].as_str().unwrap().to_string()))
+            .collect()
+    }
+}
+
+#[test]
+fn debug_adapter() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let stream = listener.accept().unwrap().0;
+        dap::serve(stream.try_clone().unwrap(), stream).unwrap();
+    });
+    let stream = TcpStream::connect(address).unwrap();
+    let mut client = Client { reader: BufReader::new(stream.try_clone().unwrap()), stream, seq: 0, events: Vec::new() };
+
+    let capabilities = client.request("initialize", json!({ "adapterID": "8080" }));
+    assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+    client.request("launch", json!({
+        "program": "cpudiag.bin", "origin": "100", "listing": "cpudiag.lst", "source": "cpudiag.asm",
+    }));
+    client.event("initialized");
+    let cpu = cpudiag_line("CPU:");
+    let czi = cpudiag_line("CZI:");
+    let breakpoints = client.request("setBreakpoints", json!({
+        "source": { "path": "cpudiag.asm" },
+        "breakpoints": [{ "line": cpu - 2 }, { "line": czi + 1 }, { "line": 100000 }],
+    }));
+    let lines: Vec<&Value> = breakpoints["breakpoints"].as_array().unwrap().iter().map(|b| &b["line"]).collect();
+    assert_eq!(lines, vec![&json!(cpu), &json!(czi + 1), &json!(100000)]);
+    assert_eq!(breakpoints["breakpoints"][2]["verified"], false);
+
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    assert_eq!(client.stack(), vec![(cpu as u64, "cpu".to_string())]);
+
+    // Several calls deep, each frame on the line of its call
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "breakpoint");
+    let stack = client.stack();
+    assert_eq!(stack[0], ((czi + 1) as u64, "czi".to_string()));
+    assert_eq!(stack[1], ((czi - 4) as u64, "cnzi".to_string()));
+    assert!(stack.len() > 4);
+    assert_eq!(stack.last().unwrap(), &(cpudiag_line("\tCPO\tCPOI") as u64, "c030".to_string()));
+
+    let scopes = client.request("scopes", json!({ "frameId": 0 }));
+    let registers = scopes["scopes"][0]["variablesReference"].clone();
+    let variables = client.request("variables", json!({ "variablesReference": registers }));
+    let value = |name: &str| variables["variables"].as_array().unwrap().iter()
+        .find(|v| v["name"] == name).unwrap()["value"].clone();
+    assert_eq!(value("A"), "00H");
+    assert_eq!(value("PC"), "0315H");
+
+    client.request("next", json!({ "threadId": 1 }));
+    assert_eq!(client.event("stopped")["reason"], "step");
+    assert_eq!(client.stack()[0].0, (czi + 2) as u64);
+    client.request("stepOut", json!({ "threadId": 1 }));
+    client.event("stopped");
+    assert_eq!(client.stack()[0], ((czi - 3) as u64, "cnzi".to_string()));
+
+    let value = client.request("setVariable", json!({ "variablesReference": registers, "name": "A", "value": "17" }));
+    assert_eq!(value["value"], "17H");
+    assert_eq!(client.request("evaluate", json!({ "expression": "[0100]" }))["result"], "0C3H");
+
+    client.request("disconnect", json!({}));
+    server.join().unwrap();
+}
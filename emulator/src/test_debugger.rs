@@ -0,0 +1,208 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::debugger::{Condition, Debugger, Stop};
+use crate::emulator::Emulator;
+use crate::gdb;
+use crate::test_util::emulator_with;
+
+const PROGRAM: &str = "
+        ORG     0
+        LXI     SP,100H
+        MVI     A,0
+LOOP:   CALL    INCR            ; 0005
+        CPI     5               ; 0008
+        JNZ     LOOP            ; 000A
+        HLT                     ; 000D
+INCR:   INR     A               ; 000E
+        CALL    NOTHING         ; 000F
+        RET                     ; 0012
+NOTHING: RET                    ; 0013
+";
+
+fn debugger() -> Debugger {
+    let mut result = Debugger::new(emulator_with(PROGRAM));
+    result.set_interrupts(false);
+    result
+}
+
+#[test]
+fn breakpoints() {
+    let mut debugger = debugger();
+    debugger.add_breakpoint(0x0e, Some(Condition::parse("A == 3 && [0005] == CD").unwrap()));
+    assert_eq!(debugger.run(None).unwrap(), Stop::Breakpoint(0x0e));
+    assert_eq!(debugger.registers().a, 3);
+    // Resuming doesn't stop on the same breakpoint again right away
+    debugger.add_breakpoint(0x0e, None);
+    assert_eq!(debugger.run(None).unwrap(), Stop::Breakpoint(0x0e));
+    assert_eq!(debugger.registers().a, 4);
+    assert_eq!(debugger.breakpoints().next().unwrap().hits, 1);
+
+    debugger.enable_breakpoint(0x0e, false);
+    assert_eq!(debugger.run(Some(3)).unwrap(), Stop::Limit);
+    assert_eq!(debugger.run(None).unwrap(), Stop::Halted);
+    assert_eq!(debugger.pc(), 0x0e);
+    assert_eq!(debugger.registers().a, 5);
+    assert!(Condition::parse("A = 3").is_err());
+}
+
+#[test]
+fn stepping() {
+    let mut debugger = debugger();
+    assert_eq!(debugger.run_to(0x05, None).unwrap(), Stop::Done);
+    // Over the call
+    assert_eq!(debugger.step_over(None).unwrap(), Stop::Done);
+    assert_eq!((debugger.pc(), debugger.registers().a), (0x08, 1));
+    // Into it, then out of it from the nested call
+    debugger.run_to(0x05, None).unwrap();
+    debugger.step().unwrap();
+    debugger.run_to(0x13, None).unwrap();
+    assert_eq!(debugger.registers().sp, 0xfc);
+    assert_eq!(debugger.step_out(None).unwrap(), Stop::Done);
+    assert_eq!(debugger.pc(), 0x12);
+    assert_eq!(debugger.step_out(None).unwrap(), Stop::Done);
+    assert_eq!((debugger.pc(), debugger.registers().sp), (0x08, 0x100));
+    // A breakpoint in the subroutine stops a step over
+    debugger.run_to(0x05, None).unwrap();
+    debugger.add_breakpoint(0x13, None);
+    assert_eq!(debugger.step_over(None).unwrap(), Stop::Breakpoint(0x13));
+}
+
+#[test]
+fn registers_and_memory() {
+    let mut debugger = debugger();
+    debugger.set_register("hl", 0x2400).unwrap();
+    debugger.set_register("cy", 1).unwrap();
+    assert!(debugger.set_register("x", 1).is_err());
+    debugger.write_memory(0x2400, &[0x41, 0x42]);
+    assert_eq!(debugger.read_memory(0x2400, 2), vec![0x41, 0x42]);
+    assert!(debugger.memory_view(0x2400, 2).starts_with("2400: 41 42"));
+    let registers = debugger.registers();
+    assert_eq!((registers.h, registers.l, registers.flags & 1), (0x24, 0x00, 1));
+    assert!(debugger.registers_view().contains("lh:2400"));
+
+    let addresses: Vec<u16> = debugger.disassemble_around(0x08, 2, 1).iter().map(|i| i.address).collect();
+    assert_eq!(addresses, vec![0x03, 0x05, 0x08, 0x0a]);
+}
+
+#[test]
+fn same_interrupts_as_frames() {
+    let mut emulator = Emulator::new_space_invaders_from("../space-invaders.rom").unwrap();
+    let mut debugger = Debugger::new(Emulator::new_space_invaders_from("../space-invaders.rom").unwrap());
+    for _ in 0..30 {
+        emulator.run_one_frame().unwrap();
+    }
+    while debugger.frames() < 30 {
+        debugger.step().unwrap();
+    }
+    assert_eq!(debugger.emulator().snapshot(), emulator.snapshot());
+}
+
+/// Send a packet and return the reply, the way GDB does.
+fn rsp(stream: &mut TcpStream, packet: &str) -> String {
+    let checksum = packet.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    stream.write_all(format!("${}#{:02x}", packet, checksum).as_bytes()).unwrap();
+    let mut ack = [0];
+    stream.read_exact(&mut ack).unwrap();
+    assert_eq!(ack[0], b'+');
+    rsp_reply(stream)
+}
+
+fn rsp_reply(stream: &mut TcpStream) -> String {
+    // Byte by byte, so that nothing after the packet is read
+    let mut reader = stream.try_clone().unwrap();
+    let mut bytes = std::iter::from_fn(|| {
+        let mut byte = [0];
+        reader.read_exact(&mut byte).ok().map(|_| byte[0])
+    });
+    assert_eq!(bytes.next(), Some(b'$'));
+    let reply: Vec<u8> = bytes.by_ref().take_while(|b| *b != b'#').collect();
+    let checksum: Vec<u8> = bytes.take(2).collect();
+    let expected = reply.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    assert_eq!(String::from_utf8_lossy(&checksum), format!("{:02x}", expected));
+    stream.write_all(b"+").unwrap();
+    String::from_utf8(reply).unwrap()
+}
+
+#[test]
+fn gdb_remote_protocol() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut debugger = debugger();
+        gdb::serve(&mut debugger, listener.accept().unwrap().0).unwrap();
+        debugger.pc()
+    });
+    let mut stream = TcpStream::connect(address).unwrap();
+
+    // A packet with a wrong checksum is refused, GDB sends it again
+    stream.write_all(b"$?#00").unwrap();
+    let mut ack = [0];
+    stream.read_exact(&mut ack).unwrap();
+    assert_eq!(ack[0], b'-');
+
+    assert!(rsp(&mut stream, "qSupported:multiprocess+;swbreak+").contains("qXfer:features:read+"));
+    let target = rsp(&mut stream, "qXfer:features:read:target.xml:0,1000");
+    assert!(target.starts_with("l<?xml") && ! target.contains("<architecture>"), "{}", target);
+    assert_eq!(rsp(&mut stream, "qXfer:features:read:target.xml:1,ffffffffffffffff"), "E01");
+    assert_eq!(rsp(&mut stream, "?"), "S05");
+    assert_eq!(rsp(&mut stream, "vMustReplyEmpty"), "");
+    // AF, BC, DE, HL, SP, PC
+    assert_eq!(rsp(&mut stream, "g"), "020000000000000000000000");
+    assert_eq!(rsp(&mut stream, "m5,3"), "cd0e00");
+    assert_eq!(rsp(&mut stream, "m0,10000").len(), 2 * 0x10000);
+    assert_eq!(rsp(&mut stream, "m0,ffffffff"), "E01");
+
+    assert_eq!(rsp(&mut stream, "Z0,e,1"), "OK");
+    assert_eq!(rsp(&mut stream, "c"), "S05");
+    assert_eq!(rsp(&mut stream, "p5"), "0e00");
+    assert_eq!(rsp(&mut stream, "p4"), "fe00");
+    assert_eq!(rsp(&mut stream, "m00fe,2"), "0800");
+    assert_eq!(rsp(&mut stream, "s"), "S05");
+    assert_eq!(rsp(&mut stream, "g"), "0201000000000000fe000f00");
+    assert_eq!(rsp(&mut stream, "c"), "S05");
+    assert!(rsp(&mut stream, "p0").ends_with("01"));
+    assert_eq!(rsp(&mut stream, "z0,e,1"), "OK");
+
+    assert_eq!(rsp(&mut stream, "P3=0024"), "OK");
+    assert_eq!(rsp(&mut stream, "M2400,2:4142"), "OK");
+    assert_eq!(rsp(&mut stream, "m2400,2"), "4142");
+    assert_eq!(rsp(&mut stream, "G020000000000004200020001"), "OK");
+    assert_eq!(rsp(&mut stream, "g"), "020000000000004200020001");
+    assert_eq!(rsp(&mut stream, "P9=0000"), "E01");
+
+    // An endless loop, until Ctrl-C
+    assert_eq!(rsp(&mut stream, "M0100,3:c30001"), "OK");
+    stream.write_all(b"$c#63").unwrap();
+    let mut ack = [0];
+    stream.read_exact(&mut ack).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(&[0x03]).unwrap();
+    assert_eq!(rsp_reply(&mut stream), "S02");
+
+    // MVI A,7 and STA 2400H with a write watchpoint
+    assert_eq!(rsp(&mut stream, "M0110,5:3e07320024"), "OK");
+    assert_eq!(rsp(&mut stream, "P5=1001"), "OK");
+    assert_eq!(rsp(&mut stream, "Z2,2400,1"), "OK");
+    assert_eq!(rsp(&mut stream, "c"), "T05watch:2400;");
+    assert_eq!(rsp(&mut stream, "m2400,1"), "07");
+    assert_eq!(rsp(&mut stream, "z2,2400,1"), "OK");
+    assert_eq!(rsp(&mut stream, "Z2,2,ffffffffffffffff"), "E01");
+    assert_eq!(rsp(&mut stream, "P5=0001"), "OK");
+
+    // IN 7, which isn't supported: the message of the error, then SIGILL
+    assert_eq!(rsp(&mut stream, "M0120,2:db07"), "OK");
+    assert_eq!(rsp(&mut stream, "P5=2001"), "OK");
+    let output = rsp(&mut stream, "s");
+    let message = (1..output.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&output[i..i + 2], 16).unwrap() as char).collect::<String>();
+    assert!(output.starts_with('O') && message.starts_with("Unsupported IN port 7"), "{}", message);
+    assert_eq!(rsp_reply(&mut stream), "S04");
+    assert_eq!(rsp(&mut stream, "P5=0001"), "OK");
+
+    assert_eq!(rsp(&mut stream, "D"), "OK");
+    let pc = server.join().unwrap();
+    assert!((0x100..0x103).contains(&pc));
+}
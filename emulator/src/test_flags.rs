@@ -0,0 +1,261 @@
+//! Flag results of every opcode. The ALU opcodes are checked against a table of
+//! operands and expected results, and every other opcode must leave the flags alone.
+
+use crate::emulator::Emulator;
+use crate::opcodes::*;
+use crate::test_util::emulator_with_bytes;
+
+/// Flags are written as the letters of the ones that are set, in PSW order:
+/// S(ign), Z(ero), A(uxiliary carry), P(arity), C(arry).
+fn flags(letters: &str) -> u8 {
+    letters.chars().fold(0b10, |result, letter| result | match letter {
+        'S' => 0x80,
+        'Z' => 0x40,
+        'A' => 0x10,
+        'P' => 0x04,
+        'C' => 0x01,
+        _ => panic!("Unknown flag {}", letter),
+    })
+}
+
+/// Address that HL points to for the `_M` opcodes.
+const M: usize = 0x2000;
+
+/// Run `opcode` once. `operand` goes into the register (or memory) the opcode reads, or
+/// into its immediate byte. Returns the emulator after the step.
+fn run(opcode: u8, a: u8, operand: u8, flags_in: &str) -> Emulator {
+    let mut emulator = emulator_with_bytes(&[opcode, operand], 0);
+    emulator.write_memory(M as u16, operand);
+    let state = emulator.state_mut();
+    state.psw.a = a;
+    state.psw.set_flags(flags(flags_in));
+    state.h = (M >> 8) as u8;
+    state.l = M as u8;
+    state.sp = 0x2400;
+    // The register operand is encoded in the low three bits (ALU ops) or in bits 3-5 (INR/DCR)
+    let register = if opcode < 0x40 { (opcode >> 3) & 7 } else { opcode & 7 };
+    if (0x80..0xc0).contains(&opcode) || (opcode < 0x40 && (opcode & 0x06) == 0x04) {
+        match register {
+            0 => state.b = operand,
+            1 => state.c = operand,
+            2 => state.d = operand,
+            3 => state.e = operand,
+            4 => state.h = operand,
+            5 => state.l = operand,
+            6 => {},
+            _ => state.psw.a = operand,
+        }
+    }
+    emulator.step().unwrap();
+    emulator
+}
+
+/// The value of the register (or memory) that `opcode` writes.
+fn result(emulator: &mut Emulator, opcode: u8) -> u8 {
+    let state = emulator.state_mut();
+    if opcode < 0x40 && (opcode & 0x06) == 0x04 {
+        match (opcode >> 3) & 7 {
+            0 => state.b,
+            1 => state.c,
+            2 => state.d,
+            3 => state.e,
+            4 => state.h,
+            5 => state.l,
+            6 => emulator.memory().read(M),
+            _ => state.psw.a,
+        }
+    } else {
+        state.psw.a
+    }
+}
+
+struct Case {
+    opcode: u8,
+    a: u8,
+    operand: u8,
+    flags_in: &'static str,
+    result: u8,
+    flags_out: &'static str,
+}
+
+fn c(opcode: u8, a: u8, operand: u8, flags_in: &'static str, result: u8, flags_out: &'static str)
+        -> Case {
+    Case { opcode, a, operand, flags_in, result, flags_out }
+}
+
+fn alu_cases() -> Vec<Case> {
+    vec![
+        c(ADD_B, 0x6c, 0x2e, "", 0x9a, "SAP"),
+        c(ADD_C, 0xff, 0x01, "", 0x00, "ZAPC"),
+        c(ADD_D, 0x0f, 0x01, "C", 0x10, "A"),
+        c(ADD_E, 0x3e, 0x3e, "", 0x7c, "A"),
+        c(ADD_H, 0x00, 0x01, "SZAPC", 0x01, ""),
+        c(ADD_L, 0x80, 0x80, "C", 0x00, "ZPC"),
+        c(ADD_M, 0x08, 0x10, "", 0x18, "P"),
+        c(ADD_A, 0x12, 0x12, "C", 0x24, "P"),
+        c(ADC_B, 0x7f, 0x7f, "", 0xfe, "SA"),
+        c(ADC_C, 0x00, 0x00, "C", 0x01, ""),
+        c(ADC_D, 0x10, 0x01, "", 0x11, "P"),
+        c(ADC_E, 0x6c, 0x2e, "", 0x9a, "SAP"),
+        c(ADC_H, 0xff, 0x01, "", 0x00, "ZAPC"),
+        c(ADC_L, 0x0f, 0x01, "C", 0x11, "AP"),
+        c(ADC_M, 0x3e, 0x3e, "", 0x7c, "A"),
+        c(ADC_A, 0x00, 0x00, "SZAPC", 0x01, ""),
+        c(SUB_B, 0x80, 0x80, "C", 0x00, "ZAP"),
+        c(SUB_C, 0x08, 0x10, "", 0xf8, "SAC"),
+        c(SUB_D, 0x12, 0x34, "C", 0xde, "SPC"),
+        c(SUB_E, 0x7f, 0x7f, "", 0x00, "ZAP"),
+        c(SUB_H, 0x00, 0x00, "C", 0x00, "ZAP"),
+        c(SUB_L, 0x10, 0x01, "", 0x0f, "P"),
+        c(SUB_M, 0x6c, 0x2e, "", 0x3e, ""),
+        c(SUB_A, 0xff, 0xff, "", 0x00, "ZAP"),
+        c(SBB_B, 0x0f, 0x01, "C", 0x0d, "A"),
+        c(SBB_C, 0x3e, 0x3e, "", 0x00, "ZAP"),
+        c(SBB_D, 0x00, 0x01, "SZAPC", 0xfe, "SC"),
+        c(SBB_E, 0x80, 0x80, "C", 0xff, "SPC"),
+        c(SBB_H, 0x08, 0x10, "", 0xf8, "SAC"),
+        c(SBB_L, 0x12, 0x34, "C", 0xdd, "SPC"),
+        c(SBB_M, 0x7f, 0x7f, "", 0x00, "ZAP"),
+        c(SBB_A, 0x00, 0x00, "C", 0xff, "SPC"),
+        c(ANA_B, 0x10, 0x01, "", 0x00, "ZP"),
+        c(ANA_C, 0x6c, 0x2e, "", 0x2c, "A"),
+        c(ANA_D, 0xff, 0x01, "", 0x01, "A"),
+        c(ANA_E, 0x0f, 0x01, "C", 0x01, "A"),
+        c(ANA_H, 0x3e, 0x3e, "", 0x3e, "A"),
+        c(ANA_L, 0x00, 0x01, "SZAPC", 0x00, "ZP"),
+        c(ANA_M, 0x80, 0x80, "C", 0x80, "S"),
+        c(ANA_A, 0x08, 0x08, "", 0x08, "A"),
+        c(XRA_B, 0x12, 0x34, "C", 0x26, ""),
+        c(XRA_C, 0x7f, 0x7f, "", 0x00, "ZP"),
+        c(XRA_D, 0x00, 0x00, "C", 0x00, "ZP"),
+        c(XRA_E, 0x10, 0x01, "", 0x11, "P"),
+        c(XRA_H, 0x6c, 0x2e, "", 0x42, "P"),
+        c(XRA_L, 0xff, 0x01, "", 0xfe, "S"),
+        c(XRA_M, 0x0f, 0x01, "C", 0x0e, ""),
+        c(XRA_A, 0x3e, 0x3e, "", 0x00, "ZP"),
+        c(ORA_B, 0x00, 0x01, "SZAPC", 0x01, ""),
+        c(ORA_C, 0x80, 0x80, "C", 0x80, "S"),
+        c(ORA_D, 0x08, 0x10, "", 0x18, "P"),
+        c(ORA_E, 0x12, 0x34, "C", 0x36, "P"),
+        c(ORA_H, 0x7f, 0x7f, "", 0x7f, ""),
+        c(ORA_L, 0x00, 0x00, "C", 0x00, "ZP"),
+        c(ORA_M, 0x10, 0x01, "", 0x11, "P"),
+        c(ORA_A, 0x6c, 0x6c, "", 0x6c, "P"),
+        c(CMP_B, 0xff, 0x01, "", 0xff, "SA"),
+        c(CMP_C, 0x0f, 0x01, "C", 0x0f, "A"),
+        c(CMP_D, 0x3e, 0x3e, "", 0x3e, "ZAP"),
+        c(CMP_E, 0x00, 0x01, "SZAPC", 0x00, "SPC"),
+        c(CMP_H, 0x80, 0x80, "C", 0x80, "ZAP"),
+        c(CMP_L, 0x08, 0x10, "", 0x08, "SAC"),
+        c(CMP_M, 0x12, 0x34, "C", 0x12, "SPC"),
+        c(CMP_A, 0x7f, 0x7f, "", 0x7f, "ZAP"),
+        c(ADI, 0x00, 0x00, "C", 0x00, "ZP"),
+        c(ADI, 0x10, 0x01, "", 0x11, "P"),
+        c(ACI, 0x6c, 0x2e, "", 0x9a, "SAP"),
+        c(ACI, 0xff, 0x01, "", 0x00, "ZAPC"),
+        c(SUI, 0x0f, 0x01, "C", 0x0e, "A"),
+        c(SUI, 0x3e, 0x3e, "", 0x00, "ZAP"),
+        c(SBI, 0x00, 0x01, "SZAPC", 0xfe, "SC"),
+        c(SBI, 0x80, 0x80, "C", 0xff, "SPC"),
+        c(ANI, 0x08, 0x10, "", 0x00, "ZAP"),
+        c(ANI, 0x12, 0x34, "C", 0x10, ""),
+        c(XRI, 0x7f, 0x7f, "", 0x00, "ZP"),
+        c(XRI, 0x00, 0x00, "C", 0x00, "ZP"),
+        c(ORI, 0x10, 0x01, "", 0x11, "P"),
+        c(ORI, 0x6c, 0x2e, "", 0x6e, ""),
+        c(CPI, 0xff, 0x01, "", 0xff, "SA"),
+        c(CPI, 0x0f, 0x01, "C", 0x0f, "A"),
+        c(INR_B, 0x55, 0x0f, "", 0x10, "A"),
+        c(DCR_B, 0x55, 0x00, "SZAP", 0xff, "SP"),
+        c(INR_C, 0x55, 0xff, "C", 0x00, "ZAPC"),
+        c(DCR_C, 0x55, 0x10, "C", 0x0f, "PC"),
+        c(INR_D, 0x55, 0x7f, "", 0x80, "SA"),
+        c(DCR_D, 0x55, 0x01, "", 0x00, "ZAP"),
+        c(INR_E, 0x55, 0x00, "SZAP", 0x01, ""),
+        c(DCR_E, 0x55, 0x80, "", 0x7f, ""),
+        c(INR_H, 0x55, 0x10, "C", 0x11, "PC"),
+        c(DCR_H, 0x55, 0x3a, "SZAPC", 0x39, "APC"),
+        c(INR_L, 0x55, 0x01, "", 0x02, ""),
+        c(DCR_L, 0x55, 0x0f, "", 0x0e, "A"),
+        c(INR_M, 0x55, 0x80, "", 0x81, "SP"),
+        c(DCR_M, 0x55, 0xff, "C", 0xfe, "SAC"),
+        c(INR_A, 0x3a, 0x3a, "SZAPC", 0x3b, "C"),
+        c(DCR_A, 0x7f, 0x7f, "", 0x7e, "AP"),
+        c(DAA, 0x9b, 0x00, "", 0x01, "AC"),
+        c(DAA, 0x19, 0x00, "A", 0x1f, ""),
+        c(DAA, 0x99, 0x00, "", 0x99, "SP"),
+        c(DAA, 0x9a, 0x00, "", 0x00, "ZAPC"),
+        c(DAA, 0x00, 0x00, "C", 0x60, "PC"),
+        c(DAA, 0x15, 0x00, "", 0x15, ""),
+        c(RLC, 0x80, 0, "", 0x01, "C"),
+        c(RLC, 0x01, 0, "SZAPC", 0x02, "SZAP"),
+        c(RRC, 0x01, 0, "", 0x80, "C"),
+        c(RRC, 0x02, 0, "SZAPC", 0x01, "SZAP"),
+        c(RAL, 0x80, 0, "C", 0x01, "C"),
+        c(RAL, 0x80, 0, "", 0x00, "C"),
+        c(RAR, 0x01, 0, "", 0x00, "C"),
+        c(RAR, 0x00, 0, "C", 0x80, ""),
+        c(STC, 0x00, 0, "", 0x00, "C"),
+        c(CMC, 0x00, 0, "SZAPC", 0x00, "SZAP"),
+        c(CMC, 0x00, 0, "", 0x00, "C"),
+        c(CMA, 0x51, 0, "SZAPC", 0xae, "SZAPC"),
+        c(CMA, 0x51, 0, "", 0xae, ""),
+    ]
+}
+
+#[test]
+fn alu_flags() {
+    for case in alu_cases() {
+        let mut emulator = run(case.opcode, case.a, case.operand, case.flags_in);
+        let name = OPCODES.get(&case.opcode).unwrap().name;
+        let actual = (result(&mut emulator, case.opcode), emulator.registers().flags);
+        assert_eq!(actual, (case.result, flags(case.flags_out)),
+                   "{} a:{:02x} operand:{:02x} flags:{}", name, case.a, case.operand, case.flags_in);
+    }
+}
+
+#[test]
+fn dad_flags() {
+    // DAD only affects the carry
+    let cases = [
+        (DAD_B, 0xffff, 0x0001, "", 0x0000, "C"),
+        (DAD_D, 0x1234, 0x4321, "SZAPC", 0x5555, "SZAP"),
+        (DAD_H, 0x8000, 0x8000, "", 0x0000, "C"),
+        (DAD_SP, 0x1000, 0xf123, "SZAP", 0x0123, "SZAPC"),
+    ];
+    for (opcode, hl, rp, flags_in, expected_hl, flags_out) in cases.iter() {
+        let mut emulator = emulator_with_bytes(&[*opcode], 0);
+        let state = emulator.state_mut();
+        state.psw.set_flags(flags(flags_in));
+        state.h = (hl >> 8) as u8;
+        state.l = *hl as u8;
+        match *opcode {
+            DAD_B => { state.b = (rp >> 8) as u8; state.c = *rp as u8; },
+            DAD_D => { state.d = (rp >> 8) as u8; state.e = *rp as u8; },
+            DAD_SP => state.sp = *rp,
+            _ => {},
+        }
+        emulator.step().unwrap();
+        let registers = emulator.registers();
+        assert_eq!((((registers.h as usize) << 8) | registers.l as usize, registers.flags),
+                   (*expected_hl, flags(flags_out)), "{:02x}", opcode);
+    }
+}
+
+#[test]
+fn other_opcodes_leave_flags_alone() {
+    let alu: Vec<u8> = alu_cases().iter().map(|c| c.opcode)
+        .chain(vec![DAD_B, DAD_D, DAD_H, DAD_SP, POP_PSW])
+        .collect();
+    for opcode in 0..=0xff_u8 {
+        if alu.contains(&opcode) {
+            continue;
+        }
+        for flags_in in ["", "SZAPC", "ZP", "SAC"].iter() {
+            // 3 is a valid port for both IN and OUT
+            let emulator = run(opcode, 0x42, 3, flags_in);
+            assert_eq!(emulator.registers().flags, flags(flags_in),
+                       "{} changed the flags", OPCODES.get(&opcode).unwrap().name);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use std::sync::{Arc, Mutex};
+
+use crate::debugger::{Debugger, Stop};
+use crate::hooks::{Access, MemoryEvent};
+use crate::test_util::{emulator_with, run_until_halted};
+
+const PROGRAM: &str = "
+        ORG     0
+        LXI     SP,100H
+        MVI     B,3
+LOOP:   LDA     LIVES           ; 0005
+        DCR     A               ; 0008
+        STA     LIVES           ; 0009
+        CALL    COUNT           ; 000C
+        DCR     B               ; 000F
+        JNZ     LOOP            ; 0010
+        HLT                     ; 0013
+COUNT:  RET                     ; 0014
+LIVES:  DB      3               ; 0015
+";
+
+#[test]
+fn hooks() {
+    let mut emulator = emulator_with(PROGRAM);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let record = |events: &Arc<Mutex<Vec<MemoryEvent>>>| {
+        let events = events.clone();
+        move |event: &mut MemoryEvent| events.lock().unwrap().push(*event)
+    };
+    emulator.add_hook(Access::Write, 0x15..=0x15, record(&events));
+    let calls = emulator.add_hook(Access::Execute, 0x14..=0x14, record(&events));
+    // The stack
+    let stack = emulator.add_hook(Access::Read, 0xfe..=0xff, record(&events));
+    run_until_halted(&mut emulator);
+    let events = std::mem::take(&mut *events.lock().unwrap());
+    assert_eq!(events.len(), 3 * 4);
+    assert_eq!(events[0..4], [
+        MemoryEvent { access: Access::Write, address: 0x15, value: 2, pc: 0x09 },
+        MemoryEvent { access: Access::Execute, address: 0x14, value: 0xc9, pc: 0x14 },
+        MemoryEvent { access: Access::Read, address: 0xfe, value: 0x0f, pc: 0x14 },
+        MemoryEvent { access: Access::Read, address: 0xff, value: 0x00, pc: 0x14 },
+    ]);
+    assert_eq!(events[8].to_string(), "0009 wrote 00 to 0015");
+    assert!(emulator.remove_hook(calls));
+    assert!(emulator.remove_hook(stack));
+    assert!(! emulator.remove_hook(stack));
+
+    // A cheat: the lives never go down, and reading them always gives 3
+    let mut emulator = emulator_with(PROGRAM);
+    emulator.add_hook(Access::Write, 0x15..=0x15, |event| event.value = 3);
+    run_until_halted(&mut emulator);
+    assert_eq!(emulator.read_memory(0x15), 3);
+    let mut emulator = emulator_with(PROGRAM);
+    emulator.add_hook(Access::Read, 0x15..=0x15, |event| event.value = 3);
+    run_until_halted(&mut emulator);
+    assert_eq!((emulator.read_memory(0x15), emulator.registers().a), (2, 2));
+    // The debuggers' writes aren't hooked
+    emulator.write_memory(0x15, 9);
+    assert_eq!(emulator.read_memory(0x15), 9);
+}
+
+#[test]
+fn watchpoints() {
+    let mut debugger = Debugger::new(emulator_with(PROGRAM));
+    debugger.set_interrupts(false);
+    let id = debugger.add_watchpoint(Access::Write, 0x15..=0x15);
+    debugger.add_watchpoint(Access::Read, 0xfe..=0xff);
+    assert_eq!(debugger.run(None).unwrap(),
+        Stop::Watchpoint(MemoryEvent { access: Access::Write, address: 0x15, value: 2, pc: 0x09 }));
+    // After the instruction
+    assert_eq!(debugger.pc(), 0x0c);
+    assert_eq!(debugger.run(None).unwrap(),
+        Stop::Watchpoint(MemoryEvent { access: Access::Read, address: 0xfe, value: 0x0f, pc: 0x14 }));
+    assert_eq!(debugger.watchpoints().len(), 2);
+    assert!(debugger.remove_watchpoint(id));
+    assert_eq!(debugger.watchpoints().len(), 1);
+    assert!(matches!(debugger.run(None).unwrap(), Stop::Watchpoint(event) if event.pc == 0x14));
+}
@@ -0,0 +1,63 @@
+use crate::monitor::{Monitor, MonitorConfig, ViolationKind};
+use crate::test_util::emulator_with;
+
+fn monitor(source: &str, frames: usize) -> Monitor {
+    let mut emulator = emulator_with(source);
+    emulator.enable_monitor(MonitorConfig { code: 0..=0xff, interrupt_frames: 2, ..MonitorConfig::space_invaders() });
+    for _ in 0..frames {
+        emulator.run_one_frame().unwrap();
+    }
+    emulator.disable_monitor().unwrap()
+}
+
+#[test]
+fn violations() {
+    let monitor = monitor("
+        ORG     0
+        LXI     SP,2400H
+        MVI     A,1             ; 0003
+        STA     0030H           ; 0005
+        JMP     RAM             ; 0008
+
+        ORG     2100H
+RAM:    LXI     SP,3000H
+HERE:   JMP     HERE
+", 5);
+    let kinds: Vec<(ViolationKind, u64)> = monitor.violations().iter()
+        .map(|violation| (violation.kind, violation.frame))
+        .collect();
+    assert_eq!(kinds, [
+        (ViolationKind::RomWrite { address: 0x30, value: 1 }, 0),
+        (ViolationKind::Pc(0x2100), 0),
+        (ViolationKind::Sp(0x3000), 0),
+        (ViolationKind::Interrupts(3), 2),
+    ]);
+    assert_eq!(monitor.frames(), 5);
+    assert_eq!(monitor.total(), 4);
+    let violation = &monitor.violations()[0];
+    assert_eq!(violation.registers.pc, 0x08);
+    assert_eq!(violation.context.iter().map(|entry| entry.instruction.address).collect::<Vec<_>>(), [0, 3, 5]);
+    let summary = monitor.summary();
+    assert!(summary.starts_with("Frames: 5\nViolations: 4\n  PC outside of the code: 1\n"), "{}", summary);
+    assert!(summary.contains("\nFrame 0, cycle 30: Write of 01 to the ROM at 0030\n"), "{}", summary);
+}
+
+#[test]
+fn no_violations() {
+    let monitor = monitor("
+        ORG     0
+        LXI     SP,2400H
+        EI
+LOOP:   JMP     LOOP
+
+        ORG     8
+        EI
+        RET
+
+        ORG     10H
+        EI
+        RET
+", 5);
+    assert_eq!(monitor.total(), 0);
+    assert!(monitor.violations().is_empty());
+}
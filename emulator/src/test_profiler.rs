@@ -0,0 +1,57 @@
+use crate::assembler::assemble;
+use crate::profiler::RoutineProfile;
+use crate::test_util::{emulator_with_bytes, run_until_halted};
+
+const PROGRAM: &str = "
+        ORG     0
+        LXI     SP,100H         ; 10
+        CALL    OUTER           ; 17
+        MVI     B,2             ; 7
+        CALL    COUNT           ; 17
+        HLT                     ; 7
+OUTER:  CALL    INNER           ; 17
+        CALL    INNER           ; 17
+        RET                     ; 10
+INNER:  NOP                     ; 4
+        RET                     ; 10
+COUNT:  DCR     B               ; 5
+        RZ                      ; 11 when it returns, 5 otherwise
+        CALL    COUNT           ; 17
+        RET                     ; 10
+";
+
+#[test]
+fn profiler() {
+    let program = assemble(PROGRAM).unwrap();
+    let mut emulator = emulator_with_bytes(&program.bytes, program.origin);
+    emulator.enable_profiler();
+    run_until_halted(&mut emulator);
+    let profiler = emulator.disable_profiler().unwrap();
+    let label = |name: &str| program.symbols[name];
+    let routine = |name: &str, calls, inclusive, exclusive|
+        RoutineProfile { address: label(name), calls, inclusive, exclusive };
+
+    // COUNT runs twice, the inner call only counts once in its inclusive cycles
+    assert_eq!(profiler.routines(), vec![
+        routine("OUTER", 1, 72, 44),
+        routine("COUNT", 2, 53, 53),
+        routine("INNER", 2, 28, 28),
+    ]);
+    assert_eq!((profiler.cycles(), profiler.instructions()), (10 + 17 + 7 + 17 + 7 + 72 + 53, 18));
+    let counts: Vec<(u16, u64)> = profiler.addresses().map(|(address, count, _)| (address, count)).collect();
+    assert_eq!(counts[counts.len() - 4..], [(label("COUNT"), 2), (label("COUNT") + 1, 2),
+        (label("COUNT") + 2, 1), (label("COUNT") + 5, 1)]);
+
+    let names = |address: u16| program.symbols.iter()
+        .find(|(_, value)| **value == address)
+        .map(|(name, _)| name.to_lowercase());
+    assert_eq!(profiler.folded(&names), "\
+        root 58\n\
+        root;count 37\n\
+        root;count;count 16\n\
+        root;outer 44\n\
+        root;outer;inner 28\n");
+    let report = profiler.report(&names, 3);
+    assert!(report.starts_with("183 cycles, 18 instructions, 0 cycles halted\n"));
+    assert!(report.contains("         1           72  39.34           44  24.04  000c outer\n"));
+}
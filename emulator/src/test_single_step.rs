@@ -0,0 +1,110 @@
+//! Runs the single-step vectors in `single_step/`: one file per opcode, each holding a list
+//! of tests with an initial state (registers and the RAM bytes that matter), the state
+//! after executing exactly one instruction, and the number of cycles it takes:
+//!
+//! ```json
+//! { "name": "c4 9ab7",
+//!   "initial": { "pc": 39607, "sp": 35936, "a": 97, "b": 34, "c": 195, "d": 175, "e": 175,
+//!                "f": 23, "h": 252, "l": 110, "inte": 0, "ram": [[39607, 196], ...] },
+//!   "final": { ... },
+//!   "cycles": 17 }
+//! ```
+//!
+//! `f` is the low byte of PSW and `inte` the interrupt enable flip-flop.
+
+use serde_json::Value;
+use crate::emulator::Emulator;
+use crate::memory::Memory;
+use crate::state::State;
+
+const REGISTERS: [&str; 11] = ["pc", "sp", "a", "b", "c", "d", "e", "f", "h", "l", "inte"];
+
+fn field(state: &Value, name: &str) -> usize {
+    state[name].as_u64().unwrap_or_else(|| panic!("Missing field {}", name)) as usize
+}
+
+fn ram(state: &Value) -> Vec<(usize, u8)> {
+    state["ram"].as_array().unwrap().iter()
+        .map(|pair| (pair[0].as_u64().unwrap() as usize, pair[1].as_u64().unwrap() as u8))
+        .collect()
+}
+
+fn register(state: &State, name: &str) -> usize {
+    match name {
+        "pc" => state.pc,
+        "sp" => state.sp,
+        "a" => state.psw.a as usize,
+        "b" => state.b as usize,
+        "c" => state.c as usize,
+        "d" => state.d as usize,
+        "e" => state.e as usize,
+        "f" => (state.psw.value() & 0xff) as usize,
+        "h" => state.h as usize,
+        "l" => state.l as usize,
+        "inte" => state.enable_interrupts as usize,
+        _ => panic!("Unknown register {}", name),
+    }
+}
+
+/// Run one vector and return the differences with its expected final state.
+fn run_vector(test: &Value) -> Vec<String> {
+    let initial = &test["initial"];
+    let mut memory = Memory::new();
+    for (address, value) in ram(initial) {
+        memory.write(address, value);
+    }
+    let mut emulator = Emulator::new(Box::new(memory), field(initial, "pc"));
+    let state = emulator.state_mut();
+    state.sp = field(initial, "sp");
+    state.psw.a = field(initial, "a") as u8;
+    state.b = field(initial, "b") as u8;
+    state.c = field(initial, "c") as u8;
+    state.d = field(initial, "d") as u8;
+    state.e = field(initial, "e") as u8;
+    state.psw.set_flags(field(initial, "f") as u8);
+    state.h = field(initial, "h") as u8;
+    state.l = field(initial, "l") as u8;
+    state.enable_interrupts = field(initial, "inte") != 0;
+
+    let mut result = Vec::new();
+    let cycles = match emulator.step() {
+        Ok(step) => step.cycles as usize,
+        Err(e) => return vec![e.to_string()],
+    };
+
+    let expected = &test["final"];
+    for name in REGISTERS.iter() {
+        let (expected, actual) = (field(expected, name), register(emulator.state_mut(), name));
+        if expected != actual {
+            result.push(format!("{}: expected {:02x}, got {:02x}", name, expected, actual));
+        }
+    }
+    for (address, value) in ram(expected) {
+        let actual = emulator.memory().read(address);
+        if value != actual {
+            result.push(format!("ram[{:04x}]: expected {:02x}, got {:02x}", address, value, actual));
+        }
+    }
+    let expected_cycles = field(test, "cycles");
+    if cycles != expected_cycles {
+        result.push(format!("cycles: expected {}, got {}", expected_cycles, cycles));
+    }
+    result
+}
+
+#[test]
+fn single_step() {
+    let mut failures = Vec::new();
+    for opcode in 0..=0xff {
+        let file_name = format!("single_step/{:02x}.json", opcode);
+        let content = std::fs::read_to_string(&file_name).unwrap();
+        let tests: Value = serde_json::from_str(&content).unwrap();
+        for test in tests.as_array().unwrap() {
+            let diffs = run_vector(test);
+            if ! diffs.is_empty() {
+                failures.push(format!("{}: {}", test["name"].as_str().unwrap(), diffs.join(", ")));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{} failures:\n{}", failures.len(), failures.join("\n"));
+}
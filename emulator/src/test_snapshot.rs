@@ -0,0 +1,157 @@
+use crate::emulator::Emulator;
+use crate::error::EmulatorError;
+use crate::movie::Movie;
+use crate::opcodes::*;
+use crate::rewind::RewindConfig;
+use crate::snapshot::Snapshot;
+use crate::test_util::emulator_with_bytes;
+
+/// A program that keeps changing registers, memory, the shift register and port 3,
+/// depending on the input port 1.
+fn emulator() -> Emulator {
+    let program = [
+        LXI_SP, 0x00, 0x24,
+        MVI_A, 0x12, OUT, 4,    // shift register data
+        MVI_A, 3, OUT, 2,       // shift register offset
+        EI,
+        // 000c:
+        IN, 1, ADD_B, MOV_B_A,
+        OUT, 3,
+        STA, 0x00, 0x24,
+        OUT, 4,
+        JMP, 0x0c, 0x00,
+    ];
+    let mut result = emulator_with_bytes(&program, 0);
+    result.set_rom_set("test");
+    result
+}
+
+fn run(emulator: &mut Emulator, steps: usize) {
+    for _ in 0..steps {
+        emulator.step().unwrap();
+    }
+}
+
+#[test]
+fn restore_resumes_identically() {
+    let mut e = emulator();
+    run(&mut e, 1000);
+    e.shared_state().lock().unwrap().set_bit_in_1(4, true);
+    let snapshot = e.snapshot();
+    assert_eq!(snapshot.registers.pc, e.registers().pc);
+    assert_eq!(snapshot.in_1, 0x18);
+    run(&mut e, 1000);
+    let expected = e.snapshot();
+
+    let mut e2 = emulator();
+    e2.restore(&Snapshot::from_bytes(&snapshot.to_bytes()).unwrap()).unwrap();
+    assert_eq!(e2.snapshot(), snapshot);
+    run(&mut e2, 1000);
+    assert_eq!(e2.snapshot(), expected);
+}
+
+#[test]
+fn invalid_snapshots() {
+    let mut e = emulator();
+    run(&mut e, 100);
+    let bytes = e.snapshot().to_bytes();
+
+    let mut corrupted = bytes.clone();
+    corrupted[0x2000] ^= 1;
+    assert_eq!(Snapshot::from_bytes(&corrupted),
+        Err(EmulatorError::InvalidSnapshot("wrong checksum".into())));
+    assert_eq!(Snapshot::from_bytes(&bytes[..100]),
+        Err(EmulatorError::InvalidSnapshot("wrong checksum".into())));
+    assert_eq!(Snapshot::from_bytes(b"nothing"),
+        Err(EmulatorError::InvalidSnapshot("not a snapshot".into())));
+
+    let mut other = emulator();
+    other.set_rom_set("invaders");
+    let before = other.snapshot();
+    assert_eq!(other.restore(&Snapshot::from_bytes(&bytes).unwrap()),
+        Err(EmulatorError::SnapshotRomMismatch { expected: "invaders".into(), found: "test".into() }));
+    assert_eq!(other.snapshot(), before);
+}
+
+#[test]
+fn long_rom_set_names() {
+    let mut e = emulator();
+    e.set_rom_set(&"x".repeat(300));
+    let snapshot = e.snapshot();
+    assert_eq!(Snapshot::from_bytes(&snapshot.to_bytes()), Ok(snapshot));
+}
+
+#[test]
+fn rewind() {
+    let mut e = emulator();
+    e.enable_rewind(RewindConfig { depth: 5, memory_budget: 1 << 20 });
+    let mut frames = vec![e.snapshot()];
+    for _ in 0..8 {
+        e.run_one_frame().unwrap();
+        frames.push(e.snapshot());
+    }
+    assert_eq!(e.rewind_buffer().unwrap().len(), 5);
+
+    for expected in frames[3..8].iter().rev() {
+        assert!(e.rewind_one_frame().unwrap());
+        assert_eq!(&e.snapshot(), expected);
+    }
+    assert!(!e.rewind_one_frame().unwrap());
+    assert_eq!(e.snapshot(), frames[3]);
+
+    // Resuming from there replays the same frames
+    e.run_one_frame().unwrap();
+    assert_eq!(e.snapshot(), frames[4]);
+    assert!(e.rewind_one_frame().unwrap());
+    assert_eq!(e.snapshot(), frames[3]);
+}
+
+#[test]
+fn rewind_memory_budget() {
+    let mut e = emulator();
+    e.enable_rewind(RewindConfig { depth: 100, memory_budget: 300 });
+    for _ in 0..10 {
+        e.run_one_frame().unwrap();
+    }
+    let buffer = e.rewind_buffer().unwrap();
+    assert!(buffer.size() <= 300);
+    assert!(buffer.len() < 9);
+}
+
+#[test]
+fn movie_replay() {
+    // Press and release a key on a few frames
+    let press = |e: &Emulator, frame: usize| {
+        e.shared_state().lock().unwrap().set_bit_in_1(4, frame % 7 < 3);
+    };
+    let mut e = emulator();
+    for frame in 0..5 {
+        press(&e, frame);
+        e.run_one_frame().unwrap();
+    }
+    e.start_recording(true);
+    for frame in 5..40 {
+        press(&e, frame);
+        e.run_one_frame().unwrap();
+    }
+    let movie = e.stop_recording().unwrap();
+    assert_eq!(movie.frames.len(), 35);
+    assert_eq!(movie.frames[0], (0x08, 0));
+    assert_eq!(movie.frames[2], (0x18, 0));
+    let expected = e.snapshot();
+
+    let movie = Movie::from_bytes(&movie.to_bytes()).unwrap();
+    let mut e2 = emulator();
+    e2.shared_state().lock().unwrap().set_bit_in_1(6, true);
+    e2.play(movie.clone()).unwrap();
+    while !e2.movie_player().unwrap().is_finished() {
+        e2.run_one_frame().unwrap();
+    }
+    assert_eq!(e2.snapshot(), expected);
+
+    let rom_hash = movie.rom_hash;
+    let other_rom = Movie { rom_hash: rom_hash ^ 1, .. movie };
+    assert_eq!(emulator().play(other_rom), Err(EmulatorError::MovieRomMismatch {
+        expected: rom_hash, found: rom_hash ^ 1
+    }));
+}
@@ -0,0 +1,76 @@
+use crate::assembler::assemble;
+use crate::debugger::Debugger;
+use crate::disassembler::Analysis;
+use crate::memory::Memory;
+use crate::symbols::{Symbol, SymbolKind, Symbols};
+use crate::test_util::emulator_with;
+use crate::trace::{RingBuffer, TraceConfig, Tracer};
+
+const SYMBOLS: &str = "
+# Space Invaders
+01EF DrawShieldPl1          ; Draw the shields of player 1
+20c0 isrDelay      data     ; Counted down by the interrupts
+0x08FF DrawChar    code
+";
+
+#[test]
+fn parse() {
+    let symbols = Symbols::parse(SYMBOLS).unwrap();
+    assert_eq!(symbols.len(), 3);
+    assert_eq!(symbols.get(0x20c0), Some(&Symbol {
+        address: 0x20c0, name: "isrDelay".into(), kind: SymbolKind::Data,
+        comment: Some("Counted down by the interrupts".into()),
+    }));
+    assert_eq!(symbols.get(0x08ff).map(|symbol| symbol.kind), Some(SymbolKind::Code));
+    assert_eq!(symbols.address("drawshieldpl1"), Some(0x01ef));
+    assert_eq!(symbols.location(0x01f2).as_deref(), Some("DrawShieldPl1+3"));
+    // The data symbols aren't routines
+    assert_eq!(symbols.location(0x20c1), None);
+    assert_eq!(symbols.location(0x09fe).as_deref(), Some("DrawChar+FF"));
+    assert_eq!(symbols.location(0x0100), None);
+    assert_eq!(symbols.parse_address("DrawShieldPl1+3"), Ok(0x01f2));
+    assert_eq!(symbols.parse_address("1a5f"), Ok(0x1a5f));
+    assert!(symbols.parse_address("Nowhere+1").is_err());
+    assert!(Symbols::parse("01EF DrawShieldPl1 routine").is_err());
+    assert!(Symbols::parse("XYZ DrawShieldPl1").is_err());
+}
+
+#[test]
+fn names_everywhere() {
+    let symbols = Symbols::parse(SYMBOLS).unwrap();
+    let mut memory = Memory::new();
+    memory.read_file("../space-invaders.rom", 0).unwrap();
+    assert_eq!(memory.disassemble(0x07cb, &symbols).0.trim_end(), "07cb: cd ef 01   CALL    DrawShieldPl1");
+    assert_eq!(memory.disassemble(0x01f2, &symbols).0.trim_end(), "01f2 <DrawShieldPl1+3>: c3 f8 01   JMP     01F8H");
+
+    // The disassembly defines the symbols, and still assembles to the ROM
+    let rom = std::fs::read("../space-invaders.rom").unwrap();
+    let mut analysis = Analysis::new(&rom, 0, &[0, 8, 0x10]);
+    analysis.set_symbols(symbols.clone());
+    let listing = analysis.listing();
+    assert!(listing.starts_with("isrDelay        EQU     20C0H ; Counted down by the interrupts\n"));
+    assert!(listing.contains("\nDrawShieldPl1: ; Draw the shields of player 1\n"));
+    assert!(listing.contains("        STA     isrDelay                ; 08A6  32 C0 20\n"));
+    assert_eq!(assemble(&listing).unwrap().bytes, rom);
+
+    // Traces
+    let mut emulator = emulator_with("
+        ORG     0
+        CALL    01EFH
+    ");
+    let ring = RingBuffer::new(1);
+    emulator.enable_trace(Tracer::new(TraceConfig::default(), ring.clone()));
+    emulator.step().unwrap();
+    let entry = &ring.entries()[0];
+    assert!(entry.format(&|address| symbols.label(address)).contains(" CALL    DrawShieldPl1 A=00 "));
+    assert!(entry.to_string().contains(" CALL    01EFH  "));
+
+    // The debugger
+    let mut debugger = Debugger::new(emulator);
+    debugger.set_symbols(symbols);
+    assert_eq!(debugger.parse_address("DrawChar"), Ok(0x08ff));
+    assert_eq!(debugger.describe(0x01ef), "01ef <DrawShieldPl1>");
+    debugger.write_memory(0x20c0, &[3]);
+    assert!(debugger.parse_condition("[isrDelay] == 3 && PC == DrawShieldPl1").unwrap().is_true(debugger.emulator()));
+    assert!(debugger.parse_condition("[isrDelay] == Nothing").is_err());
+}
@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+
+use crate::test_util::{emulator_with, run_until_halted};
+use crate::trace::{parse_range, RingBuffer, TraceConfig, TraceEntry, Tracer};
+use crate::trace_diff::{Expected, TraceDiff};
+
+const PROGRAM: &str = "
+        ORG     0
+        LXI     SP,100H
+        MVI     A,0
+LOOP:   INR     A               ; 0005
+        CPI     5               ; 0006
+        JNZ     LOOP            ; 0008
+        HLT                     ; 000B
+";
+
+#[test]
+fn line_format() {
+    let mut emulator = emulator_with(PROGRAM);
+    let ring = RingBuffer::new(2);
+    emulator.enable_trace(Tracer::new(TraceConfig::default(), ring.clone()));
+    run_until_halted(&mut emulator);
+    assert_eq!(emulator.tracer().unwrap().traced(), 3 + 5 * 3);
+    let lines: Vec<String> = ring.entries().iter().map(TraceEntry::to_string).collect();
+    assert_eq!(lines, vec![
+        "0000000117 0008: C2 05 00  JNZ     0005H      A=05 BC=0000 DE=0000 HL=0000 SP=0100 F=56 .ZAP.",
+        "0000000127 000B: 76        HLT                A=05 BC=0000 DE=0000 HL=0000 SP=0100 F=56 .ZAP.",
+    ]);
+    assert_eq!(emulator.cycles(), 134);
+}
+
+#[test]
+fn ranges_and_triggers() {
+    let pcs = Arc::new(Mutex::new(Vec::new()));
+    let record = |config: TraceConfig| {
+        let mut emulator = emulator_with(PROGRAM);
+        let sink = pcs.clone();
+        emulator.enable_trace(Tracer::new(config, move |entry: &TraceEntry| {
+            sink.lock().unwrap().push(entry.instruction.address)
+        }));
+        run_until_halted(&mut emulator);
+        emulator.disable_trace().unwrap().finish().unwrap();
+        std::mem::take(&mut *pcs.lock().unwrap())
+    };
+    let ranges = vec![parse_range("6-7").unwrap(), parse_range("0b").unwrap()];
+    assert_eq!(record(TraceConfig { ranges, .. Default::default() }), vec![6, 6, 6, 6, 6, 0x0b]);
+    assert_eq!(record(TraceConfig { start: Some(8), count: Some(4), .. Default::default() }), vec![8, 5, 6, 8]);
+    assert!(parse_range("x-2").is_err());
+}
+
+/// Compare a run of the program with `reference`.
+fn diff(reference: &str) -> TraceDiff {
+    let mut emulator = emulator_with(PROGRAM);
+    let diff = TraceDiff::new(reference, 2);
+    emulator.enable_trace(Tracer::new(TraceConfig::default(), diff.clone()));
+    run_until_halted(&mut emulator);
+    diff
+}
+
+#[test]
+fn trace_diff() {
+    let mut emulator = emulator_with(PROGRAM);
+    let ring = RingBuffer::new(100);
+    emulator.enable_trace(Tracer::new(TraceConfig::default(), ring.clone()));
+    run_until_halted(&mut emulator);
+    let mut lines: Vec<String> = ring.entries().iter().map(TraceEntry::to_string).collect();
+    lines.insert(0, "# A comment".into());
+
+    let same = diff(&lines.join("\n"));
+    assert!(same.is_done());
+    assert_eq!((same.matched(), same.len(), same.divergence()), (18, 18, None));
+
+    // The flags of the fifth instruction
+    let flags = lines[5].find(" F=").unwrap();
+    let actual = u64::from_str_radix(&lines[5][flags + 3..flags + 5], 16).unwrap();
+    lines[5].replace_range(flags..flags + 5, " F=FF");
+    let different = diff(&lines.join("\n"));
+    let divergence = different.divergence().unwrap();
+    assert_eq!((divergence.instructions, divergence.line), (4, 6));
+    assert_eq!(divergence.differences, vec![("F".to_string(), 0xff, actual)]);
+    let history: Vec<u16> = divergence.history.iter().map(|entry| entry.instruction.address).collect();
+    assert_eq!(history, vec![5, 6]);
+    assert!(divergence.to_string().contains(&format!("Different: F expected FF, actual {:X}", actual)));
+
+    // The format of State::disassemble
+    let expected = Expected::parse("a:05 b:00 c:01 d:00 e:00 lh:2400 pc:1a5f sp:23fc [C=1 P=0 S=0 Z=1]").unwrap();
+    assert_eq!(expected.registers[2], ("C".to_string(), 1));
+    assert_eq!(expected.registers[5], ("HL".to_string(), 0x2400));
+    assert_eq!(expected.registers[8], ("CY".to_string(), 1));
+    assert_eq!(Expected::parse("0000000117 0008: C2 05 00  JNZ     0005H").unwrap(),
+        Expected { cycles: Some(117), registers: vec![("PC".to_string(), 8)] });
+    assert_eq!(Expected::parse("  # 0008:"), None);
+}
@@ -0,0 +1,27 @@
+//! What the tests share: emulators running small programs.
+
+use crate::assembler::assemble;
+use crate::emulator::Emulator;
+use crate::memory::Memory;
+
+/// An emulator with `bytes` in its memory from `origin`, starting at 0.
+pub(crate) fn emulator_with_bytes(bytes: &[u8], origin: u16) -> Emulator {
+    let mut memory = Memory::new();
+    for (i, byte) in bytes.iter().enumerate() {
+        memory.write(origin as usize + i, *byte);
+    }
+    Emulator::new(Box::new(memory), 0)
+}
+
+/// An emulator with the program assembled from `source` in its memory, starting at 0.
+pub(crate) fn emulator_with(source: &str) -> Emulator {
+    let program = assemble(source).unwrap();
+    emulator_with_bytes(&program.bytes, program.origin)
+}
+
+/// Step until the program halts.
+pub(crate) fn run_until_halted(emulator: &mut Emulator) {
+    while ! emulator.registers().halted {
+        emulator.step().unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+//! Execution traces, configured at runtime: which instructions are traced and where the
+//! trace goes.
+//!
+//! Each traced instruction is a `TraceEntry`, with the registers before it runs and the
+//! cycles run by the emulator until then. Its line format is stable, so that traces can
+//! be compared with `diff` or with other emulators:
+//!
+//! ```text
+//! 0000042316 1A5F: 3A C0 20  LDA     20C0H      A=00 BC=0000 DE=1B00 HL=2402 SP=2400 F=46 .Z.P.
+//! ```
+//!
+//! The cycle count on 10 digits, PC, the bytes of the instruction, the instruction, then
+//! A, the pairs, SP, and the flags as a byte and as letters (S Z AC P CY, `.` when clear).
+//!
+//! A `FileSink` with symbols names the operands, e.g. `CALL    DrawShieldPl1`.
+//!
+//! The entries go to a `TraceSink`: a file (`FileSink`), a ring buffer of the last ones
+//! (`RingBuffer`) or any closure taking a `&TraceEntry`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use crate::disassembler::Instruction;
+use crate::error::EmulatorError;
+use crate::state::Registers;
+use crate::symbols::Symbols;
+
+/// Which instructions are traced.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TraceConfig {
+    /// Only trace the instructions at these addresses, all of them if it's empty
+    pub ranges: Vec<RangeInclusive<u16>>,
+    /// Start tracing when PC reaches this address, right away if `None`
+    pub start: Option<u16>,
+    /// Stop after tracing this many instructions
+    pub count: Option<u64>,
+}
+
+/// An executed instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// The cycles run by the emulator before this instruction
+    pub cycles: u64,
+    pub instruction: Instruction,
+    /// The registers before the instruction runs
+    pub registers: Registers,
+}
+
+impl TraceEntry {
+    /// The line of the entry, with the operands named by `label`.
+    pub fn format(&self, label: &dyn Fn(u16) -> Option<String>) -> String {
+        let r = &self.registers;
+        let pair = |high: u8, low: u8| (high as u16) << 8 | low as u16;
+        let flags: String = [(7, 'S'), (6, 'Z'), (4, 'A'), (2, 'P'), (0, 'C')].iter()
+            .map(|(bit, letter)| if r.flags >> bit & 1 == 1 { *letter } else { '.' })
+            .collect();
+        let bytes: Vec<String> = self.instruction.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("{:010} {:04X}: {:8}  {:18} A={:02X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} F={:02X} {}",
+            self.cycles, self.instruction.address, bytes.join(" "), self.instruction.format(label),
+            r.a, pair(r.b, r.c), pair(r.d, r.e), pair(r.h, r.l), r.sp, r.flags, flags)
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format(&|_| None))
+    }
+}
+
+/// Where the trace goes.
+pub trait TraceSink: Send {
+    fn record(&mut self, entry: &TraceEntry);
+
+    /// Called when the tracing is over.
+    fn finish(&mut self) -> Result<(), EmulatorError> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&TraceEntry) + Send> TraceSink for F {
+    fn record(&mut self, entry: &TraceEntry) {
+        self(entry)
+    }
+}
+
+/// Writes one line per entry to a file.
+pub struct FileSink {
+    file_name: String,
+    writer: BufWriter<File>,
+    symbols: Symbols,
+    /// The first write error, reported by `finish()`
+    error: Option<String>,
+}
+
+impl FileSink {
+    pub fn create(file_name: &str) -> Result<FileSink, EmulatorError> {
+        let file = File::create(file_name)
+            .map_err(|e| EmulatorError::Io { file_name: file_name.into(), message: e.to_string() })?;
+        Ok(FileSink { file_name: file_name.into(), writer: BufWriter::new(file), symbols: Symbols::new(), error: None })
+    }
+
+    /// Show the operands with their names in `symbols`.
+    pub fn with_symbols(self, symbols: Symbols) -> FileSink {
+        FileSink { symbols, ..self }
+    }
+}
+
+impl TraceSink for FileSink {
+    fn record(&mut self, entry: &TraceEntry) {
+        if self.error.is_none() {
+            let symbols = &self.symbols;
+            let label = |address: u16| symbols.label(address);
+            if let Err(e) = writeln!(self.writer, "{}", entry.format(&label)) {
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), EmulatorError> {
+        let result = self.writer.flush().map_err(|e| e.to_string());
+        match self.error.take().map_or(result, Err) {
+            Ok(()) => Ok(()),
+            Err(message) => Err(EmulatorError::Io { file_name: self.file_name.clone(), message }),
+        }
+    }
+}
+
+/// Keeps the last entries. It's a handle: keep a clone to read the entries while the
+/// emulator owns the other one.
+#[derive(Clone)]
+pub struct RingBuffer {
+    capacity: usize,
+    entries: Arc<Mutex<VecDeque<TraceEntry>>>,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer { capacity, entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))) }
+    }
+
+    /// The entries, the oldest first.
+    pub fn entries(&self) -> Vec<TraceEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl TraceSink for RingBuffer {
+    fn record(&mut self, entry: &TraceEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        if self.capacity > 0 {
+            entries.push_back(entry.clone());
+        }
+    }
+}
+
+/// A `TraceConfig` and its sink, given to `Emulator::enable_trace()`.
+pub struct Tracer {
+    config: TraceConfig,
+    sink: Box<dyn TraceSink>,
+    started: bool,
+    traced: u64,
+}
+
+impl Tracer {
+    pub fn new(config: TraceConfig, sink: impl TraceSink + 'static) -> Tracer {
+        let started = config.start.is_none();
+        Tracer { config, sink: Box::new(sink), started, traced: 0 }
+    }
+
+    /// Number of instructions traced so far
+    pub fn traced(&self) -> u64 {
+        self.traced
+    }
+
+    /// Whether the instruction at `pc` is traced, which starts the trace at the start
+    /// address.
+    pub(crate) fn wants(&mut self, pc: u16) -> bool {
+        if ! self.started {
+            self.started = self.config.start == Some(pc);
+        }
+        self.started
+            && self.config.count.is_none_or(|count| self.traced < count)
+            && (self.config.ranges.is_empty() || self.config.ranges.iter().any(|range| range.contains(&pc)))
+    }
+
+    pub(crate) fn record(&mut self, entry: &TraceEntry) {
+        self.traced += 1;
+        self.sink.record(entry);
+    }
+
+    /// Flush the sink, e.g. the buffer of a file.
+    pub fn finish(mut self) -> Result<(), EmulatorError> {
+        self.sink.finish()
+    }
+}
+
+/// Parse a range of addresses in hexadecimal, "1439-1447", or a single one.
+pub fn parse_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let number = |s: &str| u16::from_str_radix(s.trim().trim_start_matches("0x"), 16)
+        .map_err(|_| format!("Invalid range: {}", s));
+    match s.split_once('-') {
+        Some((first, last)) => Ok(number(first)?..=number(last)?),
+        None => number(s).map(|address| address..=address),
+    }
+}
@@ -0,0 +1,218 @@
+//! Compares the instructions the emulator executes with a reference trace, e.g. the log
+//! of another emulator, and stops at the first divergence.
+//!
+//! The reference has one line per executed instruction, with the state before it runs.
+//! A line is made of whitespace separated fields, the ones it doesn't have aren't
+//! compared:
+//! - `NAME=VALUE` or `NAME:VALUE`, the value in hexadecimal, for the registers (`A` to
+//!   `L`, `F` for the flags byte), the pairs (`BC`, `DE`, `HL` or `LH`, `SP`, `PC`) and the
+//!   flags (`S`, `Z`, `AC`, `P`, `CY`). Between brackets `C` is the carry, as in the output
+//!   of `State::disassemble`: `a:00 b:00 c:00 d:00 e:00 lh:2400 pc:1a5f sp:23fc [C=0 P=1 S=0 Z=1]`,
+//! - `XXXX:`, four hexadecimal digits and a colon, for PC,
+//! - a decimal number at the start of the line for the cycles run before the instruction.
+//!
+//! The other fields, like the bytes and the mnemonic, are ignored, so the lines of
+//! `emulator::trace` are a valid reference. Empty lines and lines starting with `#` are
+//! skipped.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use crate::debugger;
+use crate::error::EmulatorError;
+use crate::state::Registers;
+use crate::trace::{TraceEntry, TraceSink};
+
+/// The fields of a line of the reference.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Expected {
+    pub cycles: Option<u64>,
+    /// The registers, pairs and flags, with the names of `debugger::register()`
+    pub registers: Vec<(String, u16)>,
+}
+
+impl Expected {
+    /// `None` for a line without any field.
+    pub fn parse(line: &str) -> Option<Expected> {
+        let line = line.trim();
+        if line.starts_with('#') {
+            return None;
+        }
+        let mut result = Expected::default();
+        let mut in_brackets = false;
+        for (i, token) in line.split_whitespace().enumerate() {
+            let mut token = token;
+            if let Some(rest) = token.strip_prefix('[') {
+                in_brackets = true;
+                token = rest;
+            }
+            let closing = token.ends_with(']');
+            token = token.trim_end_matches(']');
+            if i == 0 && ! token.is_empty() && token.bytes().all(|b| b.is_ascii_digit()) {
+                result.cycles = token.parse().ok();
+            } else if let Some(pc) = token.strip_suffix(':').filter(|pc| pc.len() == 4) {
+                if let Ok(pc) = u16::from_str_radix(pc, 16) {
+                    result.registers.push(("PC".into(), pc));
+                }
+            } else if let Some((name, value)) = token.split_once(['=', ':']) {
+                let name = match name.to_ascii_uppercase().as_str() {
+                    "C" if in_brackets => "CY".to_string(),
+                    "LH" => "HL".to_string(),
+                    name => name.to_string(),
+                };
+                let known = debugger::register(&Registers::default(), &name).is_some();
+                if let (true, Ok(value)) = (known, u16::from_str_radix(value, 16)) {
+                    result.registers.push((name, value));
+                }
+            }
+            if closing {
+                in_brackets = false;
+            }
+        }
+        if result.cycles.is_none() && result.registers.is_empty() {
+            None
+        } else {
+            Some(result)
+        }
+    }
+
+    /// The fields that are different in `entry`: name, expected and actual values.
+    pub fn compare(&self, entry: &TraceEntry) -> Vec<(String, u64, u64)> {
+        let mut result = Vec::new();
+        if let Some(cycles) = self.cycles.filter(|cycles| *cycles != entry.cycles) {
+            result.push(("CYCLES".to_string(), cycles, entry.cycles));
+        }
+        for (name, value) in &self.registers {
+            let actual = debugger::register(&entry.registers, name).unwrap_or_default();
+            if actual != *value {
+                result.push((name.clone(), *value as u64, actual as u64));
+            }
+        }
+        result
+    }
+}
+
+/// Where the emulator and the reference parted ways.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    /// Number of instructions that matched before
+    pub instructions: usize,
+    /// The line of the reference, from 1, and its text
+    pub line: usize,
+    pub reference: String,
+    pub actual: TraceEntry,
+    /// The fields that are different: name, expected and actual values
+    pub differences: Vec<(String, u64, u64)>,
+    /// The instructions before, the oldest first
+    pub history: Vec<TraceEntry>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Divergence after {} instructions, at line {} of the reference", self.instructions, self.line)?;
+        if ! self.history.is_empty() {
+            writeln!(f, "Previous instructions:")?;
+            for entry in &self.history {
+                writeln!(f, "  {}", entry)?;
+            }
+        }
+        writeln!(f, "Actual:   {}", self.actual)?;
+        writeln!(f, "Expected: {}", self.reference.trim())?;
+        let differences: Vec<String> = self.differences.iter()
+            .map(|(name, expected, actual)| format!("{} expected {:X}, actual {:X}", name, expected, actual))
+            .collect();
+        writeln!(f, "Different: {}", differences.join(", "))
+    }
+}
+
+struct Comparison {
+    /// Line number, fields and text of each line of the reference
+    reference: Vec<(usize, Expected, String)>,
+    next: usize,
+    history: VecDeque<TraceEntry>,
+    context: usize,
+    divergence: Option<Divergence>,
+}
+
+/// A trace sink comparing each instruction with the next line of the reference. It's a
+/// handle, like `trace::RingBuffer`: keep a clone to read the result.
+#[derive(Clone)]
+pub struct TraceDiff {
+    comparison: Arc<Mutex<Comparison>>,
+}
+
+impl TraceDiff {
+    /// Keep the last `context` instructions to show them with the divergence.
+    pub fn new(reference: &str, context: usize) -> TraceDiff {
+        let reference = reference.lines().enumerate()
+            .filter_map(|(i, line)| Some((i + 1, Expected::parse(line)?, line.to_string())))
+            .collect();
+        TraceDiff { comparison: Arc::new(Mutex::new(Comparison {
+            reference, next: 0, history: VecDeque::new(), context, divergence: None,
+        }))}
+    }
+
+    pub fn load(file_name: &str, context: usize) -> Result<TraceDiff, EmulatorError> {
+        let reference = fs::read_to_string(file_name)
+            .map_err(|e| EmulatorError::Io { file_name: file_name.into(), message: e.to_string() })?;
+        Ok(TraceDiff::new(&reference, context))
+    }
+
+    /// Number of instructions that matched the reference so far
+    pub fn matched(&self) -> usize {
+        let comparison = self.comparison.lock().unwrap();
+        comparison.next - comparison.divergence.is_some() as usize
+    }
+
+    /// Number of instructions in the reference
+    pub fn len(&self) -> usize {
+        self.comparison.lock().unwrap().reference.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether there's a divergence or the whole reference was compared.
+    pub fn is_done(&self) -> bool {
+        let comparison = self.comparison.lock().unwrap();
+        comparison.divergence.is_some() || comparison.next == comparison.reference.len()
+    }
+
+    pub fn divergence(&self) -> Option<Divergence> {
+        self.comparison.lock().unwrap().divergence.clone()
+    }
+
+    /// The last instructions compared, the oldest first.
+    pub fn history(&self) -> Vec<TraceEntry> {
+        self.comparison.lock().unwrap().history.iter().cloned().collect()
+    }
+}
+
+impl TraceSink for TraceDiff {
+    fn record(&mut self, entry: &TraceEntry) {
+        let mut comparison = self.comparison.lock().unwrap();
+        if comparison.divergence.is_some() || comparison.next == comparison.reference.len() {
+            return;
+        }
+        let (line, expected, text) = &comparison.reference[comparison.next];
+        let differences = expected.compare(entry);
+        if ! differences.is_empty() {
+            comparison.divergence = Some(Divergence {
+                instructions: comparison.next,
+                line: *line,
+                reference: text.clone(),
+                actual: entry.clone(),
+                differences,
+                history: comparison.history.iter().cloned().collect(),
+            });
+        } else if comparison.context > 0 {
+            if comparison.history.len() == comparison.context {
+                comparison.history.pop_front();
+            }
+            comparison.history.push_back(entry.clone());
+        }
+        comparison.next += 1;
+    }
+}
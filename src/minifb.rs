@@ -1,4 +1,4 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
@@ -7,9 +7,13 @@ use std::time::{Duration, SystemTime};
 use minifb::{Key, KeyRepeat, Window, WindowOptions};
 
 use emulator::emulator::{HEIGHT, WIDTH};
+use emulator::emulator_state::Command;
+use emulator::rewind::RewindConfig;
 use emulator::memory::GRAPHIC_MEMORY_SIZE;
+use emulator::screen;
+use emulator::sound::SoundEvents;
 
-use crate::sounds::{ALL_SOUNDS, Message, Sound, SoundType};
+use crate::sounds::{Message, Sound};
 
 pub fn run_minifb() {
     let key_mappings = {
@@ -30,17 +34,21 @@ pub fn run_minifb() {
 
     let (sender, receiver): (Sender<Message>, Receiver<Message>)  = mpsc::channel();
     let sound = Sound::new(receiver);
-    let mut sounds: HashSet<SoundType> = HashSet::new();
+    let mut sound_events = SoundEvents::new();
     thread::spawn(move || {
         sound.run();
     });
 
-    let shared_state = emulator::emulator::Emulator::start_emulator();
+    let shared_state = match emulator::emulator::Emulator::start_emulator_with(Some(REWIND)) {
+        Ok(shared_state) => shared_state,
+        Err(e) => {
+            println!("Couldn't start the emulator: {}", e);
+            return;
+        }
+    };
 
     let width = WIDTH as usize;
     let height = HEIGHT as usize;
-    let mut buffer: Vec<u32> = vec![0; width * height * MAGNIFICATION * MAGNIFICATION];
-
     let mut options = WindowOptions::default();
     options.resize = true;
     let mut window = Window::new("space-invade.rs", width * 3, height * 3, options)
@@ -48,6 +56,10 @@ pub fn run_minifb() {
             panic!("{}", e);
         });
 
+    let mut recording = false;
+    let mut recording_gif = false;
+    let mut recording_video = false;
+
     // Only update the title every one second or so
     let mut last_title_update = SystemTime::now();
 
@@ -76,7 +88,11 @@ pub fn run_minifb() {
         // Released keys
         let keys_released: Vec<Key> = window.get_keys_released();
         for key in keys_released {
-            update_state(key, false);
+            if key == Key::R {
+                shared_state.lock().unwrap().set_rewinding(false);
+            } else {
+                update_state(key, false);
+            }
         }
 
         // Pressed keys
@@ -97,6 +113,69 @@ pub fn run_minifb() {
                         // Tilt
                         shared_state.lock().unwrap().set_bit_in_2(2, true);
                     }
+                    Key::R => {
+                        // Rewind for as long as the key is held
+                        shared_state.lock().unwrap().set_rewinding(true);
+                    }
+                    Key::F5 => {
+                        // Quick save
+                        shared_state.lock().unwrap().send(Command::SaveSnapshot(QUICK_SAVE.into()));
+                    }
+                    Key::F9 => {
+                        // Quick load
+                        shared_state.lock().unwrap().send(Command::LoadSnapshot(QUICK_SAVE.into()));
+                    }
+                    Key::F2 => {
+                        // Start or stop recording a movie
+                        let command = if recording {
+                            println!("Movie saved in {}", MOVIE);
+                            Command::StopRecording(MOVIE.into())
+                        } else {
+                            println!("Recording a movie");
+                            Command::StartRecording
+                        };
+                        recording = ! recording;
+                        shared_state.lock().unwrap().send(command);
+                    }
+                    Key::F3 => {
+                        // Replay the movie
+                        shared_state.lock().unwrap().send(Command::PlayMovie(MOVIE.into()));
+                    }
+                    Key::F11 => {
+                        // Start or stop recording an animated GIF
+                        let command = if recording_gif {
+                            println!("GIF saved");
+                            Command::StopGif
+                        } else {
+                            let file_name = next_file_name("gif");
+                            println!("Recording {}", file_name);
+                            Command::StartGif(file_name)
+                        };
+                        recording_gif = ! recording_gif;
+                        shared_state.lock().unwrap().send(command);
+                    }
+                    Key::F10 => {
+                        // Start or stop recording a video, with its sound
+                        let command = if recording_video {
+                            println!("Video saved");
+                            Command::StopVideo
+                        } else {
+                            let name = next_file_name("y4m").trim_end_matches(".y4m").to_string();
+                            println!("Recording {}.y4m and {}.wav", name, name);
+                            Command::StartVideo(name)
+                        };
+                        recording_video = ! recording_video;
+                        shared_state.lock().unwrap().send(command);
+                    }
+                    Key::F12 => {
+                        // Screenshot
+                        let file_name = next_file_name("png");
+                        let graphic_memory = shared_state.lock().unwrap().graphic_memory();
+                        match screen::save_png(&file_name, &graphic_memory) {
+                            Ok(_) => println!("Screenshot saved in {}", file_name),
+                            Err(e) => println!("{}", e),
+                        }
+                    }
                     _ => {
                         // If the emulator is paused, any key will unpause it
                         if shared_state.lock().unwrap().is_paused() {
@@ -112,24 +191,7 @@ pub fn run_minifb() {
         //
         let graphic_memory: [u8; GRAPHIC_MEMORY_SIZE] = shared_state.lock().unwrap().graphic_memory();
 
-        let mut i: usize = 0;
-        for ix in 0..width {
-            for iy in (0..height).step_by(8) {
-                let mut byte = graphic_memory[i];
-                i += 1;
-                for b in 0..8 {
-                    let x: i32 = ix as i32 * MAGNIFICATION as i32;
-                    let y: i32 = (height as i32 - (iy as i32 + b)) * MAGNIFICATION as i32;
-                    let color = if byte & 1 == 0 { BLACK } else {
-                        if iy > 200 && iy < 220 { RED }
-                        else if iy < 80 { GREEN }
-                        else { WHITE }
-                    };
-                    byte >>= 1;
-                    buffer[(y - 1) as usize * width + x as usize] = color;
-                }
-            }
-        }
+        let buffer = screen::render(&graphic_memory);
 
         window.update_with_buffer(&buffer, width, height)
             .unwrap();
@@ -137,50 +199,45 @@ pub fn run_minifb() {
         //
         // Process sounds
         //
-        let mut update_sound = |value: u8, bit: u8, sound_type: SoundType| {
-            let sound2 = sound_type.clone();
-            let is_playing = sounds.contains(&sound2);
-            let on = (value & (1 << bit)) != 0;
-            // Update our internal table to keep track of which sounds are currently playing
-            if on {
-                sounds.insert(sound2);
-            } else {
-                sounds.remove(&sound2);
-            }
-            // If the status of that sound changed, let the sound thread know
-            if on ^ is_playing {
-                match sender.send(Message { sound_type, on }) {
-                    Ok(_) => { }
-                    Err(e) => { println!("Err: {e}") }
-                }
-            }
-        };
-
-        {
+        let (out_3, out_5) = {
             let state = shared_state.lock().unwrap();
-            for sd in ALL_SOUNDS.iter() {
-                update_sound(state.get_out(sd.channel_bit.channel), sd.channel_bit.bit, sd.sound_type)
+            (state.get_out(3).unwrap_or(0), state.get_out(5).unwrap_or(0))
+        };
+        // Let the sound thread know about the sounds that were turned on or off
+        for (sound_type, on) in sound_events.update(out_3, out_5) {
+            match sender.send(Message { sound_type, on }) {
+                Ok(_) => { }
+                Err(e) => { println!("Err: {e}") }
             }
         }
 
         if last_title_update.elapsed().unwrap().gt(&Duration::from_millis(1000)) {
-            let paused = if shared_state.lock().unwrap().is_paused() { " - Paused" } else { "" };
+            let state = shared_state.lock().unwrap();
+            let status = if let Some(error) = state.error() { format!(" - {}", error) }
+                else if state.is_paused() { " - Paused".into() }
+                else { "".into() };
             window.set_title(
                 format!("space-invade.rs - Cédric Beust - {:.2} Mhz{}",
-                    shared_state.lock().unwrap().get_megahertz(),
-                    paused)
+                    state.get_megahertz(),
+                    status)
                     .as_str());
             last_title_update = SystemTime::now();
         }
     }
 }
 
-const MAGNIFICATION: usize = 1;
+const QUICK_SAVE: &str = "space-invaders.snapshot";
+const MOVIE: &str = "space-invaders.movie";
+
+/// Up to 30 seconds of rewind
+const REWIND: RewindConfig = RewindConfig { depth: 30 * 60, memory_budget: 32 * 1024 * 1024 };
 
-const RED: u32 = 0x00ff0000;
-const GREEN: u32 = 0x0000ff00;
-const WHITE: u32 = 0xffffff;
-const BLACK: u32 = 0;
+/// The first space-invaders-<n>.<extension> that doesn't exist yet
+fn next_file_name(extension: &str) -> String {
+    (1..).map(|n| format!("space-invaders-{}.{}", n, extension))
+        .find(|file_name| ! std::path::Path::new(file_name).exists())
+        .unwrap()
+}
 
 pub struct ChannelBit {
     pub channel: u8,
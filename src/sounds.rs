@@ -2,23 +2,9 @@ use std::collections::HashMap;
 use std::fs::read;
 use std::io::{BufReader, Cursor};
 use std::sync::mpsc::Receiver;
-use lazy_static::lazy_static;
 
 use rodio::{Decoder, OutputStream, Sink};
-use crate::minifb::ChannelBit;
-
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
-pub enum SoundType {
-    Fire,
-    InvaderDies,
-    PlayerDies,
-    Ufo,
-    Invader1,
-    Invader2,
-    Invader3,
-    Invader4,
-    UfoHit,
-}
+use emulator::sound::{SoundType, SOUNDS, SOUNDS_DIRECTORY};
 
 pub struct Message {
     pub sound_type: SoundType,
@@ -30,42 +16,11 @@ pub struct Sound {
     sound_files: HashMap<SoundType, Vec<u8>>,
 }
 
-pub struct SoundInfo {
-    pub sound_type: SoundType,
-    pub path: String,
-    pub channel_bit: ChannelBit,
-}
-
-impl SoundInfo {
-    fn new(sound_type: SoundType, path: &str, channel: u8, bit: u8) -> Self {
-        Self {
-            sound_type, path: path.into(), channel_bit: ChannelBit { channel, bit }
-        }
-    }
-}
-
-lazy_static! {
-    pub static ref ALL_SOUNDS: Vec<SoundInfo> = {
-        let mut result = Vec::new();
-        result.push(SoundInfo::new(SoundType::Ufo, "sounds/ufo_lowpitch.wav", 3, 0));
-        result.push(SoundInfo::new(SoundType::Fire, "sounds/shoot.wav", 3, 1));
-        result.push(SoundInfo::new(SoundType::PlayerDies, "sounds/explosion.wav", 3, 2));
-        result.push(SoundInfo::new(SoundType::InvaderDies, "sounds/invaderkilled.wav", 3, 3));
-        result.push(SoundInfo::new(SoundType::Invader1, "sounds/fastinvader1.wav", 5, 0));
-        result.push(SoundInfo::new(SoundType::Invader2, "sounds/fastinvader2.wav", 5, 1));
-        result.push(SoundInfo::new(SoundType::Invader3, "sounds/fastinvader3.wav", 5, 2));
-        result.push(SoundInfo::new(SoundType::Invader4, "sounds/fastinvader4.wav", 5, 3));
-        result.push(SoundInfo::new(SoundType::UfoHit, "sounds/explosion.wav", 5, 4));
-
-        result
-    };
-}
-
 impl Sound {
     pub fn new(receiver: Receiver<Message>) -> Self {
         let mut sound_files = HashMap::new();
-        for s in ALL_SOUNDS.iter() {
-            sound_files.insert(s.sound_type, read(s.path.clone()).unwrap());
+        for s in SOUNDS.iter() {
+            sound_files.insert(s.sound_type, read(format!("{}/{}", SOUNDS_DIRECTORY, s.file_name)).unwrap());
         }
         Self { receiver, sound_files }
     }
@@ -1,9 +1,9 @@
 mod utils;
 
+use std::sync::{Arc, Mutex};
 use wasm_bindgen::prelude::*;
 use emulator::emulator_state::*;
 use emulator::emulator::Emulator;
-use emulator::emulator::*;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -26,14 +26,26 @@ pub fn greet() {
     alert("Hello, Cedric 2!");
 }
 
+/// One running emulator, as seen from JavaScript. Each call to `start_emulator()`
+/// returns a new one with its own memory.
 #[wasm_bindgen]
-pub fn start_emulator() {
-    log("Initializing shared state");
-    let _ = Emulator::start_emulator();
-    log("... done!");
+pub struct EmulatorHandle {
+    shared_state: Arc<Mutex<SharedState>>,
 }
 
 #[wasm_bindgen]
-pub fn graphic_memory() -> Vec<u8> {
-    emulator::emulator::graphic_memory()
+impl EmulatorHandle {
+    pub fn graphic_memory(&self) -> Vec<u8> {
+        self.shared_state.lock().unwrap().graphic_memory().to_vec()
+    }
+}
+
+#[wasm_bindgen]
+pub fn start_emulator() -> Result<EmulatorHandle, JsValue> {
+    utils::set_panic_hook();
+    log("Initializing shared state");
+    let shared_state = Emulator::start_emulator()
+        .map_err(|e| JsValue::from_str(&e.to_string()))?;
+    log("... done!");
+    Ok(EmulatorHandle { shared_state })
 }
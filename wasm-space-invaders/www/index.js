@@ -1,9 +1,14 @@
 import * as wasm from "wasm-space-invaders";
 
-wasm.start_emulator();
-var memory = wasm.graphic_memory();
+try {
+    // Throws the message of the EmulatorError if the emulator couldn't start
+    const emulator = wasm.start_emulator();
+    var memory = emulator.graphic_memory();
 
-console.log("memory: " + memory[0]);
+    console.log("memory: " + memory[0]);
+} catch (e) {
+    console.error("Couldn't start the emulator: " + e);
+}
 
 // wasm.greet();
 